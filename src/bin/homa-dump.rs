@@ -0,0 +1,514 @@
+/*
+homa-dump
+
+Decode Homa datagrams offline from a pcap file or from hex input, one packet
+per line, and reconstruct the timeline of every message seen
+
+Packets are accepted as written by the capture of the admin interface, as
+captured by tcpdump on the raw transport with the outer IPv4 header, or
+encapsulated in UDP on the configured port
+*/
+use bincode::deserialize;
+use clap::Parser;
+use homad::config::CONST;
+use homad::models::datagram::HomaDatagram;
+use homad::models::datagram::HomaDatagramType;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::net::Ipv4Addr;
+
+#[derive(Parser)]
+#[command(version, long_about = None)]
+#[allow(non_snake_case)]
+struct Config {
+    /// Pcap file or file of hex packets, one per line, stdin if omitted
+    INPUT: Option<String>,
+    /// Read the input as hex even if it starts like a pcap file
+    #[arg(long)]
+    HEX: bool,
+    /// Port of the UDP transport
+    #[arg(long, default_value_t = 4146)]
+    UDP_PORT: u16,
+    /// Print only the datagrams and no message timelines
+    #[arg(long)]
+    NO_TIMELINE: bool,
+}
+
+// A captured packet with its timestamp in seconds
+struct CapturedPacket {
+    timestamp: f64,
+    data: Vec<u8>,
+}
+
+// A decoded datagram with the addresses and DSCP of its IPv4 header
+struct DecodedDatagram {
+    timestamp: f64,
+    source_address: Ipv4Addr,
+    destination_address: Ipv4Addr,
+    dscp: u8,
+    checksum_valid: bool,
+    datagram: HomaDatagram,
+}
+
+impl DecodedDatagram {
+    // Identify the message by its sender, receiver and message id,
+    // control datagrams travel from the receiver to the sender
+    fn message_key(&self) -> MessageKey {
+        match self.datagram.datagram_type {
+            HomaDatagramType::Data => MessageKey {
+                sender: (self.source_address, self.datagram.source_id),
+                receiver: (self.destination_address, self.datagram.destination_id),
+                message_id: self.datagram.message_id,
+            },
+            _ => MessageKey {
+                sender: (self.destination_address, self.datagram.destination_id),
+                receiver: (self.source_address, self.datagram.source_id),
+                message_id: self.datagram.message_id,
+            },
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+struct MessageKey {
+    sender: (Ipv4Addr, u32),
+    receiver: (Ipv4Addr, u32),
+    message_id: u64,
+}
+
+#[derive(Default)]
+struct Timeline {
+    datagrams: u64,
+    start: f64,
+    end: f64,
+    message_length: u64,
+    datagram_length: u64,
+    // Data datagrams sent before the first grant
+    unscheduled_datagrams: u64,
+    unscheduled_bytes: u64,
+    unscheduled_end: f64,
+    data_sequence_numbers: HashSet<u32>,
+    data_bytes: u64,
+    repeated_data: u64,
+    all_data: Option<f64>,
+    first_grant: Option<f64>,
+    last_grant: Option<(f64, u32)>,
+    grants: u64,
+    resends: Vec<(f64, Vec<(u32, u32)>)>,
+    busy: Vec<f64>,
+    overloaded: Vec<f64>,
+    invalid_checksums: u64,
+}
+
+impl Timeline {
+    fn add(&mut self, decoded: &DecodedDatagram) {
+        let timestamp = decoded.timestamp;
+        let datagram = &decoded.datagram;
+        if self.datagrams == 0 {
+            self.start = timestamp;
+        }
+        self.datagrams += 1;
+        self.end = timestamp;
+        if !decoded.checksum_valid {
+            self.invalid_checksums += 1;
+            return;
+        }
+        match datagram.datagram_type {
+            HomaDatagramType::Data => {
+                let length = datagram.payload.len() as u64;
+                self.message_length = datagram.message_length;
+                self.datagram_length = self.datagram_length.max(length);
+                if self.first_grant.is_none() {
+                    self.unscheduled_datagrams += 1;
+                    self.unscheduled_bytes += length;
+                    self.unscheduled_end = timestamp;
+                }
+                if self.data_sequence_numbers.insert(datagram.sequence_number) {
+                    self.data_bytes += length;
+                    if self.data_bytes >= self.message_length && self.all_data.is_none() {
+                        self.all_data = Some(timestamp);
+                    }
+                } else {
+                    self.repeated_data += 1;
+                }
+            }
+            HomaDatagramType::Grant => {
+                self.first_grant.get_or_insert(timestamp);
+                self.last_grant = Some((timestamp, datagram.sequence_number));
+                self.grants += 1;
+            }
+            HomaDatagramType::Resend => self.resends.push((timestamp, datagram.ranges.clone())),
+            HomaDatagramType::Busy => self.busy.push(timestamp),
+            HomaDatagramType::Overloaded => self.overloaded.push(timestamp),
+        }
+    }
+
+    // The final grant carries the number of datagrams of the message
+    fn completion(&self) -> Option<f64> {
+        let (timestamp, sequence_number) = self.last_grant?;
+        if self.datagram_length == 0 {
+            return None;
+        }
+        let datagram_count = self.message_length.div_ceil(self.datagram_length);
+        (sequence_number as u64 >= datagram_count).then_some(timestamp)
+    }
+
+    fn print(&self, key: &MessageKey) {
+        let relative = |timestamp: f64| timestamp - self.start;
+        println!(
+            "message {} {}:{} > {}:{} length {}",
+            key.message_id,
+            key.sender.0,
+            key.sender.1,
+            key.receiver.0,
+            key.receiver.1,
+            self.message_length
+        );
+        println!(
+            "  +{:.6} unscheduled burst: {} datagrams, {} bytes, until +{:.6}",
+            0.0,
+            self.unscheduled_datagrams,
+            self.unscheduled_bytes,
+            relative(self.unscheduled_end)
+        );
+        if let (Some(first_grant), Some((last_grant, _))) = (self.first_grant, self.last_grant) {
+            println!(
+                "  +{:.6} grants: {} until +{:.6}",
+                relative(first_grant),
+                self.grants,
+                relative(last_grant)
+            );
+        }
+        for (timestamp, ranges) in &self.resends {
+            let ranges = ranges
+                .iter()
+                .map(|(start, end)| match end - start {
+                    1 => start.to_string(),
+                    _ => format!("{}-{}", start, end - 1),
+                })
+                .collect::<Vec<_>>();
+            println!(
+                "  +{:.6} resend seq {}",
+                relative(*timestamp),
+                ranges.join(",")
+            );
+        }
+        if let Some(all_data) = self.all_data {
+            println!("  +{:.6} all data seen", relative(all_data));
+        }
+        for timestamp in &self.busy {
+            println!("  +{:.6} busy", relative(*timestamp));
+        }
+        for timestamp in &self.overloaded {
+            println!("  +{:.6} overloaded", relative(*timestamp));
+        }
+        match self.completion() {
+            Some(completion) => println!("  +{:.6} complete", relative(completion)),
+            None => println!("  incomplete, last seen +{:.6}", relative(self.end)),
+        }
+        if self.repeated_data > 0 {
+            println!("  {} repeated data datagrams", self.repeated_data);
+        }
+        if self.invalid_checksums > 0 {
+            println!(
+                "  {} datagrams with invalid checksum",
+                self.invalid_checksums
+            );
+        }
+    }
+}
+
+// Read the pcap records, the byte order and timestamp resolution
+// are given by the magic number, a truncated last record as left by an
+// interrupted capture ends the packets with a warning
+fn read_pcap(bytes: &[u8]) -> Result<Vec<CapturedPacket>, String> {
+    let header = bytes.get(..24).ok_or("Truncated pcap header")?;
+    let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let (little_endian, resolution) = match magic {
+        0xa1b2c3d4 => (true, 1e-6),
+        0xa1b23c4d => (true, 1e-9),
+        0xd4c3b2a1 => (false, 1e-6),
+        0x4d3cb2a1 => (false, 1e-9),
+        _ => return Err("Not a pcap file".to_string()),
+    };
+    let read_u32 = |bytes: &[u8]| {
+        let bytes = bytes[..4].try_into().unwrap();
+        if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    };
+    let linktype = read_u32(&header[20..]);
+
+    let mut packets = Vec::new();
+    let mut offset = 24;
+    while offset < bytes.len() {
+        let record = &bytes[offset..];
+        let Some(data) = record
+            .get(8..12)
+            .and_then(|length| record.get(16..16 + read_u32(length) as usize))
+        else {
+            eprintln!(
+                "Truncated pcap record at byte {}, read {} packets",
+                offset,
+                packets.len()
+            );
+            break;
+        };
+        let seconds = read_u32(record) as f64;
+        let fraction = read_u32(&record[4..]) as f64;
+        offset += 16 + data.len();
+        if let Some(data) = strip_link_header(linktype, data) {
+            packets.push(CapturedPacket {
+                timestamp: seconds + fraction * resolution,
+                data: data.to_vec(),
+            });
+        }
+    }
+    Ok(packets)
+}
+
+// Return the IPv4 packet inside the link layer frame
+fn strip_link_header(linktype: u32, data: &[u8]) -> Option<&[u8]> {
+    match linktype {
+        // BSD loopback
+        0 => data.get(4..),
+        // Ethernet, optionally with a VLAN tag
+        1 => match data.get(12..14)? {
+            [0x08, 0x00] => data.get(14..),
+            [0x81, 0x00] if data.get(16..18)? == [0x08, 0x00] => data.get(18..),
+            _ => None,
+        },
+        // Raw IP and IPv4
+        101 | 228 => Some(data),
+        // Linux cooked capture v1 and v2
+        113 if data.get(14..16)? == [0x08, 0x00] => data.get(16..),
+        276 if data.get(..2)? == [0x08, 0x00] => data.get(20..),
+        _ => None,
+    }
+}
+
+// Read one hex packet per line, ignoring whitespace, colons and comments
+fn read_hex(text: &str) -> Result<Vec<CapturedPacket>, String> {
+    let mut packets = Vec::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let digits = line
+            .trim()
+            .trim_start_matches("0x")
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != ':')
+            .collect::<Vec<char>>();
+        if digits.is_empty() {
+            continue;
+        }
+        let data = digits
+            .chunks(2)
+            .map(|pair| {
+                let pair = pair.iter().collect::<String>();
+                u8::from_str_radix(&pair, 16)
+                    .map_err(|_| format!("Invalid hex on line {}", line_number + 1))
+            })
+            .collect::<Result<Vec<u8>, String>>()?;
+        packets.push(CapturedPacket {
+            timestamp: 0.0,
+            data,
+        });
+    }
+    Ok(packets)
+}
+
+// Find the Homa datagram in the IPv4 packet, unwrapping the outer header
+// of the raw transport and the UDP header of the UDP transport
+fn decode_packet(packet: &CapturedPacket, udp_port: u16) -> Option<DecodedDatagram> {
+    let ipv4_packet = Ipv4Packet::new(&packet.data)?;
+    if ipv4_packet.get_version() != 4 {
+        return None;
+    }
+    let payload = ipv4_packet.payload();
+    let encapsulated = CapturedPacket {
+        timestamp: packet.timestamp,
+        data: payload.to_vec(),
+    };
+    match ipv4_packet.get_next_level_protocol() {
+        IpNextHeaderProtocols::Udp => {
+            let udp_packet = UdpPacket::new(payload)?;
+            if udp_packet.get_source() != udp_port && udp_packet.get_destination() != udp_port {
+                return None;
+            }
+            let encapsulated = CapturedPacket {
+                timestamp: packet.timestamp,
+                data: udp_packet.payload().to_vec(),
+            };
+            decode_packet(&encapsulated, udp_port)
+        }
+        protocol if protocol.0 == CONST::HOMA_PROTOCOL => {
+            if let Some(decoded) = decode_packet(&encapsulated, udp_port) {
+                return Some(decoded);
+            }
+            let datagram = deserialize::<HomaDatagram>(payload).ok()?;
+            let mut checked = datagram.clone();
+            let checksum_valid = checked.checksum() == Ok(datagram.checksum);
+            Some(DecodedDatagram {
+                timestamp: packet.timestamp,
+                source_address: ipv4_packet.get_source(),
+                destination_address: ipv4_packet.get_destination(),
+                dscp: ipv4_packet.get_dscp(),
+                checksum_valid,
+                datagram,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn print_datagram(decoded: &DecodedDatagram) {
+    let datagram = &decoded.datagram;
+    println!(
+        "{:.6} {}:{} > {}:{} {:?} id {} seq {} offset {} ranges {:?} prio {} dscp {} len {}/{} workload {} {:?} limit {} for {}us checksum {}",
+        decoded.timestamp,
+        decoded.source_address,
+        datagram.source_id,
+        decoded.destination_address,
+        datagram.destination_id,
+        datagram.datagram_type,
+        datagram.message_id,
+        datagram.sequence_number,
+        datagram.offset,
+        datagram.ranges,
+        datagram.priority,
+        decoded.dscp,
+        datagram.payload.len(),
+        datagram.message_length,
+        datagram.workload.unscheduled_priority_levels,
+        datagram.workload.cutoffs,
+        datagram.workload.unscheduled_datagram_limit,
+        datagram.workload.limit_lifetime,
+        if decoded.checksum_valid { "ok" } else { "bad" }
+    );
+}
+
+fn read_input(config: &Config) -> Result<Vec<CapturedPacket>, String> {
+    let bytes = match &config.INPUT {
+        Some(path) => fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?,
+        None => {
+            let mut bytes = Vec::new();
+            io::stdin()
+                .read_to_end(&mut bytes)
+                .map_err(|e| format!("Failed to read stdin: {}", e))?;
+            bytes
+        }
+    };
+    if !config.HEX {
+        if let Ok(packets) = read_pcap(&bytes) {
+            return Ok(packets);
+        }
+    }
+    let text = String::from_utf8(bytes).map_err(|_| "Input is neither pcap nor hex")?;
+    read_hex(&text)
+}
+
+fn main() -> Result<(), String> {
+    let config = Config::parse();
+    let packets = read_input(&config)?;
+
+    let mut timelines = BTreeMap::<MessageKey, Timeline>::new();
+    for packet in &packets {
+        if let Some(decoded) = decode_packet(packet, config.UDP_PORT) {
+            print_datagram(&decoded);
+            timelines
+                .entry(decoded.message_key())
+                .or_default()
+                .add(&decoded);
+        }
+    }
+
+    if !config.NO_TIMELINE {
+        for (key, timeline) in &timelines {
+            println!();
+            timeline.print(key);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_packet;
+    use super::read_hex;
+    use super::read_pcap;
+    use homad::models::datagram::HomaDatagram;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn decode_test() {
+        let mut datagram = HomaDatagram {
+            message_id: 7,
+            sequence_number: 3,
+            payload: vec![1, 2, 3],
+            message_length: 3,
+            ..Default::default()
+        };
+        let _ = datagram.checksum();
+        let packet = datagram.to_ipv4(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), 5);
+
+        let mut pcap = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        pcap.extend_from_slice(&[0; 8]);
+        pcap.extend_from_slice(&65535u32.to_le_bytes());
+        pcap.extend_from_slice(&228u32.to_le_bytes());
+        pcap.extend_from_slice(&1u32.to_le_bytes());
+        pcap.extend_from_slice(&500000u32.to_le_bytes());
+        pcap.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        pcap.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        pcap.extend_from_slice(&packet);
+
+        let packets = read_pcap(&pcap).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].timestamp, 1.5);
+        let decoded = decode_packet(&packets[0], 4146).unwrap();
+        assert!(decoded.checksum_valid);
+        assert_eq!(decoded.dscp, 5);
+        assert_eq!(decoded.datagram.message_id, 7);
+        assert_eq!(decoded.datagram.sequence_number, 3);
+
+        let hex = packet
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        let packets = read_hex(&hex).unwrap();
+        assert!(decode_packet(&packets[0], 4146).is_some());
+    }
+
+    #[test]
+    fn truncated_pcap_test() {
+        let packet = HomaDatagram::default().to_ipv4(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            0,
+        );
+        let mut pcap = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        pcap.extend_from_slice(&[0; 8]);
+        pcap.extend_from_slice(&65535u32.to_le_bytes());
+        pcap.extend_from_slice(&228u32.to_le_bytes());
+        for _ in 0..2 {
+            pcap.extend_from_slice(&[0; 8]);
+            pcap.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(&packet);
+        }
+
+        // Cut in the data and in the header of the last record
+        assert_eq!(read_pcap(&pcap[..pcap.len() - 1]).unwrap().len(), 1);
+        let header_cut = pcap.len() - packet.len() - 6;
+        assert_eq!(read_pcap(&pcap[..header_cut]).unwrap().len(), 1);
+        assert_eq!(read_pcap(&pcap).unwrap().len(), 2);
+    }
+}
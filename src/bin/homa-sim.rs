@@ -0,0 +1,94 @@
+/*
+homa-sim
+
+Simulate a cluster of homad hosts under a workload and print the slowdown of
+the delivered messages by message length, the options of homad apply to the
+simulated hosts
+*/
+use clap::Parser;
+use homad::config::set_config;
+use homad::config::Config as HomadConfig;
+use homad::simulator::simulate;
+use homad::simulator::SimulationOptions;
+use homad::simulator::Workload;
+use std::fs;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(version, long_about = None)]
+#[allow(non_snake_case)]
+struct SimulatorConfig {
+    /// Number of simulated hosts
+    #[arg(long, default_value_t = 16)]
+    HOSTS: usize,
+    /// Number of messages sent across all hosts
+    #[arg(long, default_value_t = 10000)]
+    MESSAGES: usize,
+    /// Offered load of each host as a fraction of the link speed
+    #[arg(long, default_value_t = 0.5)]
+    LOAD: f64,
+    /// Seed of the simulation, equal seeds give equal runs
+    #[arg(long, default_value_t = 1)]
+    SEED: u64,
+    /// File of message lengths and their cumulative probabilities, one pair per line
+    #[arg(long)]
+    WORKLOAD_CDF: Option<String>,
+    /// Min message length without a workload CDF, lengths are log-uniform
+    #[arg(long, default_value_t = 100)]
+    MIN_LENGTH: u64,
+    /// Max message length without a workload CDF, lengths are log-uniform
+    #[arg(long, default_value_t = 1_000_000)]
+    MAX_LENGTH: u64,
+    /// Propagation delay of each link in nanoseconds
+    #[arg(long, default_value_t = 1000)]
+    LINK_DELAY: u64,
+    /// Buffer of each switch port in bytes, 0 for unlimited
+    #[arg(long, default_value_t = 0)]
+    SWITCH_BUFFER: u64,
+    #[command(flatten)]
+    HOMAD: HomadConfig,
+}
+
+fn main() -> Result<(), String> {
+    let config = SimulatorConfig::parse();
+    let workload = match &config.WORKLOAD_CDF {
+        Some(path) => Workload::from_cdf(
+            &fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?,
+        )?,
+        None => Workload::log_uniform(config.MIN_LENGTH, config.MAX_LENGTH),
+    };
+    let options = SimulationOptions {
+        hosts: config.HOSTS,
+        messages: config.MESSAGES,
+        load: config.LOAD,
+        seed: config.SEED,
+        workload,
+        link_speed: config.HOMAD.LINK_SPEED,
+        link_delay: Duration::from_nanos(config.LINK_DELAY),
+        switch_buffer: config.SWITCH_BUFFER,
+    };
+    config.HOMAD.validate()?;
+    set_config(config.HOMAD);
+
+    let result = simulate(options)?;
+    let delivered = result
+        .messages
+        .iter()
+        .filter(|(_, completion_time)| completion_time.is_some())
+        .count();
+    println!(
+        "# {} messages, {} delivered, {} datagrams dropped, {:.6} s simulated",
+        result.messages.len(),
+        delivered,
+        result.dropped_datagrams,
+        result.duration.as_secs_f64()
+    );
+    println!("# length messages median_slowdown p99_slowdown mean_slowdown");
+    for (length, messages, median, p99, mean) in result.slowdowns() {
+        println!(
+            "{} {} {:.3} {:.3} {:.3}",
+            length, messages, median, p99, mean
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,171 @@
+/*
+AdminListener actor
+
+This actor initializes the admin unix socket at the specified path
+
+The actor then accepts operators and reads newline-delimited JSON commands,
+each command is applied to the CaptureTransport or the FaultTransport, or
+reads the counters of the registered applications or of the transport, and is
+answered with one newline-delimited JSON response
+*/
+use crate::components::application::ApplicationHandle;
+use crate::components::datagram_sender::DatagramSenderHandle;
+use crate::config::CONFIG;
+use crate::dispatch_table::DispatchTable;
+use crate::models::admin::HomaAdminCommand;
+use crate::models::admin::HomaAdminResponse;
+use crate::models::admin::HomaApplicationSummary;
+use crate::models::admin::HomaTransportSummary;
+use crate::transport::capture::CaptureTransport;
+use crate::transport::fault::FaultTransport;
+use crate::transport::Transport;
+use std::fs;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+
+pub struct AdminListener {
+    // Listener to accept operators
+    listener: UnixListener,
+    // Transport recording the captured packets
+    capture_transport: Arc<CaptureTransport>,
+    // Transport injecting faults into outgoing packets
+    fault_transport: Arc<FaultTransport>,
+    // Registered applications to report the counters of
+    application_handles: Arc<DispatchTable<u32, ApplicationHandle>>,
+    // DatagramSender counting the packets the transport failed to send
+    datagram_sender_handle: DatagramSenderHandle,
+}
+
+impl AdminListener {
+    // Remove the existing admin unix socket if one exists,
+    // then create and bind the UnixListener
+    fn init() -> Result<UnixListener, String> {
+        let _ = fs::remove_file(CONFIG.ADMIN_SOCKET_PATH.clone());
+        UnixListener::bind(CONFIG.ADMIN_SOCKET_PATH.clone())
+            .map_err(|e| format!("AdminListener failed to start UnixListener: {}", e))
+    }
+
+    // Spawn the AdminListener task as a dedicated thread
+    pub fn start(
+        capture_transport: Arc<CaptureTransport>,
+        fault_transport: Arc<FaultTransport>,
+        application_handles: Arc<DispatchTable<u32, ApplicationHandle>>,
+        datagram_sender_handle: DatagramSenderHandle,
+    ) -> Result<(), String> {
+        let listener = Self::init()?;
+        let admin_listener = Self {
+            listener,
+            capture_transport,
+            fault_transport,
+            application_handles,
+            datagram_sender_handle,
+        };
+        tokio::task::spawn_blocking(move || run_admin_listener(admin_listener));
+        Ok(())
+    }
+}
+
+// Accept operators, serve each connection on its own thread
+fn run_admin_listener(mut admin_listener: AdminListener) {
+    loop {
+        while let Ok((stream, _)) = admin_listener.listener.accept() {
+            let capture_transport = Arc::clone(&admin_listener.capture_transport);
+            let fault_transport = Arc::clone(&admin_listener.fault_transport);
+            let application_handles = Arc::clone(&admin_listener.application_handles);
+            let datagram_sender_handle = admin_listener.datagram_sender_handle.clone();
+            tokio::task::spawn_blocking(move || {
+                handle_connection(
+                    stream,
+                    capture_transport,
+                    fault_transport,
+                    application_handles,
+                    datagram_sender_handle,
+                )
+            });
+        }
+        admin_listener.listener =
+            AdminListener::init().expect("AdminListener failed to restart UnixListener");
+    }
+}
+
+// Answer each command line until the operator disconnects
+fn handle_connection(
+    stream: UnixStream,
+    capture_transport: Arc<CaptureTransport>,
+    fault_transport: Arc<FaultTransport>,
+    application_handles: Arc<DispatchTable<u32, ApplicationHandle>>,
+    datagram_sender_handle: DatagramSenderHandle,
+) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = match serde_json::from_str::<HomaAdminCommand>(&line) {
+            Ok(command) => handle_command(
+                command,
+                &capture_transport,
+                &fault_transport,
+                &application_handles,
+                &datagram_sender_handle,
+            ),
+            Err(e) => Err(format!("Invalid admin command: {}", e)),
+        };
+        let response = HomaAdminResponse::from_result(result);
+        let Ok(mut response) = serde_json::to_vec(&response) else {
+            return;
+        };
+        response.push(b'\n');
+        if writer.write_all(&response).is_err() {
+            return;
+        }
+    }
+}
+
+fn handle_command(
+    command: HomaAdminCommand,
+    capture_transport: &CaptureTransport,
+    fault_transport: &FaultTransport,
+    application_handles: &DispatchTable<u32, ApplicationHandle>,
+    datagram_sender_handle: &DatagramSenderHandle,
+) -> Result<HomaAdminResponse, String> {
+    let mut response = HomaAdminResponse::default();
+    match command {
+        HomaAdminCommand::StartCapture(options) => capture_transport.start_capture(options)?,
+        HomaAdminCommand::StopCapture => response.capture = Some(capture_transport.stop_capture()?),
+        HomaAdminCommand::StartFaults(options) => fault_transport.start_faults(options)?,
+        HomaAdminCommand::StopFaults => response.faults = Some(fault_transport.stop_faults()),
+        HomaAdminCommand::ApplicationStats => {
+            let mut applications = application_handles
+                .entries()
+                .into_iter()
+                .map(
+                    |(application_id, application_handle)| HomaApplicationSummary {
+                        application_id,
+                        dropped_datagrams: application_handle.dropped_datagrams(),
+                    },
+                )
+                .collect::<Vec<_>>();
+            applications.sort_by_key(|application| application.application_id);
+            response.applications = Some(applications);
+        }
+        HomaAdminCommand::TransportStats => {
+            response.transport = Some(HomaTransportSummary {
+                failed_packets: datagram_sender_handle.failed_packets()
+                    + fault_transport.failed_packets(),
+                unroutable_packets: fault_transport.unroutable_packets(),
+                dropped_packets: fault_transport.dropped_packets(),
+            })
+        }
+    }
+    Ok(response)
+}
@@ -9,40 +9,90 @@ a MessageReceiver if one does not exist or forwards the HomaDatagram to the
 correct MessageReceiver if one exists.
 
 Finally, it listens to all relevant MessageReceivers for (in)completion
+
+If the application registered with REGISTRATION_FLAGS::ACK, delivered messages
+are kept until the application acknowledges them, messages which are still
+unacknowledged on shutdown are handed to the ApplicationRegistrar to be
+redelivered when the application registers again
+
+If the application registered with REGISTRATION_FLAGS::RESUME, a broken stream
+only disconnects the session: MessageSenders and MessageReceivers keep running
+and the ApplicationWriter buffers deliveries until the ApplicationRegistrar
+hands over the stream of a resumed session, or the grace period expires.
+A session resumed before the broken stream is noticed replaces the stream.
+Messages still unacknowledged when the session resumes are delivered again
+
+Messages from a source address homad is not bound to are refused when they
+are accepted, the application is sent a SendFailure instead
+
+Datagrams are dispatched from the receiving threads without blocking, a
+datagram finding the queue of its actor full is dropped and counted so that a
+slow application cannot stall the reception for the other applications
 */
 use crate::components::application_reader::ApplicationReader;
 use crate::components::application_registrar::ApplicationRegistrarHandle;
 use crate::components::application_registrar::ApplicationRegistrarMessage::FromApplication;
 use crate::components::application_writer::ApplicationWriterHandle;
+use crate::components::application_writer::ApplicationWriterMessage;
 use crate::components::datagram_sender::DatagramSenderHandle;
 use crate::components::message_receiver::MessageReceiverHandle;
 use crate::components::message_sender::MessageSenderHandle;
 use crate::components::priority_manager::PriorityManagerHandle;
 use crate::components::workload_manager::WorkloadManagerHandle;
+use crate::config::CONFIG;
+use crate::dispatch_table::DispatchTable;
 use crate::models::datagram::HomaDatagram;
+use crate::models::frame::HomaSendFailure;
 use crate::models::message::HomaMessage;
+use crate::models::registration::HomaRegistrationMessage;
+use crate::models::registration::REGISTRATION_FLAGS;
 use crate::utils::split_unix_stream;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::net::Ipv4Addr;
 use std::os::unix::net::UnixStream;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use tokio::sync::mpsc::channel;
 use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio::time::Duration;
+use tokio::time::Instant;
+
+// Delivered message awaiting acknowledgement from the application, the
+// sender is absent for messages redelivered after a reconnect since their
+// MessageReceiver is gone, their acknowledgement never reaches the sender
+struct UnacknowledgedMessage {
+    message: HomaMessage,
+    ack_tx: Option<oneshot::Sender<()>>,
+}
 
 #[allow(unused)]
 struct Application {
     application_id: u32,
+    registration_message: HomaRegistrationMessage,
+    acknowledge: bool,
     rx: Receiver<ApplicationMessage>,
 
+    // Whether a stream to the application is attached, the generation
+    // is bumped on every disconnect or replaced stream to discard stale
+    // session timers and shutdowns of replaced ApplicationReaders
+    connected: bool,
+    session_generation: u64,
+
     // Set to keep track of all messages that have been delivered
     // in order to discard delayed or duplicated datagrams
     delivered_messages: HashSet<u64>,
 
+    // Messages delivered to the application but not yet acknowledged
+    unacknowledged_messages: HashMap<u64, UnacknowledgedMessage>,
+
     // Join handles to abort spawned futures when the
     // application shuts down, or when the futures complete
     application_reader_join_handle: JoinHandle<()>,
@@ -57,8 +107,8 @@ struct Application {
     application_registrar_handle: ApplicationRegistrarHandle,
     application_writer_handle: ApplicationWriterHandle,
     datagram_sender_handle: DatagramSenderHandle,
-    message_receiver_handles: Arc<Mutex<HashMap<u64, MessageReceiverHandle>>>,
-    message_sender_handles: Arc<Mutex<HashMap<u64, MessageSenderHandle>>>,
+    message_receiver_handles: Arc<DispatchTable<u64, MessageReceiverHandle>>,
+    message_sender_handles: Arc<DispatchTable<u64, MessageSenderHandle>>,
     priority_manager_handle: PriorityManagerHandle,
     workload_manager_handle: WorkloadManagerHandle,
 }
@@ -69,40 +119,149 @@ impl Application {
     async fn handle_application_message(&mut self, application_message: ApplicationMessage) {
         use ApplicationMessage::*;
         match application_message {
-            Shutdown => self.handle_shutdown().await,
-            FromDatagramReceiver(datagram, source_address, destination_address) => {
-                self.handle_from_datagram_receiver(datagram, source_address, destination_address)
+            Shutdown(generation) => {
+                self.handle_from_application_reader_shutdown(generation)
                     .await
             }
+            SessionExpired(generation) => self.handle_session_expired(generation).await,
+            FromApplicationRegistrar(stream) => {
+                self.handle_from_application_registrar(stream).await
+            }
+            FromDatagramReceiver(datagram, source_address, destination_address, arrival) => {
+                self.handle_from_datagram_receiver(
+                    datagram,
+                    source_address,
+                    destination_address,
+                    arrival,
+                )
+                .await
+            }
             FromApplicationReader(message) => self.handle_from_application_reader(message).await,
+            FromApplicationReaderBatch(messages) => {
+                self.handle_from_application_reader_batch(messages).await
+            }
+            FromApplicationReaderAck(message_id) => {
+                self.handle_from_application_reader_ack(message_id)
+            }
             FromMessageReceiver(message_id) => self.handle_from_message_receiver(message_id).await,
+            FromMessageReceiverUnacknowledged(message, ack_tx) => {
+                self.handle_from_message_receiver_unacknowledged(message, ack_tx)
+            }
             FromMessageSender(id) => self.handle_from_message_sender(id).await,
         }
     }
 
     // Upon shutdown close the receiving channel,
     // disconnect and abort all connected actors,
-    // inform the ApplicationRegistrar
+    // inform the ApplicationRegistrar and hand over unacknowledged messages
     async fn handle_shutdown(&mut self) {
         self.rx.close();
 
-        let mut message_receiver_handles = self.message_receiver_handles.lock().await;
-        message_receiver_handles.clear();
-        let mut message_sender_handles = self.message_sender_handles.lock().await;
-        message_sender_handles.clear();
+        self.message_receiver_handles.clear();
+        self.message_sender_handles.clear();
 
-        self.message_receiver_join_handles
-            .iter()
-            .map(|(id, join_handle)| join_handle.abort());
-        self.message_sender_join_handles
-            .iter()
-            .map(|(id, join_handle)| join_handle.abort());
+        for join_handle in self.message_receiver_join_handles.values() {
+            join_handle.abort();
+        }
+        for join_handle in self.message_sender_join_handles.values() {
+            join_handle.abort();
+        }
 
         self.application_writer_join_handle.abort();
         self.application_reader_join_handle.abort();
 
+        let unacknowledged_messages = self
+            .unacknowledged_messages
+            .drain()
+            .map(|(_, unacknowledged_message)| unacknowledged_message.message)
+            .collect();
         self.application_registrar_handle
-            .send(FromApplication(self.application_id))
+            .send(FromApplication(
+                self.application_id,
+                unacknowledged_messages,
+            ))
+            .await;
+    }
+
+    // Shut down when the stream breaks, unless the session can be resumed,
+    // streams replaced by a resumed session are ignored
+    async fn handle_from_application_reader_shutdown(&mut self, generation: u64) {
+        if !self.connected || generation != self.session_generation {
+            return;
+        }
+        if self
+            .registration_message
+            .has_flag(REGISTRATION_FLAGS::RESUME)
+        {
+            self.handle_disconnect().await;
+        } else {
+            self.handle_shutdown().await;
+        }
+    }
+
+    // Detach the stream from the ApplicationWriter and start the grace period,
+    // in-flight MessageSenders and MessageReceivers keep running
+    async fn handle_disconnect(&mut self) {
+        self.connected = false;
+        self.session_generation += 1;
+        self.application_reader_join_handle.abort();
+        let _ = self
+            .application_writer_handle
+            .tx
+            .send(ApplicationWriterMessage::FromApplication(None, Vec::new()))
+            .await;
+
+        let generation = self.session_generation;
+        let application_handle = self.application_handle.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(CONFIG.SESSION_GRACE)).await;
+            let _ = application_handle
+                .send(ApplicationMessage::SessionExpired(generation))
+                .await;
+        });
+    }
+
+    // Shut down if the session was not resumed within the grace period
+    async fn handle_session_expired(&mut self, generation: u64) {
+        if !self.connected && generation == self.session_generation {
+            self.handle_shutdown().await;
+        }
+    }
+
+    // Resume the session with the stream handed over by the ApplicationRegistrar,
+    // the buffered deliveries and the unacknowledged messages, which may have
+    // been lost with the broken stream, are then written to the new stream,
+    // their MessageReceivers keep waiting for the acknowledgements. The
+    // application may resume before the broken stream is noticed, the
+    // stream is then replaced
+    async fn handle_from_application_registrar(&mut self, stream: UnixStream) {
+        let Ok((read_stream, write_stream)) = split_unix_stream(stream) else {
+            return;
+        };
+        if self.connected {
+            self.session_generation += 1;
+            self.application_reader_join_handle.abort();
+        }
+        self.connected = true;
+        self.application_reader_join_handle = ApplicationReader::start(
+            read_stream,
+            &self.registration_message,
+            self.session_generation,
+            self.application_handle.clone(),
+            self.application_writer_handle.clone(),
+        );
+        let unacknowledged_messages = self
+            .unacknowledged_messages
+            .values()
+            .map(|unacknowledged_message| unacknowledged_message.message.clone())
+            .collect();
+        let _ = self
+            .application_writer_handle
+            .tx
+            .send(ApplicationWriterMessage::FromApplication(
+                Some(write_stream),
+                unacknowledged_messages,
+            ))
             .await;
     }
 
@@ -112,11 +271,12 @@ impl Application {
         datagram: HomaDatagram,
         source_address: Ipv4Addr,
         destination_address: Ipv4Addr,
+        arrival: Instant,
     ) {
         use crate::models::datagram::HomaDatagramType::*;
         match datagram.datagram_type {
             Data => {
-                self.handle_data_datagram(datagram, source_address, destination_address)
+                self.handle_data_datagram(datagram, source_address, destination_address, arrival)
                     .await
             }
             _ => self.handle_control_datagram(datagram).await,
@@ -130,69 +290,137 @@ impl Application {
         datagram: HomaDatagram,
         source_address: Ipv4Addr,
         destination_address: Ipv4Addr,
+        arrival: Instant,
     ) {
         let message_id = datagram.message_id;
         if self.delivered_messages.contains(&message_id) {
             return;
         }
 
-        let mut message_receivers = self.message_receiver_handles.lock().await;
-        if let Some(message_receiver_handle) = message_receivers.get(&message_id) {
+        if let Some(message_receiver_handle) = self.message_receiver_handles.get(&message_id) {
             message_receiver_handle.tx.send(datagram).await;
             return;
         }
 
+        // A finishing MessageReceiver removes its handle before it exits
+        if self.message_receiver_join_handles.contains_key(&message_id) {
+            return;
+        }
+
         let (message_receiver_handle, join_handle) = MessageReceiverHandle::new(
             datagram,
             source_address,
             destination_address,
+            arrival,
             self.application_handle.clone(),
             self.application_writer_handle.clone(),
             self.datagram_sender_handle.clone(),
             self.priority_manager_handle.clone(),
             self.workload_manager_handle.clone(),
+            self.acknowledge,
         );
-        message_receivers.insert(message_id, message_receiver_handle);
+        self.message_receiver_handles
+            .insert(message_id, message_receiver_handle);
         self.message_receiver_join_handles
             .insert(message_id, join_handle);
     }
 
     // Forward datagram to MessageSender
     async fn handle_control_datagram(&mut self, datagram: HomaDatagram) {
-        if let Some(message_sender_handle) = self
-            .message_sender_handles
-            .lock()
-            .await
-            .get(&datagram.message_id)
-        {
+        if let Some(message_sender_handle) = self.message_sender_handles.get(&datagram.message_id) {
             message_sender_handle.tx.send(datagram).await;
         }
     }
 
     // Spawn a new MessageSender after receiving message from ApplicationReader
     async fn handle_from_application_reader(&mut self, mut message: HomaMessage) {
+        let source_address = Ipv4Addr::from(message.source_address);
+        if !self.datagram_sender_handle.can_send_from(source_address) {
+            let error = format!("Source address {} is not bound", source_address);
+            let _ = self
+                .application_writer_handle
+                .tx
+                .send(ApplicationWriterMessage::FromMessageSender(
+                    HomaSendFailure::from_message(&message, error),
+                ))
+                .await;
+            return;
+        }
         message.id = rand::random();
         let message_id = message.id;
-        let mut message_senders = self.message_sender_handles.lock().await;
-        if message_senders.contains_key(&message_id) {
+        if self.message_sender_handles.contains_key(&message_id) {
             return;
         }
         let (message_sender_handle, join_handle) = MessageSenderHandle::new(
             message,
             self.application_handle.clone(),
+            self.application_writer_handle.clone(),
             self.datagram_sender_handle.clone(),
             self.priority_manager_handle.clone(),
             self.workload_manager_handle.clone(),
         );
-        message_senders.insert(message_id, message_sender_handle);
+        self.message_sender_handles
+            .insert(message_id, message_sender_handle);
         self.message_sender_join_handles
             .insert(message_id, join_handle);
     }
 
+    // Spawn a MessageSender for each message in the batch
+    async fn handle_from_application_reader_batch(&mut self, messages: Vec<HomaMessage>) {
+        for message in messages {
+            self.handle_from_application_reader(message).await;
+        }
+    }
+
+    // Release the MessageReceiver waiting for the acknowledgement
+    fn handle_from_application_reader_ack(&mut self, message_id: u64) {
+        if let Some(unacknowledged_message) = self.unacknowledged_messages.remove(&message_id) {
+            if let Some(ack_tx) = unacknowledged_message.ack_tx {
+                let _ = ack_tx.send(());
+            }
+        }
+    }
+
+    // Keep the delivered message until it is acknowledged
+    fn handle_from_message_receiver_unacknowledged(
+        &mut self,
+        message: HomaMessage,
+        ack_tx: oneshot::Sender<()>,
+    ) {
+        self.unacknowledged_messages.insert(
+            message.id,
+            UnacknowledgedMessage {
+                message,
+                ack_tx: Some(ack_tx),
+            },
+        );
+    }
+
+    // Redeliver messages left unacknowledged by a previous connection,
+    // keep tracking them if the application still acknowledges messages
+    async fn redeliver_unacknowledged_messages(&mut self, messages: Vec<HomaMessage>) {
+        for message in messages {
+            if self.acknowledge {
+                self.unacknowledged_messages.insert(
+                    message.id,
+                    UnacknowledgedMessage {
+                        message: message.clone(),
+                        ack_tx: None,
+                    },
+                );
+            }
+            let _ = self
+                .application_writer_handle
+                .tx
+                .send(ApplicationWriterMessage::FromMessageReceiver(message))
+                .await;
+        }
+    }
+
     // Disconnect and abort MessageReceiver
     async fn handle_from_message_receiver(&mut self, id: u64) {
         self.delivered_messages.insert(id);
-        self.message_receiver_handles.lock().await.remove(&id);
+        self.message_receiver_handles.remove(&id);
         if let Some(join_handle) = self.message_receiver_join_handles.remove(&id) {
             join_handle.abort();
         }
@@ -200,7 +428,7 @@ impl Application {
 
     // Disconnect and abort MessageSender
     async fn handle_from_message_sender(&mut self, id: u64) {
-        self.message_sender_handles.lock().await.remove(&id);
+        self.message_sender_handles.remove(&id);
         if let Some(join_handle) = self.message_sender_join_handles.remove(&id) {
             join_handle.abort();
         }
@@ -210,15 +438,24 @@ impl Application {
 #[allow(unused)]
 #[derive(Debug)]
 pub enum ApplicationMessage {
-    Shutdown,
-    FromDatagramReceiver(HomaDatagram, Ipv4Addr, Ipv4Addr),
+    Shutdown(u64),
+    SessionExpired(u64),
+    FromApplicationRegistrar(UnixStream),
+    // Datagram with its source and destination address and arrival instant
+    FromDatagramReceiver(HomaDatagram, Ipv4Addr, Ipv4Addr, Instant),
     FromApplicationReader(HomaMessage),
+    FromApplicationReaderBatch(Vec<HomaMessage>),
+    FromApplicationReaderAck(u64),
     FromMessageReceiver(u64),
+    FromMessageReceiverUnacknowledged(HomaMessage, oneshot::Sender<()>),
     FromMessageSender(u64),
 }
 
 // Receive ApplicationMessages and handle them
-async fn run_application(mut application: Application) {
+async fn run_application(mut application: Application, unacknowledged_messages: Vec<HomaMessage>) {
+    application
+        .redeliver_unacknowledged_messages(unacknowledged_messages)
+        .await;
     while let Some(application_message) = application.rx.recv().await {
         application
             .handle_application_message(application_message)
@@ -229,15 +466,18 @@ async fn run_application(mut application: Application) {
 #[derive(Clone)]
 pub struct ApplicationHandle {
     tx: Sender<ApplicationMessage>,
-    pub message_senders: Arc<Mutex<HashMap<u64, MessageSenderHandle>>>,
-    pub message_receivers: Arc<Mutex<HashMap<u64, MessageReceiverHandle>>>,
+    pub message_senders: Arc<DispatchTable<u64, MessageSenderHandle>>,
+    pub message_receivers: Arc<DispatchTable<u64, MessageReceiverHandle>>,
+    // Datagrams dropped because the queue of their actor was full
+    dropped_datagrams: Arc<AtomicU64>,
 }
 
 impl ApplicationHandle {
     // Start Application actor, return the actor and join jandles
     pub fn new(
-        id: u32,
+        registration_message: HomaRegistrationMessage,
         stream: UnixStream,
+        unacknowledged_messages: Vec<HomaMessage>,
 
         application_registrar_handle: ApplicationRegistrarHandle,
         datagram_sender_handle: DatagramSenderHandle,
@@ -247,27 +487,39 @@ impl ApplicationHandle {
         let (read_stream, write_stream) = split_unix_stream(stream)?;
 
         let (application_writer_handle, application_writer_join_handle) =
-            ApplicationWriterHandle::new(write_stream);
+            ApplicationWriterHandle::new(write_stream, &registration_message);
 
         let (tx, rx) = channel::<ApplicationMessage>(1000);
 
-        let message_senders = Arc::new(Mutex::new(HashMap::new()));
-        let message_receivers = Arc::new(Mutex::new(HashMap::new()));
+        let message_senders = Arc::new(DispatchTable::new());
+        let message_receivers = Arc::new(DispatchTable::new());
 
         let application_handle = Self {
             tx,
             message_senders: Arc::clone(&message_senders),
             message_receivers: Arc::clone(&message_receivers),
+            dropped_datagrams: Arc::new(AtomicU64::new(0)),
         };
 
-        let application_reader_join_handle =
-            ApplicationReader::start(read_stream, application_handle.clone());
+        let application_reader_join_handle = ApplicationReader::start(
+            read_stream,
+            &registration_message,
+            0,
+            application_handle.clone(),
+            application_writer_handle.clone(),
+        );
 
         let application = Application {
-            application_id: id,
+            application_id: registration_message.application_id,
+            acknowledge: registration_message.has_flag(REGISTRATION_FLAGS::ACK),
+            registration_message,
             rx,
 
+            connected: true,
+            session_generation: 0,
+
             delivered_messages: HashSet::new(),
+            unacknowledged_messages: HashMap::new(),
 
             application_reader_join_handle,
             application_writer_join_handle,
@@ -283,12 +535,25 @@ impl ApplicationHandle {
             priority_manager_handle,
             workload_manager_handle,
         };
-        let join_handle = tokio::spawn(run_application(application));
+        let join_handle = tokio::spawn(run_application(application, unacknowledged_messages));
 
         Ok((application_handle, join_handle))
     }
 
+    // Handle to a channel standing in for the Application actor,
+    // used by the simulator to collect completions
+    #[cfg(feature = "sim")]
+    pub(crate) fn detached(tx: Sender<ApplicationMessage>) -> Self {
+        Self {
+            tx,
+            message_senders: Arc::new(DispatchTable::new()),
+            message_receivers: Arc::new(DispatchTable::new()),
+            dropped_datagrams: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
     // Async send an ApplicationMessage to the Application actor
+    #[allow(clippy::result_large_err)]
     pub async fn send(
         &self,
         application_message: ApplicationMessage,
@@ -297,6 +562,7 @@ impl ApplicationHandle {
     }
 
     // Blocking send an ApplicationMessage to the Application actor
+    #[allow(clippy::result_large_err)]
     pub fn blocking_send(
         &self,
         application_message: ApplicationMessage,
@@ -304,56 +570,74 @@ impl ApplicationHandle {
         self.tx.blocking_send(application_message)
     }
 
-    // Send data HomaDatagram to existing MessageReceiver
-    // or send to Application
+    pub fn dropped_datagrams(&self) -> u64 {
+        self.dropped_datagrams.load(Ordering::Relaxed)
+    }
+
+    // Count the datagram as dropped if its queue was full, return false
+    // then, a datagram for an actor that is finishing is discarded silently
+    fn count_dropped<T>(&self, result: Result<(), TrySendError<T>>) -> bool {
+        match result {
+            Err(TrySendError::Full(_)) => {
+                self.dropped_datagrams.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    // Stop dispatching datagrams to the MessageReceiver,
+    // called before it closes its channel
+    pub fn remove_message_receiver(&self, message_id: u64) {
+        self.message_receivers.remove(&message_id);
+    }
+
+    // Queue data HomaDatagram to existing MessageReceiver
+    // or to Application
     fn handle_data_datagram(
         &self,
         datagram: HomaDatagram,
         source_address: Ipv4Addr,
         destination_address: Ipv4Addr,
-    ) {
+        arrival: Instant,
+    ) -> bool {
         use super::application::ApplicationMessage::FromDatagramReceiver;
-        if let Some(message_receiver_handle) = self
-            .message_receivers
-            .blocking_lock()
-            .get(&datagram.message_id)
-        {
-            let _ = message_receiver_handle.tx.blocking_send(datagram);
-            return;
+        if let Some(message_receiver_handle) = self.message_receivers.get(&datagram.message_id) {
+            return self.count_dropped(message_receiver_handle.tx.try_send(datagram));
         }
-        let _ = self.blocking_send(FromDatagramReceiver(
+        self.count_dropped(self.tx.try_send(FromDatagramReceiver(
             datagram,
             source_address,
             destination_address,
-        ));
+            arrival,
+        )))
     }
 
-    // Send control HomaDatagram to existing MessageSender
-    fn handle_control_datagram(&self, datagram: HomaDatagram) {
-        if let Some(message_sender_handle) = self
-            .message_senders
-            .blocking_lock()
-            .get(&datagram.message_id)
-        {
-            let _ = message_sender_handle.tx.blocking_send(datagram);
+    // Queue control HomaDatagram to existing MessageSender
+    fn handle_control_datagram(&self, datagram: HomaDatagram) -> bool {
+        match self.message_senders.get(&datagram.message_id) {
+            Some(message_sender_handle) => {
+                self.count_dropped(message_sender_handle.tx.try_send(datagram))
+            }
+            None => true,
         }
     }
 
-    // Multiplex and handle HomaDatagram types
-    pub fn blocking_send_datagram(
+    // Multiplex and handle HomaDatagram types without blocking,
+    // return false if the datagram was dropped as its queue was full
+    pub fn try_send_datagram(
         &self,
         datagram: HomaDatagram,
         source_address: Ipv4Addr,
         destination_address: Ipv4Addr,
-    ) {
+        arrival: Instant,
+    ) -> bool {
         use crate::models::datagram::HomaDatagramType::*;
         match datagram.datagram_type {
             Data => {
-                self.handle_data_datagram(datagram, source_address, destination_address);
-            }
-            _ => {
-                self.handle_control_datagram(datagram);
+                self.handle_data_datagram(datagram, source_address, destination_address, arrival)
             }
+            _ => self.handle_control_datagram(datagram),
         }
     }
 }
@@ -48,7 +48,7 @@ fn run_application_listener(mut application_listener: ApplicationListener) {
         while let Ok((stream, _)) = application_listener.listener.accept() {
             application_listener
                 .application_registrar_handle
-                .blocking_send(FromApplicationListener(stream))
+                .blocking_send(FromApplicationListener(stream, None))
                 .expect("ApplicationListener -> ApplicationRegistrar failed");
         }
         application_listener.listener = ApplicationListener::init()
@@ -6,59 +6,209 @@ This actor is bound to a specific application
 It listens for messages from the application, deserializes them and passes them to the
 Application actor
 
+Messages are read as bincode frames, or as newline-delimited JSON frames if
+the application registered with REGISTRATION_FLAGS::JSON_LINES
+
+If the application registered with REGISTRATION_FLAGS::FD_PASSING, frames
+are read together with any file descriptors passed alongside them, file
+sinks are forwarded to the ApplicationWriter
+
 Upon detecting that the stream from the application is no longer readable,
 it shuts down the write and read sides of the stream and informs the
 Application actor
 */
 use crate::components::application::ApplicationHandle;
 use crate::components::application::ApplicationMessage::FromApplicationReader;
+use crate::components::application::ApplicationMessage::FromApplicationReaderAck;
+use crate::components::application::ApplicationMessage::FromApplicationReaderBatch;
 use crate::components::application::ApplicationMessage::Shutdown;
+use crate::components::application_writer::ApplicationWriterHandle;
+use crate::components::application_writer::ApplicationWriterMessage;
+use crate::models::frame::HomaFdMessage;
+use crate::models::frame::HomaFdSink;
+use crate::models::frame::HomaFrameHeader;
+use crate::models::frame::HomaFrameType;
+use crate::models::frame::HomaJsonFrame;
+use crate::models::frame::HomaJsonFrameError;
+use crate::models::message::HomaFileSink;
 use crate::models::message::HomaMessage;
+use crate::models::registration::HomaRegistrationMessage;
+use crate::models::registration::REGISTRATION_FLAGS;
+use crate::utils::recv_exact_with_fds;
+use async_std::io::BufReader;
 use async_std::os::unix::net::UnixStream as AsyncUnixStream;
+use bincode::deserialize;
+use std::fs::File;
+use std::os::fd::OwnedFd;
 use std::os::unix::net::UnixStream;
+use tokio::io::unix::AsyncFd;
 use tokio::task::JoinHandle;
 
 pub struct ApplicationReader {
     stream: UnixStream,
     application_handle: ApplicationHandle,
+    application_writer_handle: ApplicationWriterHandle,
+    json_lines: bool,
+    fd_passing: bool,
+    // Session generation of the stream, reported when it breaks
+    session_generation: u64,
 }
 
 impl ApplicationReader {
     // Spawn the ApplicationReader task as a future
-    pub fn start(stream: UnixStream, application_handle: ApplicationHandle) -> JoinHandle<()> {
+    pub fn start(
+        stream: UnixStream,
+        registration_message: &HomaRegistrationMessage,
+        session_generation: u64,
+        application_handle: ApplicationHandle,
+        application_writer_handle: ApplicationWriterHandle,
+    ) -> JoinHandle<()> {
         let application_reader = ApplicationReader {
             stream,
             application_handle,
+            application_writer_handle,
+            json_lines: registration_message.has_flag(REGISTRATION_FLAGS::JSON_LINES),
+            fd_passing: registration_message.has_flag(REGISTRATION_FLAGS::FD_PASSING),
+            session_generation,
         };
         tokio::spawn(run_application_reader(application_reader))
     }
+
+    // Send the message to the Application actor
+    async fn handle_message(&self, message: HomaMessage) {
+        self.application_handle
+            .send(FromApplicationReader(message))
+            .await
+            .expect("ApplicationReader -> Application failed");
+    }
+
+    // Send the batch of messages to the Application actor as a single event
+    async fn handle_batch(&self, messages: Vec<HomaMessage>) {
+        self.application_handle
+            .send(FromApplicationReaderBatch(messages))
+            .await
+            .expect("ApplicationReader -> Application failed");
+    }
+
+    // Send the acknowledgement of a delivered message to the Application actor
+    async fn handle_ack(&self, message_id: u64) {
+        self.application_handle
+            .send(FromApplicationReaderAck(message_id))
+            .await
+            .expect("ApplicationReader -> Application failed");
+    }
+
+    // Listen for bincode frames until the stream fails
+    async fn read_bincode_frames(&self, stream: &mut AsyncUnixStream) {
+        while let Ok((header, body)) = HomaFrameHeader::read_frame(stream).await {
+            if self
+                .handle_frame(header.frame_type, &body, None)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    // Listen for bincode frames and the file descriptors passed
+    // alongside them until the stream fails
+    async fn read_fd_frames(&self) -> Result<(), String> {
+        let stream = self.stream.try_clone().map_err(|e| e.to_string())?;
+        stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let socket = AsyncFd::new(OwnedFd::from(stream)).map_err(|e| e.to_string())?;
+        loop {
+            let mut header_bytes = [0u8; 8];
+            let mut fds = recv_exact_with_fds(&socket, &mut header_bytes).await?;
+            let header = HomaFrameHeader::from_bytes(header_bytes)?;
+            header.check_length()?;
+
+            let mut body = vec![0; header.length as usize];
+            fds.append(&mut recv_exact_with_fds(&socket, &mut body).await?);
+            let file = fds.into_iter().next().map(File::from);
+
+            self.handle_frame(header.frame_type, &body, file).await?;
+        }
+    }
+
+    // Multiplex and handle HomaFrameTypes
+    async fn handle_frame(
+        &self,
+        frame_type: HomaFrameType,
+        body: &[u8],
+        file: Option<File>,
+    ) -> Result<(), String> {
+        use HomaFrameType::*;
+        let file = || file.ok_or("frame without file descriptor".to_string());
+        match frame_type {
+            Message => {
+                let message = deserialize::<HomaMessage>(body).map_err(|e| e.to_string())?;
+                self.handle_message(message).await;
+            }
+            Batch => {
+                let messages = deserialize::<Vec<HomaMessage>>(body).map_err(|e| e.to_string())?;
+                self.handle_batch(messages).await;
+            }
+            FdMessage => {
+                let fd_message = deserialize::<HomaFdMessage>(body).map_err(|e| e.to_string())?;
+                self.handle_message(fd_message.into_message(file()?)?).await;
+            }
+            FdSink => {
+                let fd_sink = deserialize::<HomaFdSink>(body).map_err(|e| e.to_string())?;
+                let file_sink = HomaFileSink {
+                    file: file()?,
+                    offset: fd_sink.offset,
+                };
+                self.application_writer_handle
+                    .tx
+                    .send(ApplicationWriterMessage::FromApplicationReader(file_sink))
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            Ack => {
+                let message_id = deserialize::<u64>(body).map_err(|e| e.to_string())?;
+                self.handle_ack(message_id).await;
+            }
+            Chunk => return Err("unexpected chunk frame".to_string()),
+            SendFailure => return Err("unexpected send failure frame".to_string()),
+        }
+        Ok(())
+    }
+
+    // Listen for JSON frames until the stream fails, frames other than
+    // messages, batches and acks are ignored and invalid lines are skipped
+    async fn read_json_frames(&self, stream: &mut AsyncUnixStream) {
+        use HomaJsonFrame::*;
+        let mut reader = BufReader::new(stream);
+        loop {
+            match HomaJsonFrame::from_buf_reader(&mut reader).await {
+                Ok(frame) => match frame {
+                    Message(message) => self.handle_message(message).await,
+                    Batch { messages } => self.handle_batch(messages).await,
+                    Ack { message_id } => self.handle_ack(message_id).await,
+                    _ => (),
+                },
+                Err(HomaJsonFrameError::Invalid(_)) => (),
+                Err(HomaJsonFrameError::Closed(_)) => break,
+            }
+        }
+    }
 }
 
 // Listen for messages, deserialize them and send them to the Application actor
 async fn run_application_reader(application_reader: ApplicationReader) {
     let mut stream = AsyncUnixStream::from(application_reader.stream.try_clone().unwrap());
-    loop {
-        match HomaMessage::from_unix_stream(&mut stream).await {
-            Ok(message_option) => {
-                if let Some(message) = message_option {
-                    application_reader
-                        .application_handle
-                        .send(FromApplicationReader(message))
-                        .await
-                        .expect("ApplicationReader -> Application failed");
-                } else {
-                    break;
-                }
-            }
-            Err(_) => {
-                break;
-            }
-        }
+    if application_reader.json_lines {
+        application_reader.read_json_frames(&mut stream).await;
+    } else if application_reader.fd_passing {
+        let _ = application_reader.read_fd_frames().await;
+    } else {
+        application_reader.read_bincode_frames(&mut stream).await;
     }
     let _ = stream.shutdown(std::net::Shutdown::Both);
     application_reader
         .application_handle
-        .send(Shutdown)
+        .send(Shutdown(application_reader.session_generation))
         .await
         .expect("ApplicationReader -> Application failed");
 }
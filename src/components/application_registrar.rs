@@ -4,28 +4,136 @@ ApplicationRegistrar actor
 This actor is responsible for registering/creating new Applications,
 checking that there is no existing application with the same id
 
-It also listens for Applications shutting down and degestering them
+It also listens for Applications shutting down and degestering them, keeping
+their unacknowledged messages to be redelivered when they register again. At
+most the session buffer of messages are kept for each application, within a
+limit of bytes for all of them, and they are dropped after the session grace
+period, the oldest first when the limit is reached
+
+Applications registering with REGISTRATION_FLAGS::RESUME are given a session
+token, presenting it again hands the new stream to the existing Application
 */
 use crate::components::application::ApplicationHandle;
+use crate::components::application::ApplicationMessage::FromApplicationRegistrar;
 use crate::components::datagram_sender::DatagramSenderHandle;
 use crate::components::priority_manager::PriorityManagerHandle;
 use crate::components::workload_manager::WorkloadManagerHandle;
+use crate::config::CONFIG;
+use crate::config::CONST;
+use crate::dispatch_table::DispatchTable;
+use crate::models::message::HomaMessage;
 use crate::models::registration::HomaRegistrationMessage;
+use crate::models::registration::REGISTRATION_FLAGS;
 use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::os::unix::net::UnixStream;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 use tokio::sync::mpsc::channel;
 use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
 
+// Unacknowledged messages of an application which shut down
+struct UnacknowledgedMessages {
+    messages: Vec<HomaMessage>,
+    bytes: u64,
+    expiry: Instant,
+}
+
+// Unacknowledged messages of the applications which shut down, bounded in
+// messages for each application and in bytes for all, expired after the
+// lifetime, entries are in the order the applications shut down
+struct UnacknowledgedStore {
+    message_limit: usize,
+    byte_limit: u64,
+    lifetime: Duration,
+    entries: VecDeque<(u32, UnacknowledgedMessages)>,
+    bytes: u64,
+}
+
+impl UnacknowledgedStore {
+    fn new(message_limit: usize, byte_limit: u64, lifetime: Duration) -> Self {
+        Self {
+            message_limit,
+            byte_limit,
+            lifetime,
+            entries: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    // Drop the entries whose lifetime is over
+    fn expire(&mut self, now: Instant) {
+        while let Some((_, unacknowledged_messages)) = self.entries.front() {
+            if unacknowledged_messages.expiry > now {
+                break;
+            }
+            self.bytes -= unacknowledged_messages.bytes;
+            self.entries.pop_front();
+        }
+    }
+
+    // Keep the messages within the limits, evicting the oldest entries
+    // to make room for them, expired entries are dropped along
+    fn insert(&mut self, id: u32, messages: Vec<HomaMessage>, now: Instant) {
+        self.take(id, now);
+        let mut bytes = 0;
+        let mut kept_messages = Vec::new();
+        for message in messages.into_iter().take(self.message_limit) {
+            let length = message.content.len() as u64;
+            if bytes + length > self.byte_limit {
+                break;
+            }
+            bytes += length;
+            kept_messages.push(message);
+        }
+        if kept_messages.is_empty() {
+            return;
+        }
+        while self.bytes + bytes > self.byte_limit {
+            let Some((_, unacknowledged_messages)) = self.entries.pop_front() else {
+                break;
+            };
+            self.bytes -= unacknowledged_messages.bytes;
+        }
+        self.bytes += bytes;
+        self.entries.push_back((
+            id,
+            UnacknowledgedMessages {
+                messages: kept_messages,
+                bytes,
+                expiry: now + self.lifetime,
+            },
+        ));
+    }
+
+    // Remove the messages of the application
+    fn take(&mut self, id: u32, now: Instant) -> Vec<HomaMessage> {
+        self.expire(now);
+        let Some(i) = self
+            .entries
+            .iter()
+            .position(|(entry_id, _)| *entry_id == id)
+        else {
+            return Vec::new();
+        };
+        let (_, unacknowledged_messages) = self.entries.remove(i).unwrap();
+        self.bytes -= unacknowledged_messages.bytes;
+        unacknowledged_messages.messages
+    }
+}
+
 pub struct ApplicationRegistrar {
     rx: Receiver<ApplicationRegistrarMessage>,
 
-    application_handles: Arc<Mutex<HashMap<u32, ApplicationHandle>>>,
+    application_handles: Arc<DispatchTable<u32, ApplicationHandle>>,
     application_join_handles: HashMap<u32, JoinHandle<()>>,
+    unacknowledged_store: UnacknowledgedStore,
+    session_tokens: HashMap<u32, u64>,
     application_registrar_handle: ApplicationRegistrarHandle,
     datagram_sender_handle: DatagramSenderHandle,
     priority_manager_handle: PriorityManagerHandle,
@@ -33,8 +141,14 @@ pub struct ApplicationRegistrar {
 }
 
 impl ApplicationRegistrar {
-    // Get registration message from new stream and create the application
+    // Get registration message from new stream and create the application,
+    // the reads and writes of the registration time out so that a stalled
+    // application does not hold up the others
     fn handle_from_application_listener(&mut self, mut stream: UnixStream) -> Result<(), String> {
+        set_registration_timeout(
+            &stream,
+            Some(Duration::from_millis(CONST::REGISTRATION_TIMEOUT)),
+        )?;
         match HomaRegistrationMessage::from_unix_stream(&mut stream) {
             Ok(registration_message) => self.create_application(registration_message, stream),
             Err(e) => Err(e),
@@ -42,27 +156,49 @@ impl ApplicationRegistrar {
     }
 
     // Check Application with the id does not exist and then
-    // spawn the Application
+    // spawn the Application, or resume its session if the
+    // application presented the matching token
     fn create_application(
         &mut self,
         registration_message: HomaRegistrationMessage,
-        stream: UnixStream,
+        mut stream: UnixStream,
     ) -> Result<(), String> {
-        let mut application_handles = self.application_handles.lock().unwrap();
         let id = registration_message.application_id;
+        let resume = registration_message.has_flag(REGISTRATION_FLAGS::RESUME);
+        let resume_token = if resume {
+            HomaRegistrationMessage::read_resume_token(&mut stream)?
+        } else {
+            0
+        };
 
-        match application_handles.get(&id) {
+        match self.application_handles.get(&id) {
+            Some(application_handle)
+                if resume_token != 0 && self.session_tokens.get(&id) == Some(&resume_token) =>
+            {
+                HomaRegistrationMessage::write_resume_token(&mut stream, resume_token)?;
+                set_registration_timeout(&stream, None)?;
+                application_handle
+                    .blocking_send(FromApplicationRegistrar(stream))
+                    .map_err(|e| e.to_string())
+            }
             Some(_) => Err("ApplicationRegistrar tried to create existing application".to_string()),
             _ => {
+                if resume {
+                    let session_token = rand::random::<u64>().max(1);
+                    HomaRegistrationMessage::write_resume_token(&mut stream, session_token)?;
+                    self.session_tokens.insert(id, session_token);
+                }
+                set_registration_timeout(&stream, None)?;
                 let (application_handle, join_handle) = ApplicationHandle::new(
-                    id,
+                    registration_message,
                     stream,
+                    self.unacknowledged_store.take(id, Instant::now()),
                     self.application_registrar_handle.clone(),
                     self.datagram_sender_handle.clone(),
                     self.priority_manager_handle.clone(),
                     self.workload_manager_handle.clone(),
                 )?;
-                application_handles.insert(id, application_handle.clone());
+                self.application_handles.insert(id, application_handle);
                 self.application_join_handles.insert(id, join_handle);
                 Ok(())
             }
@@ -70,18 +206,31 @@ impl ApplicationRegistrar {
     }
 
     // Listen for Application shutting down and remove from the register
-    fn handle_from_application(&mut self, id: u32) {
-        let mut application_handles = self.application_handles.lock().unwrap();
-        application_handles.remove(&id);
+    fn handle_from_application(&mut self, id: u32, unacknowledged_messages: Vec<HomaMessage>) {
+        self.unacknowledged_store
+            .insert(id, unacknowledged_messages, Instant::now());
+        self.session_tokens.remove(&id);
+        self.application_handles.remove(&id);
         if let Some(join_handle) = self.application_join_handles.get(&id) {
             join_handle.abort();
         }
     }
 }
 
+// Set the timeout of the reads and writes on the stream,
+// none once the registration is complete
+fn set_registration_timeout(stream: &UnixStream, timeout: Option<Duration>) -> Result<(), String> {
+    stream
+        .set_read_timeout(timeout)
+        .map_err(|e| e.to_string())?;
+    stream.set_write_timeout(timeout).map_err(|e| e.to_string())
+}
+
+// Streams of registering applications, with a channel to report the
+// outcome of the registration to if the sender waits for it
 pub enum ApplicationRegistrarMessage {
-    FromApplicationListener(UnixStream),
-    FromApplication(u32),
+    FromApplicationListener(UnixStream, Option<oneshot::Sender<Result<(), String>>>),
+    FromApplication(u32, Vec<HomaMessage>),
 }
 
 // Multiplex ApplicationRegistrarMessages and handle them
@@ -89,10 +238,15 @@ fn run_application_registrar(mut application_registrar: ApplicationRegistrar) {
     use ApplicationRegistrarMessage::*;
     while let Some(application_registrar_message) = application_registrar.rx.blocking_recv() {
         match application_registrar_message {
-            FromApplicationListener(stream) => {
-                let _ = application_registrar.handle_from_application_listener(stream);
+            FromApplicationListener(stream, result_tx) => {
+                let result = application_registrar.handle_from_application_listener(stream);
+                if let Some(result_tx) = result_tx {
+                    let _ = result_tx.send(result);
+                }
+            }
+            FromApplication(id, unacknowledged_messages) => {
+                application_registrar.handle_from_application(id, unacknowledged_messages)
             }
-            FromApplication(id) => application_registrar.handle_from_application(id),
         }
     }
 }
@@ -105,7 +259,7 @@ pub struct ApplicationRegistrarHandle {
 impl ApplicationRegistrarHandle {
     // Initialize ApplicationRegister and return the handle
     pub fn new(
-        application_handles: Arc<Mutex<HashMap<u32, ApplicationHandle>>>,
+        application_handles: Arc<DispatchTable<u32, ApplicationHandle>>,
         priority_manager_handle: PriorityManagerHandle,
         workload_manager_handle: WorkloadManagerHandle,
         datagram_sender_handle: DatagramSenderHandle,
@@ -116,6 +270,12 @@ impl ApplicationRegistrarHandle {
             rx,
             application_handles,
             application_join_handles: HashMap::new(),
+            unacknowledged_store: UnacknowledgedStore::new(
+                CONFIG.SESSION_BUFFER,
+                CONST::UNACKNOWLEDGED_STORE_BYTES,
+                Duration::from_millis(CONFIG.SESSION_GRACE),
+            ),
+            session_tokens: HashMap::new(),
             application_registrar_handle: application_registrar_handle.clone(),
             priority_manager_handle,
             workload_manager_handle,
@@ -133,6 +293,19 @@ impl ApplicationRegistrarHandle {
         self.tx.send(application_registrar_message).await
     }
 
+    // Register the application of the stream and wait until it is
+    // registered, or fails to
+    pub async fn register(&self, stream: UnixStream) -> Result<(), String> {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.send(ApplicationRegistrarMessage::FromApplicationListener(
+            stream,
+            Some(result_tx),
+        ))
+        .await
+        .map_err(|e| e.to_string())?;
+        result_rx.await.map_err(|e| e.to_string())?
+    }
+
     // Blocking send to ApplicationRegistrar through ApplicationRegistrarHandle
     pub fn blocking_send(
         &self,
@@ -141,3 +314,45 @@ impl ApplicationRegistrarHandle {
         self.tx.blocking_send(application_registrar_message)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::UnacknowledgedStore;
+    use crate::models::message::HomaMessage;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    fn messages(lengths: &[usize]) -> Vec<HomaMessage> {
+        lengths
+            .iter()
+            .map(|length| HomaMessage {
+                content: vec![0; *length],
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unacknowledged_store_test() {
+        let now = Instant::now();
+        let mut store = UnacknowledgedStore::new(3, 100, Duration::from_secs(1));
+
+        // Messages beyond the message limit are dropped
+        store.insert(1, messages(&[10, 10, 10, 10]), now);
+        assert_eq!(store.take(1, now).len(), 3);
+        assert!(store.take(1, now).is_empty());
+
+        // The oldest entries are evicted to stay within the byte limit
+        store.insert(1, messages(&[40]), now);
+        store.insert(2, messages(&[40]), now);
+        store.insert(3, messages(&[40]), now);
+        assert!(store.take(1, now).is_empty());
+        assert_eq!(store.take(2, now).len(), 1);
+        assert_eq!(store.take(3, now).len(), 1);
+
+        // Entries are dropped once their lifetime is over
+        store.insert(4, messages(&[10]), now);
+        assert!(store.take(4, now + Duration::from_secs(1)).is_empty());
+        assert_eq!(store.bytes, 0);
+    }
+}
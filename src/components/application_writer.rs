@@ -3,57 +3,683 @@ ApplicationWriter actor
 
 This actor is responsible for listening for received messages,
 serializing them and delivering them to the application via the stream
+
+If the application registered for metadata, each message is followed by
+its HomaMessageMetadata within the same frame, or by a metadata record
+for applications using JSON frames
+
+Applications using file descriptor passing can supply file sinks, the
+content of the next delivered message is then written into the file and
+only an FdMessage frame describing it is written to the stream
+
+Messages of FdMessage frames which could not be sent are reported to the
+application in SendFailure frames, written ahead of the next deliveries
+
+Messages queued at the same time are written with a single write, and are
+coalesced into Batch frames or JSON batch records if the application
+registered for them
+
+Queued messages are delivered shortest first, unless a message has waited
+longer than the aging bound. Applications registered for chunked delivery
+receive large messages in Chunk frames, so that shorter messages completing
+in the meantime are delivered between the chunks
+
+When the stream breaks the actor keeps queueing messages up to the session
+buffer limit, until the Application actor attaches the stream of a resumed
+session or shuts the actor down. The unacknowledged messages handed over with
+the stream of a resumed session are delivered again unless still queued, and
+messages partially delivered in chunks are delivered from their start. The
+messages and send failures of a failed write are queued again, so that they
+are written once the stream of a resumed session is attached
 */
+use crate::config::CONFIG;
+use crate::config::CONST;
+use crate::models::frame::HomaChunk;
+use crate::models::frame::HomaFdMessage;
+use crate::models::frame::HomaFrameHeader;
+use crate::models::frame::HomaFrameType;
+use crate::models::frame::HomaJsonRecord;
+use crate::models::frame::HomaSendFailure;
+use crate::models::message::HomaFileSink;
 use crate::models::message::HomaMessage;
+use crate::models::registration::HomaRegistrationMessage;
+use crate::models::registration::REGISTRATION_FLAGS;
 use async_std::io::WriteExt;
 use async_std::os::unix::net::UnixStream as AsyncUnixStream;
 use bincode::serialize;
+use priority_queue::PriorityQueue;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::os::unix::net::UnixStream;
 use tokio::sync::mpsc::channel;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
 use tokio::task::JoinHandle;
+use tokio::time::Duration;
+use tokio::time::Instant;
+
+// Message waiting to be delivered, offset is the length
+// of the content already delivered in chunks
+struct PendingMessage {
+    message: HomaMessage,
+    offset: usize,
+}
+
+impl PendingMessage {
+    fn remaining_length(&self) -> u64 {
+        (self.message.content.len() - self.offset) as u64
+    }
+}
+
+// Part of the delivery queue taken to be written, the last chunk
+// of a message holds the message to queue it again if the write fails
+enum Delivery {
+    Message(HomaMessage),
+    Chunk(HomaChunk, Option<HomaMessage>),
+}
+
+// Messages waiting to be delivered, ordered by remaining length,
+// messages which have waited longer than the aging bound come first
+struct DeliveryQueue {
+    next_id: u64,
+    queue: PriorityQueue<u64, Reverse<(u64, u64)>>,
+    arrivals: VecDeque<(u64, Instant)>,
+    messages: HashMap<u64, PendingMessage>,
+    // Id in the queue of each queued message by message id
+    message_ids: HashMap<u64, u64>,
+}
+
+impl DeliveryQueue {
+    fn new() -> Self {
+        Self {
+            next_id: 0,
+            queue: PriorityQueue::new(),
+            arrivals: VecDeque::new(),
+            messages: HashMap::new(),
+            message_ids: HashMap::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    fn contains(&self, message_id: u64) -> bool {
+        self.message_ids.contains_key(&message_id)
+    }
+
+    // Deliver the messages partially delivered in chunks from their start
+    fn restart(&mut self) {
+        for (id, pending_message) in self.messages.iter_mut() {
+            if pending_message.offset > 0 {
+                pending_message.offset = 0;
+                let remaining_length = pending_message.remaining_length();
+                self.queue
+                    .change_priority(id, Reverse((remaining_length, *id)));
+            }
+        }
+    }
+
+    fn push(&mut self, message: HomaMessage) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.arrivals.push_back((id, Instant::now()));
+        self.requeue(id, PendingMessage { message, offset: 0 });
+    }
+
+    // Queue a message again, keeping its original arrival time
+    fn requeue(&mut self, id: u64, pending_message: PendingMessage) {
+        let remaining_length = pending_message.remaining_length();
+        self.queue.push(id, Reverse((remaining_length, id)));
+        self.message_ids.insert(pending_message.message.id, id);
+        self.messages.insert(id, pending_message);
+    }
+
+    // Pop the oldest message if it exceeded the aging bound,
+    // otherwise the message with the least remaining length
+    fn pop(&mut self) -> Option<(u64, PendingMessage)> {
+        while let Some((id, _)) = self.arrivals.front() {
+            if self.messages.contains_key(id) {
+                break;
+            }
+            self.arrivals.pop_front();
+        }
+        let aging = Duration::from_millis(CONFIG.DELIVERY_AGING);
+        let id = match self.arrivals.front() {
+            Some((id, arrival)) if arrival.elapsed() >= aging => {
+                self.queue.remove(id);
+                *id
+            }
+            _ => self.queue.pop()?.0,
+        };
+        let pending_message = self.messages.remove(&id)?;
+        if self.message_ids.get(&pending_message.message.id) == Some(&id) {
+            self.message_ids.remove(&pending_message.message.id);
+        }
+        Some((id, pending_message))
+    }
+}
 
 struct ApplicationWriter {
-    stream: AsyncUnixStream,
-    rx: Receiver<HomaMessage>,
+    // Stream to the application, none while the session is disconnected
+    stream: Option<AsyncUnixStream>,
+    rx: Receiver<ApplicationWriterMessage>,
+    receive_metadata: bool,
+    json_lines: bool,
+    batch: bool,
+    chunked: bool,
+
+    // File sinks supplied by the application, used in order
+    file_sinks: VecDeque<HomaFileSink>,
+    delivery_queue: DeliveryQueue,
+    // Failures of sent messages to report to the application
+    send_failures: VecDeque<HomaSendFailure>,
 }
 
 impl ApplicationWriter {
-    // Serialize and write the message to the stream
-    async fn handle_message(&mut self, message: HomaMessage) -> Option<()> {
-        if let Ok(message_bytes) = serialize(&message) {
-            let mut message_payload = (message_bytes.len() as u64).to_le_bytes().to_vec();
-            message_payload.append(&mut message_bytes.to_vec());
-            if let Err(_) = self.stream.write_all(&message_payload).await {
-                return None;
+    // Multiplex ApplicationWriterMessage types,
+    // queue messages and file sinks
+    fn handle_application_writer_message(
+        &mut self,
+        application_writer_message: ApplicationWriterMessage,
+    ) {
+        use ApplicationWriterMessage::*;
+        match application_writer_message {
+            FromMessageReceiver(message) => self.handle_from_message_receiver(message),
+            FromApplicationReader(file_sink) => self.file_sinks.push_back(file_sink),
+            FromMessageSender(send_failure) => self.handle_from_message_sender(send_failure),
+            FromApplication(stream, messages) => self.handle_from_application(stream, messages),
+        }
+    }
+
+    // Queue the message, dropping it if the session is
+    // disconnected and its buffer is full
+    fn handle_from_message_receiver(&mut self, message: HomaMessage) {
+        if self.stream.is_none() && self.delivery_queue.len() >= CONFIG.SESSION_BUFFER {
+            return;
+        }
+        self.delivery_queue.push(message)
+    }
+
+    // Queue the failure to be reported, dropping it if the session is
+    // disconnected and its buffer is full
+    fn handle_from_message_sender(&mut self, send_failure: HomaSendFailure) {
+        if self.stream.is_none() && self.send_failures.len() >= CONFIG.SESSION_BUFFER {
+            return;
+        }
+        self.send_failures.push_back(send_failure);
+    }
+
+    // Attach the stream of a resumed session along the unacknowledged
+    // messages to deliver again, or detach the broken stream, file sinks
+    // belong to the previous connection and are discarded
+    fn handle_from_application(&mut self, stream: Option<UnixStream>, messages: Vec<HomaMessage>) {
+        self.stream = stream.map(AsyncUnixStream::from);
+        self.file_sinks.clear();
+        if self.stream.is_none() {
+            return;
+        }
+        self.delivery_queue.restart();
+        for message in messages {
+            if !self.delivery_queue.contains(message.id) {
+                self.delivery_queue.push(message);
             }
         }
-        Some(())
+    }
+
+    // Check whether the message should be delivered in chunks
+    fn should_chunk(&self, pending_message: &PendingMessage, budget: usize) -> bool {
+        if !self.chunked || self.json_lines {
+            return false;
+        }
+        if pending_message.offset > 0 {
+            return true;
+        }
+        self.file_sinks.is_empty() && pending_message.remaining_length() > budget as u64
+    }
+
+    // Take deliveries from the queue in order up to the batch limit and
+    // chunk length, chunks of partially delivered messages are queued again
+    fn take_deliveries(&mut self) -> Vec<Delivery> {
+        let mut deliveries = Vec::new();
+        let mut budget = CONST::APPLICATION_WRITER_CHUNK_LENGTH;
+        while budget > 0 && deliveries.len() < CONST::APPLICATION_WRITER_BATCH_LIMIT {
+            let Some((id, mut pending_message)) = self.delivery_queue.pop() else {
+                break;
+            };
+            if !self.should_chunk(&pending_message, budget) {
+                let remaining_length = pending_message.remaining_length() as usize;
+                budget = budget.saturating_sub(remaining_length);
+                deliveries.push(Delivery::Message(pending_message.message));
+                continue;
+            }
+            let chunk =
+                HomaChunk::from_message(&pending_message.message, pending_message.offset, budget);
+            budget -= chunk.content.len();
+            pending_message.offset += chunk.content.len();
+            if chunk.is_last() {
+                deliveries.push(Delivery::Chunk(chunk, Some(pending_message.message)));
+            } else {
+                deliveries.push(Delivery::Chunk(chunk, None));
+                self.delivery_queue.requeue(id, pending_message);
+            }
+        }
+        deliveries
+    }
+
+    // Serialize the frame body, followed by the message metadata
+    // if the application requested it
+    fn serialize_body<T: Serialize>(
+        &self,
+        body: &T,
+        message: &HomaMessage,
+    ) -> bincode::Result<Vec<u8>> {
+        if self.receive_metadata {
+            let metadata = message.metadata.clone().unwrap_or_default();
+            return serialize(&(body, metadata));
+        }
+        serialize(body)
+    }
+
+    // Prefix the frame body with its header
+    fn encode_frame(&self, frame_type: HomaFrameType, body: Vec<u8>) -> Vec<u8> {
+        let header = HomaFrameHeader::new(frame_type, body.len() as u64);
+        let mut message_payload = header.to_bytes().to_vec();
+        message_payload.extend_from_slice(&body);
+        message_payload
+    }
+
+    // Serialize the message as a length prefixed bincode frame
+    fn encode_bincode_frame(&self, message: &HomaMessage) -> Result<Vec<u8>, String> {
+        let message_bytes = self
+            .serialize_body(message, message)
+            .map_err(|e| e.to_string())?;
+        Ok(self.encode_frame(HomaFrameType::Message, message_bytes))
+    }
+
+    // Serialize the chunk as a Chunk frame, the last chunk followed
+    // by the metadata of its message if the application requested it
+    fn encode_chunk_frame(
+        &self,
+        chunk: &HomaChunk,
+        message: Option<&HomaMessage>,
+    ) -> Result<Vec<u8>, String> {
+        let chunk_bytes = if self.receive_metadata && chunk.is_last() {
+            let metadata = message.and_then(|message| message.metadata.clone());
+            serialize(&(chunk, metadata.unwrap_or_default()))
+        } else {
+            serialize(chunk)
+        }
+        .map_err(|e| e.to_string())?;
+        Ok(self.encode_frame(HomaFrameType::Chunk, chunk_bytes))
+    }
+
+    // Serialize the messages as a single Batch frame, each
+    // message followed by its metadata if the application requested it,
+    // or as a JSON batch record for applications using JSON frames
+    fn encode_batch_frame(&self, messages: &[HomaMessage]) -> Result<Vec<u8>, String> {
+        if messages.len() <= 1 {
+            return match messages.first() {
+                Some(message) if self.json_lines => self.encode_json_frames(message),
+                Some(message) => self.encode_bincode_frame(message),
+                None => Ok(Vec::new()),
+            };
+        }
+        if self.json_lines {
+            return self.encode_json_batch_frames(messages);
+        }
+        let messages_bytes = if self.receive_metadata {
+            let messages = messages
+                .iter()
+                .map(|message| (message, message.metadata.clone().unwrap_or_default()))
+                .collect::<Vec<_>>();
+            serialize(&messages)
+        } else {
+            serialize(&messages)
+        }
+        .map_err(|e| e.to_string())?;
+        Ok(self.encode_frame(HomaFrameType::Batch, messages_bytes))
+    }
+
+    // Write the message content into the file sink and serialize
+    // an FdMessage frame describing it, the content is handed back
+    // to the message once written
+    async fn encode_fd_frame(
+        &self,
+        message: &mut HomaMessage,
+        file_sink: HomaFileSink,
+    ) -> Result<Vec<u8>, String> {
+        let fd_message = HomaFdMessage::from_message(message, file_sink.offset);
+        let content = std::mem::take(&mut message.content);
+        let (written, content) = tokio::task::spawn_blocking(move || {
+            let written = file_sink.write(&content);
+            (written, content)
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+        message.content = content;
+        written.map_err(|e| e.to_string())?;
+        let message_bytes = self
+            .serialize_body(&fd_message, message)
+            .map_err(|e| e.to_string())?;
+        Ok(self.encode_frame(HomaFrameType::FdMessage, message_bytes))
+    }
+
+    // Serialize the message as a JSON line, followed by
+    // a metadata line if the application requested it
+    fn encode_json_frames(&self, message: &HomaMessage) -> Result<Vec<u8>, String> {
+        let mut message_payload = HomaJsonRecord::Message(message).to_line()?;
+        if self.receive_metadata {
+            let metadata = message.metadata.clone().unwrap_or_default();
+            message_payload.append(&mut HomaJsonRecord::Metadata(&metadata).to_line()?);
+        }
+        Ok(message_payload)
+    }
+
+    // Serialize the messages as a JSON batch record, followed by a
+    // metadata record for each message if the application requested it
+    fn encode_json_batch_frames(&self, messages: &[HomaMessage]) -> Result<Vec<u8>, String> {
+        let mut messages_payload = HomaJsonRecord::Batch { messages }.to_line()?;
+        if self.receive_metadata {
+            for message in messages {
+                let metadata = message.metadata.clone().unwrap_or_default();
+                messages_payload.append(&mut HomaJsonRecord::Metadata(&metadata).to_line()?);
+            }
+        }
+        Ok(messages_payload)
+    }
+
+    // Append the last batched messages as a Batch frame, so that the
+    // deliveries are written in the order they were taken
+    fn flush_batch(
+        &self,
+        messages: &[HomaMessage],
+        batched: &mut usize,
+        messages_payload: &mut Vec<u8>,
+    ) {
+        let batch = &messages[messages.len() - std::mem::take(batched)..];
+        if let Ok(mut batch_payload) = self.encode_batch_frame(batch) {
+            messages_payload.append(&mut batch_payload);
+        }
+    }
+
+    // Serialize the send failure as a SendFailure frame
+    fn encode_send_failure_frame(&self, send_failure: &HomaSendFailure) -> Result<Vec<u8>, String> {
+        let send_failure_bytes = serialize(send_failure).map_err(|e| e.to_string())?;
+        Ok(self.encode_frame(HomaFrameType::SendFailure, send_failure_bytes))
+    }
+
+    // Serialize the queued send failures and the deliveries and write them
+    // to the stream at once, the stream is detached if the write fails and
+    // the send failures and the delivered messages are queued again
+    async fn handle_deliveries(&mut self, deliveries: Vec<Delivery>) {
+        let mut messages_payload = Vec::new();
+        let mut send_failures = std::mem::take(&mut self.send_failures);
+        for send_failure in &send_failures {
+            if let Ok(mut send_failure_payload) = self.encode_send_failure_frame(send_failure) {
+                messages_payload.append(&mut send_failure_payload);
+            }
+        }
+        // Messages of the deliveries in order, the last batched
+        // messages are encoded once the batch is flushed
+        let mut messages = Vec::new();
+        let mut batched = 0;
+        for delivery in deliveries {
+            let mut message = match delivery {
+                Delivery::Message(message) => message,
+                Delivery::Chunk(chunk, message) => {
+                    self.flush_batch(&messages, &mut batched, &mut messages_payload);
+                    if let Ok(mut chunk_payload) = self.encode_chunk_frame(&chunk, message.as_ref())
+                    {
+                        messages_payload.append(&mut chunk_payload);
+                    }
+                    messages.extend(message);
+                    continue;
+                }
+            };
+            let message_payload = if self.json_lines && !self.batch {
+                self.encode_json_frames(&message)
+            } else if let Some(file_sink) = self.file_sinks.pop_front() {
+                self.flush_batch(&messages, &mut batched, &mut messages_payload);
+                self.encode_fd_frame(&mut message, file_sink).await
+            } else if self.batch {
+                messages.push(message);
+                batched += 1;
+                continue;
+            } else {
+                self.encode_bincode_frame(&message)
+            };
+            if let Ok(mut message_payload) = message_payload {
+                messages_payload.append(&mut message_payload);
+            }
+            messages.push(message);
+        }
+        self.flush_batch(&messages, &mut batched, &mut messages_payload);
+        let written = match self.stream.as_mut() {
+            Some(stream) => {
+                messages_payload.is_empty() || stream.write_all(&messages_payload).await.is_ok()
+            }
+            None => false,
+        };
+        if written {
+            return;
+        }
+        self.stream = None;
+        send_failures.append(&mut self.send_failures);
+        self.send_failures = send_failures;
+        for message in messages {
+            self.delivery_queue.push(message);
+        }
     }
 }
 
-// Receive messages from the receiving channel and handle it
+#[allow(clippy::enum_variant_names)]
+pub enum ApplicationWriterMessage {
+    FromMessageReceiver(HomaMessage),
+    FromApplicationReader(HomaFileSink),
+    FromMessageSender(HomaSendFailure),
+    FromApplication(Option<UnixStream>, Vec<HomaMessage>),
+}
+
+// Queue messages from the receiving channel, waiting for one if none are
+// queued or the session is disconnected, then write the next deliveries
+// from the queue
 async fn run_application_writer(mut application_writer: ApplicationWriter) {
-    while let Some(message) = application_writer.rx.recv().await {
-        if application_writer.handle_message(message).await == None {
-            break;
+    loop {
+        if application_writer.stream.is_none() || application_writer.delivery_queue.is_empty() {
+            match application_writer.rx.recv().await {
+                Some(application_writer_message) => {
+                    application_writer.handle_application_writer_message(application_writer_message)
+                }
+                None => break,
+            }
+        }
+        while application_writer.delivery_queue.len() < CONST::APPLICATION_WRITER_QUEUE_LENGTH {
+            match application_writer.rx.try_recv() {
+                Ok(application_writer_message) => {
+                    application_writer.handle_application_writer_message(application_writer_message)
+                }
+                Err(_) => break,
+            }
         }
+        if application_writer.stream.is_none() {
+            continue;
+        }
+        let deliveries = application_writer.take_deliveries();
+        application_writer.handle_deliveries(deliveries).await;
     }
 }
 
 #[derive(Clone)]
 pub struct ApplicationWriterHandle {
-    pub tx: Sender<HomaMessage>,
+    pub tx: Sender<ApplicationWriterMessage>,
 }
 
 impl ApplicationWriterHandle {
     // Start the ApplicationWriter, return the actor handle and join handle
-    pub fn new(stream: UnixStream) -> (Self, JoinHandle<()>) {
-        let stream = AsyncUnixStream::from(stream.try_clone().unwrap());
-        let (tx, rx) = channel::<HomaMessage>(1000);
-        let application_writer = ApplicationWriter { stream, rx };
+    pub fn new(
+        stream: UnixStream,
+        registration_message: &HomaRegistrationMessage,
+    ) -> (Self, JoinHandle<()>) {
+        let stream = Some(AsyncUnixStream::from(stream.try_clone().unwrap()));
+        let (tx, rx) = channel::<ApplicationWriterMessage>(1000);
+        let application_writer = ApplicationWriter {
+            stream,
+            rx,
+            receive_metadata: registration_message.receive_metadata(),
+            json_lines: registration_message.has_flag(REGISTRATION_FLAGS::JSON_LINES),
+            batch: registration_message.has_flag(REGISTRATION_FLAGS::BATCH),
+            chunked: registration_message.has_flag(REGISTRATION_FLAGS::CHUNKED),
+            file_sinks: VecDeque::new(),
+            delivery_queue: DeliveryQueue::new(),
+            send_failures: VecDeque::new(),
+        };
         let join_handle = tokio::spawn(run_application_writer(application_writer));
         (Self { tx }, join_handle)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ApplicationWriter;
+    use super::Delivery;
+    use super::DeliveryQueue;
+    use crate::models::frame::HomaChunk;
+    use crate::models::frame::HomaFrameHeader;
+    use crate::models::frame::HomaFrameType;
+    use crate::models::frame::HomaJsonFrame;
+    use crate::models::frame::HomaSendFailure;
+    use crate::models::message::HomaMessage;
+    use async_std::os::unix::net::UnixStream as AsyncUnixStream;
+    use std::collections::VecDeque;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use tokio::sync::mpsc::channel;
+
+    // Batching and chunking writer of the stream
+    fn application_writer(stream: UnixStream) -> ApplicationWriter {
+        let (_tx, rx) = channel(1);
+        ApplicationWriter {
+            stream: Some(AsyncUnixStream::from(stream)),
+            rx,
+            receive_metadata: false,
+            json_lines: false,
+            batch: true,
+            chunked: true,
+            file_sinks: VecDeque::new(),
+            delivery_queue: DeliveryQueue::new(),
+            send_failures: VecDeque::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_order_test() {
+        let (stream, mut other) = UnixStream::pair().unwrap();
+        let mut application_writer = application_writer(stream);
+        let message = HomaMessage {
+            content: vec![0; 10],
+            ..Default::default()
+        };
+
+        // Messages batched before a chunk are written ahead of it
+        let deliveries = vec![
+            Delivery::Message(message.clone()),
+            Delivery::Message(message.clone()),
+            Delivery::Chunk(HomaChunk::from_message(&message, 0, 5), None),
+            Delivery::Message(message),
+        ];
+        application_writer.handle_deliveries(deliveries).await;
+        drop(application_writer);
+
+        let mut frame_types = Vec::new();
+        let mut header_bytes = [0u8; 8];
+        while other.read_exact(&mut header_bytes).is_ok() {
+            let header = HomaFrameHeader::from_bytes(header_bytes).unwrap();
+            let mut body = vec![0; header.length as usize];
+            other.read_exact(&mut body).unwrap();
+            frame_types.push(header.frame_type as u8);
+        }
+        assert_eq!(
+            frame_types,
+            vec![
+                HomaFrameType::Batch as u8,
+                HomaFrameType::Chunk as u8,
+                HomaFrameType::Message as u8
+            ]
+        );
+    }
+    #[tokio::test]
+    async fn write_failure_test() {
+        let (stream, other) = UnixStream::pair().unwrap();
+        let mut application_writer = application_writer(stream);
+        drop(other);
+        let message = HomaMessage {
+            id: 1,
+            content: vec![0; 10],
+            ..Default::default()
+        };
+        application_writer
+            .send_failures
+            .push_back(HomaSendFailure::default());
+
+        // The messages of a failed write, including the message of its
+        // last chunk, are queued again along the send failures
+        let deliveries = vec![
+            Delivery::Message(message.clone()),
+            Delivery::Chunk(
+                HomaChunk::from_message(&message, 5, 5),
+                Some(HomaMessage { id: 2, ..message }),
+            ),
+        ];
+        application_writer.handle_deliveries(deliveries).await;
+        assert!(application_writer.stream.is_none());
+        assert_eq!(application_writer.send_failures.len(), 1);
+        assert_eq!(application_writer.delivery_queue.len(), 2);
+        assert!(application_writer.delivery_queue.contains(1));
+        assert!(application_writer.delivery_queue.contains(2));
+
+        application_writer.delivery_queue.pop();
+        application_writer.delivery_queue.pop();
+        assert!(!application_writer.delivery_queue.contains(1));
+        assert!(!application_writer.delivery_queue.contains(2));
+    }
+    #[tokio::test]
+    async fn json_batch_test() {
+        let (stream, mut other) = UnixStream::pair().unwrap();
+        let mut application_writer = ApplicationWriter {
+            receive_metadata: true,
+            json_lines: true,
+            ..application_writer(stream)
+        };
+        let message = HomaMessage {
+            content: vec![0; 10],
+            ..Default::default()
+        };
+
+        // Messages queued at the same time are written as a single
+        // batch record followed by their metadata records
+        let deliveries = vec![
+            Delivery::Message(message.clone()),
+            Delivery::Message(message),
+        ];
+        application_writer.handle_deliveries(deliveries).await;
+        drop(application_writer);
+
+        let mut lines = String::new();
+        other.read_to_string(&mut lines).unwrap();
+        let frames = lines
+            .lines()
+            .map(|line| serde_json::from_str::<HomaJsonFrame>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(frames.len(), 3);
+        assert!(matches!(&frames[0], HomaJsonFrame::Batch { messages } if messages.len() == 2));
+        assert!(matches!(frames[1], HomaJsonFrame::Metadata(_)));
+        assert!(matches!(frames[2], HomaJsonFrame::Metadata(_)));
+    }
+}
@@ -3,21 +3,38 @@ DatagramReceiver actor
 
 Receive all incoming datagrams, pass them to the corresponding
 Application/MessageSender/MessageReceiver
+
+If the transport is bound to local addresses, packets to any other address
+are dropped
+
+Datagrams are dispatched without blocking, if a data datagram is dropped
+because the application is overloaded the sender is answered with an
+overloaded datagram so that it backs off before resending
 */
 use crate::components::application::ApplicationHandle;
+use crate::components::datagram_sender::DatagramSenderHandle;
+use crate::components::datagram_sender::DatagramSenderMessage;
+use crate::config::CONFIG;
+use crate::config::CONST;
+use crate::dispatch_table::DispatchTable;
 use crate::models::datagram::HomaDatagram;
+use crate::models::datagram::HomaDatagramType;
+use crate::transport::Transport;
 use bincode::deserialize;
+use pnet::packet::ipv4::Ipv4Packet;
 use pnet::packet::Packet;
-use pnet::transport::ipv4_packet_iter;
-use pnet::transport::TransportReceiver;
-use std::collections::HashMap;
+use std::io;
 use std::net::Ipv4Addr;
 use std::sync::Arc;
-use std::sync::Mutex;
 use tokio;
+use tokio::time::Instant;
 
 pub struct DatagramReceiver {
-    application_handles: Arc<Mutex<HashMap<u32, ApplicationHandle>>>,
+    application_handles: Arc<DispatchTable<u32, ApplicationHandle>>,
+    // Addresses packets are accepted for, all addresses if none
+    local_addresses: Option<Vec<Ipv4Addr>>,
+    // Used to answer dropped datagrams with overloaded datagrams
+    datagram_sender_handle: DatagramSenderHandle,
 }
 
 impl DatagramReceiver {
@@ -27,11 +44,14 @@ impl DatagramReceiver {
         packet_bytes: Vec<u8>,
         source_address: Ipv4Addr,
         destination_address: Ipv4Addr,
+        arrival: Instant,
     ) {
         if let Ok(mut datagram) = deserialize::<HomaDatagram>(&packet_bytes) {
+            // Computing the checksum overwrites the received one
+            let received_checksum = datagram.checksum;
             if let Ok(checksum) = datagram.checksum() {
-                if datagram.checksum == checksum {
-                    self.handle_datagram(datagram, source_address, destination_address);
+                if received_checksum == checksum {
+                    self.handle_datagram(datagram, source_address, destination_address, arrival);
                 }
             }
         }
@@ -43,13 +63,60 @@ impl DatagramReceiver {
         datagram: HomaDatagram,
         source_address: Ipv4Addr,
         destination_address: Ipv4Addr,
+        arrival: Instant,
     ) {
-        let applications = self.application_handles.lock().unwrap();
-        if let Some(application_handle) = applications.get(&datagram.destination_id) {
-            application_handle.blocking_send_datagram(
+        if let Some(application_handle) = self.application_handles.get(&datagram.destination_id) {
+            let overloaded = match datagram.datagram_type {
+                HomaDatagramType::Data => Some(HomaDatagram {
+                    datagram_type: HomaDatagramType::Overloaded,
+                    message_id: datagram.message_id,
+                    source_id: datagram.destination_id,
+                    destination_id: datagram.source_id,
+                    ..Default::default()
+                }),
+                _ => None,
+            };
+            if !application_handle.try_send_datagram(
                 datagram,
                 source_address,
                 destination_address,
+                arrival,
+            ) {
+                if let Some(overloaded) = overloaded {
+                    self.send_overloaded(overloaded, source_address, destination_address);
+                }
+            }
+        }
+    }
+
+    // Ask the sender of a dropped datagram to back off,
+    // the overloaded datagram is dropped as well if the DatagramSender is full
+    fn send_overloaded(
+        &self,
+        mut overloaded: HomaDatagram,
+        source_address: Ipv4Addr,
+        destination_address: Ipv4Addr,
+    ) {
+        let _ = overloaded.checksum();
+        let packet = overloaded.to_ipv4(destination_address, source_address, CONFIG.control_dscp());
+        let _ = self
+            .datagram_sender_handle
+            .try_send(DatagramSenderMessage::FromMessageReceiver(packet));
+    }
+
+    // Parse the IPv4 packet received at the arrival instant and handle its payload
+    fn handle_packet(&self, packet_bytes: Vec<u8>, arrival: Instant) {
+        if let Some(packet) = Ipv4Packet::new(&packet_bytes) {
+            if let Some(local_addresses) = &self.local_addresses {
+                if !local_addresses.contains(&packet.get_destination()) {
+                    return;
+                }
+            }
+            self.handle_packet_payload(
+                packet.payload().to_vec(),
+                packet.get_source(),
+                packet.get_destination(),
+                arrival,
             );
         }
     }
@@ -57,70 +124,54 @@ impl DatagramReceiver {
     // Start the DatagramReceiver
     #[allow(unused)]
     pub fn start(
-        transport_receiver: TransportReceiver,
-        application_handles: Arc<Mutex<HashMap<u32, ApplicationHandle>>>,
+        transport: Arc<dyn Transport>,
+        application_handles: Arc<DispatchTable<u32, ApplicationHandle>>,
+        datagram_sender_handle: DatagramSenderHandle,
     ) {
         let datagram_receiver = DatagramReceiver {
             application_handles,
+            local_addresses: transport.local_addresses(),
+            datagram_sender_handle,
         };
         tokio::task::spawn_blocking(move || {
-            run_datagram_receiver(datagram_receiver, transport_receiver);
+            run_datagram_receiver(datagram_receiver, transport);
         });
     }
 
     // Start many the DatagramReceivers
     pub fn start_many(
-        transport_receiver: TransportReceiver,
-        application_handles: Arc<Mutex<HashMap<u32, ApplicationHandle>>>,
+        transport: Arc<dyn Transport>,
+        application_handles: Arc<DispatchTable<u32, ApplicationHandle>>,
+        datagram_sender_handle: DatagramSenderHandle,
     ) {
-        let transport_receiver = Arc::new(Mutex::new(transport_receiver));
         for _ in 0..3 {
             let datagram_receiver = DatagramReceiver {
                 application_handles: Arc::clone(&application_handles),
+                local_addresses: transport.local_addresses(),
+                datagram_sender_handle: datagram_sender_handle.clone(),
             };
-            let transport_receiver = transport_receiver.clone();
+            let transport = Arc::clone(&transport);
             tokio::task::spawn_blocking(move || {
-                run_datagram_receivers(datagram_receiver, transport_receiver);
+                run_datagram_receiver(datagram_receiver, transport);
             });
         }
     }
 }
 
-// Listen for inocming packets and handle packet payloads
-fn run_datagram_receivers(
-    datagram_receiver: DatagramReceiver,
-    transport_receiver: Arc<Mutex<TransportReceiver>>,
-) {
+// Listen for batches of incoming packets and handle packet payloads,
+// the packets of a batch share its arrival instant,
+// stop once the transport is no longer connected
+fn run_datagram_receiver(datagram_receiver: DatagramReceiver, transport: Arc<dyn Transport>) {
     loop {
-        let packet_data = {
-            let mut transport_receiver_guard = transport_receiver.lock().unwrap();
-            let mut packet_iter = ipv4_packet_iter(&mut transport_receiver_guard);
-            packet_iter.next().ok().and_then(|(packet, _)| {
-                Some((
-                    packet.payload().to_vec(),
-                    packet.get_source(),
-                    packet.get_destination(),
-                ))
-            })
-        };
-        if let Some((payload, source, destination)) = packet_data {
-            datagram_receiver.handle_packet_payload(payload, source, destination);
+        match transport.recv_batch(CONST::DATAGRAM_BATCH_LIMIT) {
+            Ok(packets) => {
+                let arrival = Instant::now();
+                for packet_bytes in packets {
+                    datagram_receiver.handle_packet(packet_bytes, arrival);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => break,
+            Err(_) => (),
         }
     }
 }
-
-// Listen for inocming packets and handle packet payloads
-#[allow(unused)]
-fn run_datagram_receiver(
-    datagram_receiver: DatagramReceiver,
-    mut transport_receiver: TransportReceiver,
-) {
-    let mut packet_iter = ipv4_packet_iter(&mut transport_receiver);
-    while let Ok((packet, _)) = packet_iter.next() {
-        datagram_receiver.handle_packet_payload(
-            packet.payload().to_vec(),
-            packet.get_source(),
-            packet.get_destination(),
-        );
-    }
-}
@@ -1,51 +1,300 @@
 /*
-DatagamSender
+DatagramSender actor
 
-This component used to be an actor but testing showed that it
-is far more efficient for other actors to directly access the service
-of this component by acquiring a lock to the transport_sender and
-sending packets directly
+Other actors queue packets to this actor, which owns the transport on a
+dedicated thread. It takes as many queued packets as are available up to the
+batch limit and sends them through the transport at once, instead of every
+actor locking a channel for each packet
+
+Outgoing packets pass through an egress queue implementing sender-side SRPT,
+control datagrams from MessageReceivers are sent first, then data datagrams
+from the message with the fewest remaining bytes, in order within a message
+
+Packets are paced to the link speed, so that only a bounded number of bytes
+is queued below the daemon and the scheduling decision stays in the egress
+queue where SRPT ordering applies
+
+Packets the transport fails to send are counted, they are recovered like
+lost packets by the timeouts of the protocol
+
+The handle knows the local addresses of the transport, so that messages from
+a source address homad is not bound to are refused before they are sent
 */
-use pnet::packet::ip::IpNextHeaderProtocol;
-use pnet::packet::ipv4::Ipv4Packet;
-use pnet::transport::transport_channel;
-use pnet::transport::TransportChannelType::Layer4;
-use pnet::transport::TransportProtocol::Ipv4;
-use pnet::transport::TransportSender;
-use rand::Rng;
-use std::io;
-use std::net::IpAddr;
-use std::ops::Range;
+use crate::config::CONFIG;
+use crate::config::CONST;
+use crate::transport::Transport;
+use priority_queue::PriorityQueue;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::Sender;
+
+// Packets waiting to be sent, data packets are queued per message and
+// messages are ordered by the remaining bytes of their next packet
+#[derive(Default)]
+pub(crate) struct EgressQueue {
+    control_packets: VecDeque<Vec<u8>>,
+    message_packets: HashMap<u64, VecDeque<(u64, Vec<u8>)>>,
+    messages: PriorityQueue<u64, Reverse<u64>>,
+}
+
+impl EgressQueue {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.control_packets.is_empty() && self.messages.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, datagram_sender_message: DatagramSenderMessage) {
+        use DatagramSenderMessage::*;
+        match datagram_sender_message {
+            FromMessageSender(message_id, remaining_bytes, packet) => {
+                let packets = self.message_packets.entry(message_id).or_default();
+                packets.push_back((remaining_bytes, packet));
+                if packets.len() == 1 {
+                    self.messages.push(message_id, Reverse(remaining_bytes));
+                }
+            }
+            FromMessageReceiver(packet) => self.control_packets.push_back(packet),
+        }
+    }
+
+    // Pop a control packet if any, otherwise the next packet
+    // of the message with the fewest remaining bytes
+    pub(crate) fn pop(&mut self) -> Option<Vec<u8>> {
+        if let Some(packet) = self.control_packets.pop_front() {
+            return Some(packet);
+        }
+        let (&message_id, _) = self.messages.peek()?;
+        let packets = self.message_packets.get_mut(&message_id)?;
+        let (_, packet) = packets.pop_front()?;
+        match packets.front() {
+            Some((remaining_bytes, _)) => {
+                self.messages
+                    .change_priority(&message_id, Reverse(*remaining_bytes));
+            }
+            None => {
+                self.messages.pop();
+                self.message_packets.remove(&message_id);
+            }
+        }
+        Some(packet)
+    }
+}
+
+// Estimate of the bytes queued below the daemon, which drain at the
+// link speed from the time the link was last known to be idle
+struct Pacer {
+    bytes_per_second: u64,
+    queue_limit: u64,
+    link_idle_time: Instant,
+}
+
+impl Pacer {
+    // A link speed of 0 Mbit/s disables pacing
+    fn new(link_speed: u64, queue_limit: u64) -> Self {
+        Self {
+            bytes_per_second: link_speed * 1_000_000 / 8,
+            queue_limit,
+            link_idle_time: Instant::now(),
+        }
+    }
+
+    fn queued_bytes(&self, now: Instant) -> u64 {
+        let queued_time = self.link_idle_time.saturating_duration_since(now);
+        (queued_time.as_nanos() * self.bytes_per_second as u128 / 1_000_000_000) as u64
+    }
+
+    // Wait until the queued bytes are below the limit, return the room left
+    fn wait_for_room(&self) -> u64 {
+        if self.bytes_per_second == 0 {
+            return u64::MAX;
+        }
+        loop {
+            let queued_bytes = self.queued_bytes(Instant::now());
+            if queued_bytes < self.queue_limit {
+                return self.queue_limit - queued_bytes;
+            }
+            let excess_bytes = queued_bytes - self.queue_limit + 1;
+            std::thread::sleep(Duration::from_nanos(
+                excess_bytes * 1_000_000_000 / self.bytes_per_second,
+            ));
+        }
+    }
+
+    // Account for bytes handed to the transport
+    fn sent(&mut self, bytes: u64, now: Instant) {
+        if self.bytes_per_second == 0 {
+            return;
+        }
+        let transmit_time = Duration::from_nanos(bytes * 1_000_000_000 / self.bytes_per_second);
+        self.link_idle_time = self.link_idle_time.max(now) + transmit_time;
+    }
+}
+
+struct DatagramSender {
+    rx: Receiver<DatagramSenderMessage>,
+    transport: Arc<dyn Transport>,
+    egress_queue: EgressQueue,
+    pacer: Pacer,
+    // Packets the transport failed to send
+    failed_packets: Arc<AtomicU64>,
+}
+
+pub enum DatagramSenderMessage {
+    // Data packet with the id of its message and the bytes
+    // of the message from the start of the packet onwards
+    FromMessageSender(u64, u64, Vec<u8>),
+    FromMessageReceiver(Vec<u8>),
+}
+
+// Wait for a packet if none are queued, wait for room below the daemon,
+// queue all the packets available, then send a batch from the egress queue
+// filling the room
+fn run_datagram_sender(mut datagram_sender: DatagramSender) {
+    loop {
+        if datagram_sender.egress_queue.is_empty() {
+            match datagram_sender.rx.blocking_recv() {
+                Some(datagram_sender_message) => {
+                    datagram_sender.egress_queue.push(datagram_sender_message)
+                }
+                None => break,
+            }
+        }
+        let room = datagram_sender.pacer.wait_for_room();
+        while let Ok(datagram_sender_message) = datagram_sender.rx.try_recv() {
+            datagram_sender.egress_queue.push(datagram_sender_message);
+        }
+        let mut packets = Vec::new();
+        let mut bytes = 0;
+        while packets.len() < CONST::DATAGRAM_BATCH_LIMIT && bytes < room {
+            match datagram_sender.egress_queue.pop() {
+                Some(packet) => {
+                    bytes += packet.len() as u64;
+                    packets.push(packet);
+                }
+                None => break,
+            }
+        }
+        let sent = datagram_sender.transport.send_batch(&packets).unwrap_or(0);
+        datagram_sender
+            .failed_packets
+            .fetch_add(packets.len().saturating_sub(sent) as u64, Ordering::Relaxed);
+        datagram_sender.pacer.sent(bytes, Instant::now());
+    }
+}
 
 #[derive(Clone)]
 pub struct DatagramSenderHandle {
-    transport_senders: Vec<Arc<Mutex<TransportSender>>>,
+    tx: Sender<DatagramSenderMessage>,
+    failed_packets: Arc<AtomicU64>,
+    // Addresses packets can be sent from, all addresses if none
+    local_addresses: Option<Arc<Vec<Ipv4Addr>>>,
 }
 
 impl DatagramSenderHandle {
-    // Take the transport_sender channel as input and initialize the
-    // DatagramSenderHandle with it
-    pub fn new(transport_sender: TransportSender) -> Self {
-        let mut transport_senders = Vec::new();
-        for _ in 0..30 {
-            if let Ok((transport_sender, _)) =
-                transport_channel(300000, Layer4(Ipv4(IpNextHeaderProtocol(146))))
-            {
-                transport_senders.push(Arc::new(Mutex::new(transport_sender)));
-            }
+    // Start the DatagramSender on the transport and return the handle
+    pub fn new(transport: Arc<dyn Transport>) -> Self {
+        let (tx, rx) = channel::<DatagramSenderMessage>(CONST::DATAGRAM_SENDER_QUEUE_LENGTH);
+        let failed_packets = Arc::new(AtomicU64::new(0));
+        let local_addresses = transport.local_addresses().map(Arc::new);
+        let datagram_sender = DatagramSender {
+            rx,
+            transport,
+            egress_queue: EgressQueue::default(),
+            pacer: Pacer::new(CONFIG.LINK_SPEED, CONFIG.NIC_QUEUE_BYTES),
+            failed_packets: Arc::clone(&failed_packets),
+        };
+        tokio::task::spawn_blocking(move || run_datagram_sender(datagram_sender));
+        Self {
+            tx,
+            failed_packets,
+            local_addresses,
         }
-        Self { transport_senders }
     }
 
-    // Assemble the IP datagram and send to the destination address
-    pub async fn send(&self, packet: Vec<u8>) -> io::Result<usize> {
-        let packet = Ipv4Packet::new(&packet).unwrap();
-        let address = packet.get_destination();
-        let i =
-            rand::thread_rng().gen_range::<usize, Range<usize>>(0..self.transport_senders.len());
-        let mut transport_sender = self.transport_senders[i].lock().await;
-        transport_sender.send_to(packet, IpAddr::V4(address))
+    // Handle to a channel standing in for the DatagramSender,
+    // used by the simulator to model the link of a host
+    #[cfg(feature = "sim")]
+    pub(crate) fn detached(tx: Sender<DatagramSenderMessage>) -> Self {
+        Self {
+            tx,
+            failed_packets: Arc::new(AtomicU64::new(0)),
+            local_addresses: None,
+        }
+    }
+
+    // Number of packets the transport failed to send
+    pub fn failed_packets(&self) -> u64 {
+        self.failed_packets.load(Ordering::Relaxed)
+    }
+
+    // Whether the transport can send packets from the source address
+    pub fn can_send_from(&self, source_address: Ipv4Addr) -> bool {
+        match &self.local_addresses {
+            Some(local_addresses) => local_addresses.contains(&source_address),
+            None => true,
+        }
+    }
+
+    // Queue the IP datagram to be sent to the destination address
+    pub async fn send(
+        &self,
+        datagram_sender_message: DatagramSenderMessage,
+    ) -> Result<(), SendError<DatagramSenderMessage>> {
+        self.tx.send(datagram_sender_message).await
+    }
+
+    // Queue the IP datagram unless the queue is full, used by the
+    // receiving threads which must not block
+    #[allow(clippy::result_large_err)]
+    pub fn try_send(
+        &self,
+        datagram_sender_message: DatagramSenderMessage,
+    ) -> Result<(), TrySendError<DatagramSenderMessage>> {
+        self.tx.try_send(datagram_sender_message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DatagramSenderMessage::*;
+    use super::EgressQueue;
+    use super::Pacer;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn egress_queue_test() {
+        let mut egress_queue = EgressQueue::default();
+        egress_queue.push(FromMessageSender(1, 3000, vec![1, 0]));
+        egress_queue.push(FromMessageSender(1, 1600, vec![1, 1]));
+        egress_queue.push(FromMessageSender(2, 1000, vec![2, 0]));
+        egress_queue.push(FromMessageReceiver(vec![0]));
+
+        let order = std::iter::from_fn(|| egress_queue.pop()).collect::<Vec<_>>();
+        assert_eq!(order, vec![vec![0], vec![2, 0], vec![1, 0], vec![1, 1]]);
+    }
+
+    #[test]
+    fn pacer_test() {
+        // 8 Mbit/s drains a byte per microsecond
+        let mut pacer = Pacer::new(8, 2000);
+        let now = Instant::now();
+        pacer.sent(1500, now);
+        assert_eq!(pacer.queued_bytes(now), 1500);
+        assert_eq!(pacer.queued_bytes(now + Duration::from_micros(1000)), 500);
+        assert_eq!(pacer.queued_bytes(now + Duration::from_micros(2000)), 0);
+
+        pacer.sent(1500, now);
+        assert_eq!(pacer.queued_bytes(now), 3000);
     }
 }
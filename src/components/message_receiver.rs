@@ -1,47 +1,51 @@
 /*
 MessageReceiver actor
 
-This actor is responsible for receiving all the datagrams of a message
+This actor drives the MessageReceiver state machine of a message, feeding it
+the datagrams of the message, its timeouts, the scheduled priorities and the
+acknowledgement of the application, and carrying out its actions
 
-The actor first receives all unscheduled datagrams and issues resends if
+The state machine first receives all unscheduled datagrams and issues resends if
 they do not arrive within a specific timeout, if all unscheduled datagrams are
 not received after a maximum number of resend requests, the MessageReceiver exits
 
-The actor then receives all scheduled datagram. The actor must send a grant for each
-scheduled datagram. If the subsequent data datagram is not received within a timeout,
-the actor sends a duplicate grant. If the data datagaram is not received after a maximum
-number of duplicate grants, the MessageReceiver exits
+The state machine then receives all scheduled datagram. It grants the scheduled
+datagrams up to RTT bytes beyond the received bytes. If no data datagram is
+received within a timeout, it requests resends of the missing ones. If the data
+datagrams are not received after a maximum number of resend requests, the
+MessageReceiver exits
+
+If the application registered with REGISTRATION_FLAGS::ACK, the final grant is
+withheld until the application acknowledges the delivered message, busy
+datagrams are sent meanwhile to keep the sender waiting
 */
 use crate::components::application::ApplicationHandle;
 use crate::components::application_writer::ApplicationWriterHandle;
+use crate::components::application_writer::ApplicationWriterMessage;
 use crate::components::datagram_sender::DatagramSenderHandle;
+use crate::components::datagram_sender::DatagramSenderMessage;
 use crate::components::priority_manager::PriorityManagerHandle;
 use crate::components::workload_manager::WorkloadManagerHandle;
 use crate::config::CONFIG;
-use crate::config::CONST;
 use crate::models::datagram::HomaDatagram;
-use crate::models::datagram::HomaDatagramType;
 use crate::models::message::HomaMessage;
-use crate::models::message::HomaMessageBuilder;
-use crate::utils::fuzz_timeout;
+use crate::models::workload::HomaWorkload;
+use crate::protocol::message_receiver::MessageReceiverAction;
+use crate::protocol::message_receiver::MessageReceiverCore;
+use crate::protocol::message_receiver::MessageReceiverEvent;
+use crate::protocol::ProtocolConfig;
+use crate::utils::timer_duration;
+use crate::utils::timestamp_micros;
+use std::collections::VecDeque;
 use std::net::Ipv4Addr;
 use tokio::select;
 use tokio::sync::mpsc::channel;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
-use tokio::time::sleep;
-use tokio::time::Duration;
-
-enum UnscheduledState {
-    Incomplete,
-    Complete,
-}
-
-enum ScheduledState {
-    Incomplete,
-    Complete,
-}
+use tokio::time::sleep_until;
+use tokio::time::Instant;
 
 struct MessageReceiver {
     message_id: u64,
@@ -49,19 +53,19 @@ struct MessageReceiver {
 
     source_address: Ipv4Addr,
     destination_address: Ipv4Addr,
-
-    source_id: u32,
-    destination_id: u32,
-    priority: u8,
-    local_workload: [u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS],
-    remote_workload: [u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS],
+    // Workload of the remote host sent along the first datagram
+    remote_workload: HomaWorkload,
     message_length: u64,
-    datagrams: Vec<Option<HomaDatagram>>,
+    // Instant the first datagram of the message arrived at
+    arrival: Instant,
+    // Instant the local workload of the state machine was taken at,
+    // the workload of control datagrams is aged by the time since
+    workload_time: Instant,
 
-    expected_datagrams: u32,
-    collected_datagrams: u32,
-    collected_bytes: u64,
-    unscheduled_only: bool,
+    // Instant the timer of the state machine fires at
+    deadline: Instant,
+    // Receiver of the acknowledgement of the delivered message
+    ack_rx: Option<oneshot::Receiver<()>>,
 
     application_handle: ApplicationHandle,
     application_writer_handle: ApplicationWriterHandle,
@@ -71,229 +75,138 @@ struct MessageReceiver {
 }
 
 impl MessageReceiver {
-    // Add the received datagram to the vector of datagrams if it has not yet been received
-    fn add_datagram(&mut self, datagram: HomaDatagram) -> u64 {
-        if let Some(datagram_entry) = self.datagrams.get_mut(datagram.sequence_number as usize) {
-            if datagram_entry.is_none() {
-                self.collected_datagrams += 1;
-                self.collected_bytes += datagram.payload.len() as u64;
-                *datagram_entry = Some(datagram);
-            }
-        }
-        self.datagrams.len() as u64
-    }
-
-    // Send resend requests for all unscheduled datagrams which have not yet been received
-    async fn request_resend_unscheduled_datagrams(&mut self) {
-        let mut resend: HomaDatagram = HomaDatagram::default();
-        resend.message_id = self.message_id;
-        resend.datagram_type = HomaDatagramType::Resend;
-        resend.source_id = self.destination_id;
-        resend.destination_id = self.source_id;
-        resend.workload = self.local_workload.clone();
-        for i in 0..CONFIG.UNSCHEDULED_DATAGRAM_LIMIT {
-            if let Some(None) = self.datagrams.get(i) {
-                let mut resend = resend.clone();
-                resend.sequence_number = i as u32;
-                let _ = resend.checksum();
-                let packet = resend.to_ipv4(
-                    self.destination_address.clone(),
-                    self.source_address.clone(),
-                    56,
-                );
-                self.datagram_sender_handle
-                    .send(packet)
-                    .await
-                    .expect("MessageReceiver -> DatagramSender failed");
-            }
-        }
-    }
-
-    async fn receive_unscheduled_datagrams(&mut self) -> UnscheduledState {
-        use UnscheduledState::*;
-        let mut resend_counter = 0;
-        loop {
-            if let Complete = self.check_message_unscheduled() {
-                return Complete;
-            }
-            let timeout = fuzz_timeout(CONFIG.TIMEOUT);
-            select! {
-                _ = sleep(Duration::from_millis(timeout))=> {
-                    if resend_counter == CONFIG.RESENDS {
-                        return Incomplete
-                    }
-                    self.request_resend_unscheduled_datagrams().await;
-                    resend_counter += 1
+    // Carry out the actions of the state machine, feeding it back the
+    // priorities they ask for
+    async fn handle_actions(
+        &mut self,
+        core: &mut MessageReceiverCore,
+        actions: Vec<MessageReceiverAction>,
+    ) {
+        use MessageReceiverAction::*;
+        let mut actions = VecDeque::from(actions);
+        while let Some(action) = actions.pop_front() {
+            match action {
+                SendDatagram(datagram) => self.send_datagram(datagram).await,
+                RegisterPriority(remaining_bytes) => {
+                    self.priority_manager_handle
+                        .register_scheduled_message(self.message_id, remaining_bytes)
+                        .await
                 }
-                Some(datagram) = self.rx.recv() => {
-                    self.add_datagram(datagram);
+                UnregisterPriority => {
+                    self.priority_manager_handle
+                        .unregister_scheduled_message(self.message_id)
+                        .await
                 }
-            }
-        }
-    }
-
-    async fn receive_scheduled_datagrams(&mut self) -> ScheduledState {
-        use ScheduledState::*;
-        self.register_priority().await;
-        self.get_priority().await;
-        self.grant(self.collected_datagrams).await;
-        let mut resend_counter = 0;
-        loop {
-            let timeout = fuzz_timeout(CONFIG.TIMEOUT);
-            select! {
-                _ = sleep(Duration::from_millis(timeout)) => {
-                    if resend_counter == CONFIG.LARGE_RESENDS {
-                        self.unregister_priority().await;
-                        return Incomplete;
-                    }
-                    self.get_priority().await;
-                    self.grant(self.collected_datagrams).await;
-                    resend_counter += 1;
+                RequestPriority(remaining_bytes) => {
+                    let priority = self
+                        .priority_manager_handle
+                        .get_scheduled_priority(self.message_id, remaining_bytes)
+                        .await;
+                    actions.extend(core.handle(MessageReceiverEvent::PriorityGranted(priority)));
                 }
-                Some(datagram) = self.rx.recv() => {
-                    self.add_datagram(datagram);
-                    if let Complete =  self.check_message_scheduled() {
-                        self.unregister_priority().await;
-                        return Complete;
-                    }
-                    self.get_priority().await;
-                    self.grant(self.collected_datagrams).await;
+                SetTimer { timeout, jitter } => {
+                    self.deadline = Instant::now() + timer_duration(timeout, jitter)
                 }
+                Deliver(message, acknowledge) => self.deliver(message, acknowledge).await,
+                Finish => self.exit().await,
             }
         }
     }
 
-    async fn get_priority(&mut self) {
-        self.priority = self
-            .priority_manager_handle
-            .get_scheduled_priority(self.message_id, self.message_length - self.collected_bytes)
-            .await;
-    }
-
-    async fn register_priority(&self) {
-        self.priority_manager_handle
-            .register_scheduled_message(self.message_id, self.message_length - self.collected_bytes)
-            .await;
-    }
-
-    async fn unregister_priority(&self) {
-        self.priority_manager_handle
-            .unregister_scheduled_message(self.message_id)
-            .await;
+    async fn send_datagram(&self, mut datagram: HomaDatagram) {
+        datagram.workload = datagram.workload.aged(self.workload_time.elapsed());
+        let _ = datagram.checksum();
+        let packet = datagram.to_ipv4(
+            self.destination_address,
+            self.source_address,
+            CONFIG.control_dscp(),
+        );
+        self.datagram_sender_handle
+            .send(DatagramSenderMessage::FromMessageReceiver(packet))
+            .await
+            .expect("MessageReceiver -> DatagramSender failed");
     }
 
     async fn put_remote_workload(&self) {
         self.priority_manager_handle
-            .put_unscheduled_priority_level_partitions(self.source_address, self.remote_workload)
+            .put_remote_workload(self.source_address, self.remote_workload.clone())
             .await;
     }
 
-    async fn get_local_workload(&mut self) {
-        self.local_workload = self
+    async fn get_local_workload(&mut self) -> HomaWorkload {
+        let workload = self
             .workload_manager_handle
-            .update_workload(self.message_length)
+            .update_workload(self.message_length, self.arrival)
             .await
             .unwrap();
+        self.workload_time = Instant::now();
+        workload
     }
 
-    fn check_message_unscheduled(&self) -> UnscheduledState {
-        use UnscheduledState::*;
-        if self.collected_bytes == self.message_length {
-            return Complete;
-        }
-        if self.collected_datagrams >= CONFIG.UNSCHEDULED_DATAGRAM_LIMIT as u32 {
-            return Complete;
+    // Stamp the completion time and hand the message to the application,
+    // keeping the receiving channel open to answer retransmissions until
+    // an acknowledgement arrives
+    async fn deliver(&mut self, mut message: HomaMessage, acknowledge: bool) {
+        use crate::components::application::ApplicationMessage::*;
+        if let Some(metadata) = message.metadata.as_mut() {
+            metadata.completion_time = timestamp_micros();
         }
-        Incomplete
-    }
 
-    fn check_message_scheduled(&self) -> ScheduledState {
-        use ScheduledState::*;
-        if self.collected_bytes == self.message_length {
-            return Complete;
+        if acknowledge {
+            let (ack_tx, ack_rx) = oneshot::channel();
+            let _ = self
+                .application_handle
+                .send(FromMessageReceiverUnacknowledged(message.clone(), ack_tx))
+                .await;
+            self.ack_rx = Some(ack_rx);
+        } else {
+            self.application_handle
+                .remove_message_receiver(self.message_id);
+            self.rx.close();
         }
-        Incomplete
-    }
 
-    async fn grant(&mut self, sequence_number: u32) {
-        let mut grant = HomaDatagram::default();
-        grant.message_id = self.message_id;
-        grant.datagram_type = HomaDatagramType::Grant;
-        grant.source_id = self.destination_id;
-        grant.destination_id = self.source_id;
-        grant.sequence_number = sequence_number;
-        grant.priority = self.priority;
-        grant.workload = self.local_workload.clone();
-        let _ = grant.checksum();
-        let grant_ip = grant.to_ipv4(
-            self.destination_address.clone(),
-            self.source_address.clone(),
-            56,
-        );
-        self.datagram_sender_handle
-            .send(grant_ip)
-            .await
-            .expect("MessageReceiver -> DatagramSender failed");
-    }
-
-    async fn complete(&mut self) {
-        use crate::components::application::ApplicationMessage::*;
-        let message = self.build_message();
-        let message_id = message.id;
-        self.rx.close();
-        let _ = self.application_writer_handle.tx.send(message).await;
         let _ = self
-            .application_handle
-            .send(FromMessageReceiver(message_id))
+            .application_writer_handle
+            .tx
+            .send(ApplicationWriterMessage::FromMessageReceiver(message))
             .await;
-        self.grant(self.expected_datagrams).await;
     }
 
     async fn exit(&mut self) {
         use crate::components::application::ApplicationMessage::*;
+        self.application_handle
+            .remove_message_receiver(self.message_id);
+        self.rx.close();
         let _ = self
             .application_handle
             .send(FromMessageReceiver(self.message_id))
             .await;
     }
-
-    fn build_message(&self) -> HomaMessage {
-        let content = self
-            .datagrams
-            .iter()
-            .map(|datagram_entry| datagram_entry.to_owned().unwrap())
-            .map(|datagram| datagram.payload.clone())
-            .collect::<Vec<Vec<u8>>>()
-            .concat();
-        HomaMessageBuilder::default()
-            .id(self.message_id)
-            .source_address(self.source_address.octets())
-            .destination_address(self.destination_address.octets())
-            .source_id(self.source_id)
-            .destination_id(self.destination_id)
-            .content(content)
-            .build()
-            .unwrap()
-    }
 }
 
-async fn run_message_receiver(mut message_receiver: MessageReceiver) {
+// Feed the state machine datagrams, timeouts and the acknowledgement
+// until it finishes
+async fn run_message_receiver(
+    mut message_receiver: MessageReceiver,
+    mut core: MessageReceiverCore,
+) {
     message_receiver.put_remote_workload().await;
-    message_receiver.get_local_workload().await;
-
-    if let UnscheduledState::Incomplete = message_receiver.receive_unscheduled_datagrams().await {
-        message_receiver.exit().await;
-        return;
-    }
-    if message_receiver.unscheduled_only {
-        message_receiver.complete().await;
-        return;
-    }
-    if let ScheduledState::Incomplete = message_receiver.receive_scheduled_datagrams().await {
-        message_receiver.exit().await;
-        return;
+    let local_workload = message_receiver.get_local_workload().await;
+    let actions = core.start(local_workload);
+    message_receiver.handle_actions(&mut core, actions).await;
+    while !core.is_finished() {
+        let event = select! {
+            _ = sleep_until(message_receiver.deadline) => MessageReceiverEvent::TimerFired,
+            Some(datagram) = message_receiver.rx.recv() => MessageReceiverEvent::Datagram(datagram),
+            acknowledged = async { message_receiver.ack_rx.as_mut().unwrap().await },
+                if message_receiver.ack_rx.is_some() => {
+                message_receiver.ack_rx = None;
+                MessageReceiverEvent::Acknowledged(acknowledged.is_ok())
+            }
+        };
+        let actions = core.handle(event);
+        message_receiver.handle_actions(&mut core, actions).await;
     }
-    message_receiver.complete().await;
 }
 
 #[derive(Clone)]
@@ -302,50 +215,35 @@ pub struct MessageReceiverHandle {
 }
 
 impl MessageReceiverHandle {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         datagram: HomaDatagram,
         source_address: Ipv4Addr,
         destination_address: Ipv4Addr,
+        arrival: Instant,
 
         application_handle: ApplicationHandle,
         application_writer_handle: ApplicationWriterHandle,
         datagram_sender_handle: DatagramSenderHandle,
         priority_manager_handle: PriorityManagerHandle,
         workload_manager_handle: WorkloadManagerHandle,
+        acknowledge: bool,
     ) -> (Self, JoinHandle<()>) {
         let (tx, rx) = channel::<HomaDatagram>(1000);
 
-        let message_length = datagram.message_length;
-        let expected_datagrams = ((message_length + CONFIG.DATAGRAM_PAYLOAD_LENGTH as u64 - 1)
-            / CONFIG.DATAGRAM_PAYLOAD_LENGTH as u64) as u32;
-
-        let mut datagrams = vec![None; expected_datagrams as usize];
-        let first_datagram = datagrams
-            .get_mut(datagram.sequence_number as usize)
-            .unwrap();
-        *first_datagram = Some(datagram.clone());
-        let unscheduled_only = message_length
-            <= CONFIG.UNSCHEDULED_DATAGRAM_LIMIT as u64 * CONFIG.DATAGRAM_PAYLOAD_LENGTH as u64;
-
         let message_receiver_actor = MessageReceiver {
             message_id: datagram.message_id,
             rx,
 
             source_address,
             destination_address,
+            remote_workload: datagram.workload.clone(),
+            message_length: datagram.message_length,
+            arrival,
 
-            source_id: datagram.source_id,
-            destination_id: datagram.destination_id,
-            local_workload: [0; CONST::UNSCHEDULED_PRIORITY_PARTITIONS],
-            priority: 0,
-            remote_workload: datagram.workload,
-            message_length,
-            datagrams,
-
-            expected_datagrams,
-            collected_bytes: datagram.payload.len() as u64,
-            collected_datagrams: 1,
-            unscheduled_only,
+            workload_time: Instant::now(),
+            deadline: Instant::now(),
+            ack_rx: None,
 
             application_handle,
             application_writer_handle,
@@ -353,7 +251,15 @@ impl MessageReceiverHandle {
             priority_manager_handle,
             workload_manager_handle,
         };
-        let join_handle = tokio::spawn(run_message_receiver(message_receiver_actor));
+        let core = MessageReceiverCore::new(
+            ProtocolConfig::from_config(&CONFIG),
+            datagram,
+            source_address,
+            destination_address,
+            acknowledge,
+            timestamp_micros(),
+        );
+        let join_handle = tokio::spawn(run_message_receiver(message_receiver_actor, core));
         (Self { tx }, join_handle)
     }
 }
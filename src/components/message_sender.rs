@@ -1,29 +1,43 @@
 /*
 MessageSender actor
 
-This actor sends out unscheduled datagrams and waits for a resend
-or grant datagram
+This actor drives the MessageSender state machine of a message, sending the
+datagrams it requests and feeding it the grant and resend datagrams of the
+message and its timeouts
 
-If the actor does not receive a resend or grant within a timeout,
-the actor resends all unscheduled datagrams
+The state machine sends out unscheduled datagrams and waits for a resend
+or grant datagram. If none arrives within a timeout, all unscheduled datagrams
+are resent. Then the datagrams requested by the resends or grants are sent
 
-The actor then send any requested datagrams specified by the resends or grants
+The payloads of content passed as a file are read on a blocking thread, one
+read for each run of consecutive datagrams, if a read fails the message is
+abandoned and the failure reported to the application
 */
 use crate::components::application::ApplicationHandle;
+use crate::components::application_writer::ApplicationWriterHandle;
+use crate::components::application_writer::ApplicationWriterMessage;
 use crate::components::datagram_sender::DatagramSenderHandle;
+use crate::components::datagram_sender::DatagramSenderMessage;
 use crate::components::priority_manager::PriorityManagerHandle;
 use crate::components::workload_manager::WorkloadManagerHandle;
 use crate::config::CONFIG;
 use crate::config::CONST;
 use crate::models::datagram::HomaDatagram;
+use crate::models::frame::HomaSendFailure;
 use crate::models::message::HomaMessage;
-use crate::utils::fuzz_timeout;
+use crate::models::workload::HomaWorkload;
+use crate::protocol::message_sender::MessageSenderAction;
+use crate::protocol::message_sender::MessageSenderCore;
+use crate::protocol::message_sender::MessageSenderEvent;
+use crate::protocol::ProtocolConfig;
+use crate::utils::timer_duration;
 use std::net::Ipv4Addr;
+use std::ops::Range;
 use tokio::select;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::task::JoinHandle;
-use tokio::time::sleep;
-use tokio::time::Duration;
+use tokio::time::sleep_until;
+use tokio::time::Instant;
 
 struct MessageSender {
     message_id: u64,
@@ -31,151 +45,121 @@ struct MessageSender {
 
     source_address: Ipv4Addr,
     destination_address: Ipv4Addr,
-
-    #[allow(unused)]
-    source_id: u32,
-    #[allow(unused)]
-    destination_id: u32,
     content_length: u64,
+    // Unscheduled datagrams sent before the receiver grants any
+    unscheduled_datagrams: u32,
 
-    // Datagrams created by splitting the HomaMessage content
-    datagrams: Vec<HomaDatagram>,
+    // Message whose content is split into datagrams as they are sent
+    message: HomaMessage,
 
-    // Priority to send unscheduled HomaDatagrams with
-    unscheduled_priority: u8,
-    // Workload of the current host to inform the remote host of
-    workload: [u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS],
+    // Workload of the current host to inform the remote host of,
+    // and the instant it was taken at to age it by
+    workload: HomaWorkload,
+    workload_time: Instant,
+    // Instant the timer of the state machine fires at
+    deadline: Instant,
 
     // Actor handles to contact other relevant actors
     application_handle: ApplicationHandle,
+    application_writer_handle: ApplicationWriterHandle,
     datagram_sender_handle: DatagramSenderHandle,
     priority_manager_handle: PriorityManagerHandle,
     workload_manager_handle: WorkloadManagerHandle,
 }
 
 impl MessageSender {
-    // Multiplex and handle grant or resend types
-    async fn handle_grant_or_resend(&mut self, datagram: HomaDatagram) {
-        use crate::models::datagram::HomaDatagramType::*;
-        match datagram.datagram_type {
-            Grant => self.handle_grant(datagram).await,
-            Resend => self.handle_resend(datagram).await,
-            _ => (),
+    // Carry out the actions of the state machine,
+    // fail if a datagram could not be read
+    async fn handle_actions(&mut self, actions: Vec<MessageSenderAction>) -> Result<(), String> {
+        use MessageSenderAction::*;
+        let mut actions = actions.into_iter().peekable();
+        while let Some(action) = actions.next() {
+            match action {
+                SendDatagram {
+                    sequence_number,
+                    priority,
+                    unscheduled,
+                } => {
+                    // Send the run of consecutive datagrams starting here at once
+                    let start = sequence_number as usize;
+                    let mut end = start + 1;
+                    while end - start < CONST::DATAGRAM_BATCH_LIMIT
+                        && actions
+                            .next_if_eq(&SendDatagram {
+                                sequence_number: end as u32,
+                                priority,
+                                unscheduled,
+                            })
+                            .is_some()
+                    {
+                        end += 1;
+                    }
+                    self.send_datagrams(start..end, priority, unscheduled)
+                        .await?
+                }
+                PutRemoteWorkload(workload) => {
+                    self.priority_manager_handle
+                        .put_remote_workload(self.destination_address, workload)
+                        .await
+                }
+                SetTimer { timeout, jitter } => {
+                    self.deadline = Instant::now() + timer_duration(timeout, jitter)
+                }
+                Finish => self.complete().await,
+            }
         }
+        Ok(())
     }
 
-    // Send datagram requested by the grant datagram
-    async fn handle_grant(&mut self, grant: HomaDatagram) {
-        let i = grant.sequence_number as usize;
-        let priority = grant.priority;
-        self.send_datagram(i, priority).await;
-    }
-
-    // Send datagram requested by the resend datagram
-    async fn handle_resend(&mut self, resend: HomaDatagram) {
-        let i = resend.sequence_number as usize;
-        let priority = self.unscheduled_priority;
-        self.send_datagram(i, priority).await;
-    }
-
-    // Check if sequence number in the grant is equal to the number of datagrams
-    fn check_message_transmitted(&self, grant: &HomaDatagram) -> bool {
-        if grant.sequence_number == self.datagrams.len() as u32 {
-            return true;
-        }
-        false
+    // Tag the packet of datagram i with the bytes of the message
+    // from the datagram onwards for the egress queue
+    fn data_packet(&self, i: usize, packet: Vec<u8>) -> DatagramSenderMessage {
+        let offset = i as u64 * CONFIG.DATAGRAM_PAYLOAD_LENGTH as u64;
+        let remaining_bytes = self.content_length.saturating_sub(offset);
+        DatagramSenderMessage::FromMessageSender(self.message_id, remaining_bytes, packet)
     }
 
-    // Send datagram at index i and with specified priority
-    async fn send_datagram(&mut self, i: usize, priority: u8) {
-        if let Some(datagram) = self.datagrams.get(i) {
-            let mut datagram = datagram.to_owned();
-            datagram.workload = self.workload.clone();
+    // Send the datagrams of the range with the specified priority,
+    // unscheduled datagrams carry their priority
+    async fn send_datagrams(
+        &mut self,
+        range: Range<usize>,
+        priority: u8,
+        unscheduled: bool,
+    ) -> Result<(), String> {
+        let datagrams = self
+            .message
+            .read_datagrams(range)
+            .await
+            .map_err(|e| e.to_string())?;
+        for mut datagram in datagrams {
+            datagram.workload = self.workload.aged(self.workload_time.elapsed());
+            datagram.offset = (self.unscheduled_datagrams as u64
+                * CONFIG.DATAGRAM_PAYLOAD_LENGTH as u64)
+                .min(self.content_length);
+            if unscheduled {
+                datagram.priority = priority;
+            }
             let _ = datagram.checksum();
+            let i = datagram.sequence_number as usize;
             let packet = datagram.to_ipv4(self.source_address, self.destination_address, priority);
             self.datagram_sender_handle
-                .send(packet)
+                .send(self.data_packet(i, packet))
                 .await
                 .expect("MessageSender -> DatagramSender failed");
         }
+        Ok(())
     }
 
-    // Send all datagrams starting at index start
-    // and ending at index end (non-inclusive)
-    async fn send_datagram_slice(&mut self, start: usize, end: usize, priority: u8) {
-        for i in start..end {
-            if let Some(datagram) = self.datagrams.get(i) {
-                let mut datagram = datagram.to_owned();
-                datagram.workload = self.workload.clone();
-                datagram.priority = self.unscheduled_priority;
-                let _ = datagram.checksum();
-                let packet =
-                    datagram.to_ipv4(self.source_address, self.destination_address, priority);
-                self.datagram_sender_handle
-                    .send(packet)
-                    .await
-                    .expect("MessageSender -> DatagramSender failed");
-            }
-        }
-    }
-
-    // Send unscheduled datagrams and continuosly resend after a timeout if a
-    // resend or grant is not received, fail after resend count reached
-    async fn send_unscheduled_datagrams(&mut self) -> Option<HomaDatagram> {
-        self.send_datagram_slice(
-            0,
-            CONFIG.UNSCHEDULED_DATAGRAM_LIMIT,
-            self.unscheduled_priority,
-        )
-        .await;
-        let mut resend_counter = 0;
-        loop {
-            let timeout = fuzz_timeout(CONFIG.TIMEOUT);
-            select! {
-                _ = sleep(Duration::from_millis(timeout)) => {
-                    if resend_counter == CONFIG.RESENDS {
-                        return None
-                    }
-                    self.send_datagram_slice(0, CONFIG.UNSCHEDULED_DATAGRAM_LIMIT, self.unscheduled_priority)
-                        .await;
-                    resend_counter += 1;
-                }
-                Some(grant_or_resend) = self.rx.recv() => {
-                    self.priority_manager_handle
-                        .put_unscheduled_priority_level_partitions(
-                            self.destination_address,
-                            grant_or_resend.workload.clone(),
-                        )
-                        .await;
-                    return Some(grant_or_resend);
-                }
-            }
-        }
-    }
-
-    // Send requested datagrams until all datagrams are sent and all have been
-    // granted, fail after a max timeout
-    async fn send_requested_datagrams(&mut self, datagram: HomaDatagram) {
-        self.handle_grant_or_resend(datagram).await;
-        loop {
-            select! {
-                _ = sleep(Duration::from_millis(CONFIG.LARGE_TIMEOUT)) => {
-                    return;
-                }
-                Some(datagram) = self.rx.recv() => {
-                    self.priority_manager_handle
-                        .put_unscheduled_priority_level_partitions(
-                            self.destination_address,
-                            datagram.workload.clone(),
-                        )
-                        .await;
-                    if self.check_message_transmitted(&datagram) {
-                        return
-                    }
-                    self.handle_grant_or_resend(datagram).await;
-                }
-            }
-        }
+    // Report the failure of the message to the application and complete
+    async fn fail(&mut self, error: String) {
+        let send_failure = HomaSendFailure::from_message(&self.message, error);
+        let _ = self
+            .application_writer_handle
+            .tx
+            .send(ApplicationWriterMessage::FromMessageSender(send_failure))
+            .await;
+        self.complete().await;
     }
 
     // Close the receiving channel and inform the Application actor
@@ -189,30 +173,49 @@ impl MessageSender {
     }
 }
 
-// Get unscheduled priority for remote host and workload of the current host,
-// send all unscheduled datagrams and then send all scheduled datagrams if
-// necessary
+// Get unscheduled priority and unscheduled datagram limit for remote host and
+// workload of the current host, then feed the state machine grants, resends
+// and timeouts until it finishes or a datagram could not be read
 async fn run_message_sender(mut message_sender: MessageSender) {
-    message_sender.unscheduled_priority = message_sender
+    let unscheduled_priority = message_sender
         .priority_manager_handle
         .get_unscheduled_priority(
             message_sender.destination_address,
             message_sender.content_length,
         )
         .await;
+    let unscheduled_datagram_limit = message_sender
+        .priority_manager_handle
+        .get_unscheduled_datagram_limit(message_sender.destination_address)
+        .await;
     message_sender.workload = message_sender
         .workload_manager_handle
         .get_workload()
         .await
         .expect("MessageSender -> WorkloadManager failed");
-    if let Some(grant_or_resend) = message_sender.send_unscheduled_datagrams().await {
-        if !message_sender.check_message_transmitted(&grant_or_resend) {
-            message_sender
-                .send_requested_datagrams(grant_or_resend)
-                .await;
+    message_sender.workload_time = Instant::now();
+    let mut core = MessageSenderCore::new(
+        ProtocolConfig::from_config(&CONFIG),
+        message_sender.message.datagram_count() as u32,
+        unscheduled_priority,
+        unscheduled_datagram_limit,
+    );
+    message_sender.unscheduled_datagrams = core.unscheduled_datagrams();
+    let mut actions = core.start();
+    loop {
+        if let Err(e) = message_sender.handle_actions(actions).await {
+            message_sender.fail(e).await;
+            return;
+        }
+        if core.is_finished() {
+            return;
         }
+        let event = select! {
+            _ = sleep_until(message_sender.deadline) => MessageSenderEvent::TimerFired,
+            Some(datagram) = message_sender.rx.recv() => MessageSenderEvent::Datagram(datagram),
+        };
+        actions = core.handle(event);
     }
-    message_sender.complete().await;
 }
 
 #[derive(Clone)]
@@ -224,6 +227,7 @@ impl MessageSenderHandle {
     pub fn new(
         message: HomaMessage,
         application_handle: ApplicationHandle,
+        application_writer_handle: ApplicationWriterHandle,
         datagram_sender_handle: DatagramSenderHandle,
         priority_manager_handle: PriorityManagerHandle,
         workload_manager_handle: WorkloadManagerHandle,
@@ -231,24 +235,23 @@ impl MessageSenderHandle {
         let (tx, rx) = channel::<HomaDatagram>(1000);
         let source_address = Ipv4Addr::from(message.source_address);
         let destination_address = Ipv4Addr::from(message.destination_address);
-        let datagrams = message.split();
         let message_sender = MessageSender {
             message_id: message.id,
             rx,
 
             source_address,
             destination_address,
+            content_length: message.content_length(),
+            unscheduled_datagrams: 0,
 
-            source_id: message.source_id,
-            destination_id: message.destination_id,
-            content_length: message.content.len() as u64,
-
-            datagrams,
+            message,
 
-            unscheduled_priority: 0,
-            workload: [0; CONST::UNSCHEDULED_PRIORITY_PARTITIONS],
+            workload: HomaWorkload::default(),
+            workload_time: Instant::now(),
+            deadline: Instant::now(),
 
             application_handle,
+            application_writer_handle,
             datagram_sender_handle,
             priority_manager_handle,
             workload_manager_handle,
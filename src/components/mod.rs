@@ -1,3 +1,4 @@
+pub mod admin_listener;
 pub mod application;
 pub mod application_listener;
 pub mod application_reader;
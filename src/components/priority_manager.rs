@@ -1,5 +1,6 @@
+use crate::components::workload_manager::default_workload;
 use crate::config::CONFIG;
-use crate::config::CONST;
+use crate::models::workload::HomaWorkload;
 use priority_queue::PriorityQueue;
 use std::cmp::Ordering;
 use std::cmp::Reverse;
@@ -8,6 +9,8 @@ use std::net::Ipv4Addr;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tokio::sync::oneshot::channel;
+use tokio::time::Duration;
+use tokio::time::Instant;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum PriorityLevelEntry {
@@ -36,9 +39,13 @@ struct PriorityManager {
     rx: mpsc::Receiver<PriorityManagerMessage>,
     queue: PriorityQueue<(u64, u64), Reverse<u64>>,
     senders: HashMap<u64, oneshot::Sender<()>>,
-    scheduled_priority_levels: [PriorityLevelEntry; CONST::SCHEDULED_PRIORITY_LEVELS],
-    unscheduled_priority_partitions:
-        HashMap<Ipv4Addr, [u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS]>,
+    // One entry per scheduled message granted at once, sorted by priority
+    scheduled_priority_levels: Vec<PriorityLevelEntry>,
+    // Levels of the local host given to unscheduled datagrams,
+    // the levels below are given to scheduled datagrams
+    unscheduled_priority_levels: usize,
+    // Workloads of remote hosts and the instants their limits expire at
+    remote_workloads: HashMap<Ipv4Addr, (HomaWorkload, Instant)>,
 }
 
 impl PriorityManager {
@@ -70,7 +77,7 @@ impl PriorityManager {
         if let Some(position) = self.find_message_position(id) {
             return self.scheduled_priority_levels.get_mut(position);
         }
-        return None;
+        None
     }
 
     fn update_message(&mut self, id: u64, remaining_datagrams: u64) -> Option<u8> {
@@ -80,7 +87,10 @@ impl PriorityManager {
             self.sort_priority_levels();
         }
         if let Some(position) = self.find_message_position(id) {
-            return Some(position as u8 * CONST::PRIORITY_LEVEL_WIDTH as u8);
+            let scheduled_priority_levels =
+                CONFIG.priority_levels() - self.unscheduled_priority_levels;
+            let level = scheduled_priority_level(position, scheduled_priority_levels);
+            return Some(CONFIG.dscp(level));
         }
         None
     }
@@ -136,39 +146,44 @@ impl PriorityManager {
         message_length: u64,
         tx: oneshot::Sender<u8>,
     ) {
-        match self.unscheduled_priority_partitions.get(&address) {
-            Some(priority_level_partitions) => {
-                for (i, partition) in priority_level_partitions.iter().enumerate() {
-                    if message_length <= *partition {
-                        let _ = tx.send((56 - i * 8) as u8);
-                        return;
-                    }
-                }
-                let _ = tx.send((64 - priority_level_partitions.len() * 8) as u8);
-            }
-            _ => {
-                let rtt_bytes =
-                    CONFIG.UNSCHEDULED_DATAGRAM_LIMIT * CONFIG.DATAGRAM_PAYLOAD_LENGTH as usize;
-                let priority_level_width = rtt_bytes / CONST::UNSCHEDULED_PRIORITY_LEVELS;
-                for i in 1..CONST::UNSCHEDULED_PRIORITY_LEVELS {
-                    let partition = (i * priority_level_width) as u64;
-                    if message_length <= partition {
-                        let _ = tx.send((64 - i * 8) as u8);
-                        return;
-                    }
-                }
-                let _ = tx.send((64 - CONST::UNSCHEDULED_PRIORITY_LEVELS * 8) as u8);
+        let level = match self.remote_workloads.get(&address) {
+            Some((workload, _)) => unscheduled_priority_level(workload, message_length),
+            None => unscheduled_priority_level(&default_workload(), message_length),
+        };
+        let _ = tx.send(CONFIG.dscp(level));
+    }
+
+    // The limit of an incast at the remote host holds for the lifetime it
+    // was advertised with, so that it is lifted once the burst subsides
+    fn handle_get_unscheduled_datagram_limit(&self, address: Ipv4Addr, tx: oneshot::Sender<u32>) {
+        let limit = match self.remote_workloads.get(&address) {
+            Some((workload, limit_expiry))
+                if workload.unscheduled_datagram_limit > 0 && Instant::now() < *limit_expiry =>
+            {
+                workload.unscheduled_datagram_limit
             }
+            _ => CONFIG.UNSCHEDULED_DATAGRAM_LIMIT as u32,
+        };
+        let _ = tx.send(limit.min(CONFIG.UNSCHEDULED_DATAGRAM_LIMIT as u32));
+    }
+
+    // Datagrams without a workload are ignored, the limit of the workload
+    // expires after its lifetime from now, so that a workload taken long
+    // ago does not extend the limit
+    fn handle_put_remote_workload(&mut self, address: Ipv4Addr, workload: HomaWorkload) {
+        if workload.is_advertised() {
+            let limit_expiry =
+                Instant::now() + Duration::from_micros(workload.limit_lifetime as u64);
+            self.remote_workloads
+                .insert(address, (workload, limit_expiry));
         }
     }
 
-    fn handle_put_priority_level_partions(
-        &mut self,
-        address: Ipv4Addr,
-        priority_level_partitions: [u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS],
-    ) {
-        self.unscheduled_priority_partitions
-            .insert(address, priority_level_partitions);
+    // Re-partition the levels between unscheduled and scheduled datagrams,
+    // granted messages take their new level with their next grant
+    fn handle_put_unscheduled_priority_levels(&mut self, unscheduled_priority_levels: usize) {
+        self.unscheduled_priority_levels =
+            unscheduled_priority_levels.clamp(1, CONFIG.priority_levels() - 1);
     }
 
     fn handle_priority_manager_message(
@@ -189,23 +204,56 @@ impl PriorityManager {
             GetUnscheduledPriority(address, message_length, tx) => {
                 self.handle_get_unscheduled_priority(address, message_length, tx)
             }
-            PutUnscheduledPriorityLevelPartitions(address, priority_level_partitions) => {
-                self.handle_put_priority_level_partions(address, priority_level_partitions);
+            GetUnscheduledDatagramLimit(address, tx) => {
+                self.handle_get_unscheduled_datagram_limit(address, tx)
+            }
+            PutRemoteWorkload(address, workload) => {
+                self.handle_put_remote_workload(address, workload);
+            }
+            PutUnscheduledPriorityLevels(unscheduled_priority_levels) => {
+                self.handle_put_unscheduled_priority_levels(unscheduled_priority_levels);
             }
         };
     }
 }
 
+// Scheduled level of the position among the scheduled messages granted at
+// once, the highest positions take the highest levels and the positions
+// beyond the number of levels share the lowest level
+fn scheduled_priority_level(position: usize, scheduled_priority_levels: usize) -> usize {
+    (position + scheduled_priority_levels).saturating_sub(CONFIG.OVERCOMMITMENT)
+}
+
+// Index of the first cutoff the message length fits under, the
+// cutoffs of the remote host may be more or less than its levels
+fn unscheduled_partition(cutoffs: &[u64], message_length: u64) -> usize {
+    cutoffs
+        .iter()
+        .position(|cutoff| message_length <= *cutoff)
+        .unwrap_or(cutoffs.len())
+}
+
+// Unscheduled level of the message among the unscheduled levels the remote
+// host advertised, the first partition takes the highest level and the
+// partitions beyond the number of levels share the lowest level
+fn unscheduled_priority_level(workload: &HomaWorkload, message_length: u64) -> usize {
+    let levels = (workload.unscheduled_priority_levels as usize).clamp(1, CONFIG.priority_levels());
+    let partition = unscheduled_partition(&workload.cutoffs, message_length);
+    CONFIG.priority_levels() - 1 - partition.min(levels - 1)
+}
+
 pub enum PriorityManagerMessage {
     RegisterScheduledMessage(u64, u64, oneshot::Sender<()>),
     GetScheduledPriority(u64, u64, oneshot::Sender<u8>),
     UnregisterScheduledMessage(u64),
     GetUnscheduledPriority(Ipv4Addr, u64, oneshot::Sender<u8>),
-    PutUnscheduledPriorityLevelPartitions(Ipv4Addr, [u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS]),
+    GetUnscheduledDatagramLimit(Ipv4Addr, oneshot::Sender<u32>),
+    PutRemoteWorkload(Ipv4Addr, HomaWorkload),
+    PutUnscheduledPriorityLevels(usize),
 }
 
-fn run_priority_manager(mut priority_manager: PriorityManager) {
-    while let Some(priority_manager_message) = priority_manager.rx.blocking_recv() {
+async fn run_priority_manager(mut priority_manager: PriorityManager) {
+    while let Some(priority_manager_message) = priority_manager.rx.recv().await {
         priority_manager.handle_priority_manager_message(priority_manager_message);
     }
 }
@@ -215,6 +263,12 @@ pub struct PriorityManagerHandle {
     tx: mpsc::Sender<PriorityManagerMessage>,
 }
 
+impl Default for PriorityManagerHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PriorityManagerHandle {
     pub fn new() -> Self {
         let (tx, rx) = mpsc::channel::<PriorityManagerMessage>(1000);
@@ -223,10 +277,11 @@ impl PriorityManagerHandle {
             rx,
             queue: PriorityQueue::new(),
             senders: HashMap::new(),
-            scheduled_priority_levels: [Empty; CONST::SCHEDULED_PRIORITY_LEVELS],
-            unscheduled_priority_partitions: HashMap::new(),
+            scheduled_priority_levels: vec![Empty; CONFIG.OVERCOMMITMENT],
+            unscheduled_priority_levels: CONFIG.UNSCHEDULED_PRIORITY_LEVELS,
+            remote_workloads: HashMap::new(),
         };
-        tokio::task::spawn_blocking(move || run_priority_manager(priority_manager));
+        tokio::spawn(run_priority_manager(priority_manager));
         Self { tx }
     }
 
@@ -261,14 +316,71 @@ impl PriorityManagerHandle {
         rx.await.unwrap()
     }
 
-    pub async fn put_unscheduled_priority_level_partitions(
-        &self,
-        address: Ipv4Addr,
-        priority_level_partitions: [u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS],
-    ) {
+    pub async fn get_unscheduled_datagram_limit(&self, address: Ipv4Addr) -> u32 {
         use PriorityManagerMessage::*;
-        let priority_manager_message =
-            PutUnscheduledPriorityLevelPartitions(address, priority_level_partitions);
+        let (tx, rx) = oneshot::channel();
+        let priority_manager_message = GetUnscheduledDatagramLimit(address, tx);
         let _ = self.tx.send(priority_manager_message).await;
+        rx.await.unwrap()
+    }
+
+    pub async fn put_remote_workload(&self, address: Ipv4Addr, workload: HomaWorkload) {
+        use PriorityManagerMessage::*;
+        let priority_manager_message = PutRemoteWorkload(address, workload);
+        let _ = self.tx.send(priority_manager_message).await;
+    }
+
+    pub async fn put_unscheduled_priority_levels(&self, unscheduled_priority_levels: usize) {
+        use PriorityManagerMessage::*;
+        let priority_manager_message = PutUnscheduledPriorityLevels(unscheduled_priority_levels);
+        let _ = self.tx.send(priority_manager_message).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::scheduled_priority_level;
+    use super::unscheduled_partition;
+    use super::unscheduled_priority_level;
+    use crate::config::CONFIG;
+    use crate::models::workload::HomaWorkload;
+
+    #[test]
+    fn priority_level_test() {
+        // The default levels keep the DSCP values of 8 times the level
+        let workload = HomaWorkload {
+            unscheduled_priority_levels: 6,
+            cutoffs: vec![100, 200, 300, 400, 500],
+            ..Default::default()
+        };
+        assert_eq!(CONFIG.dscp(scheduled_priority_level(0, 2)), 0);
+        assert_eq!(CONFIG.dscp(scheduled_priority_level(1, 2)), 8);
+        assert_eq!(CONFIG.dscp(unscheduled_priority_level(&workload, 50)), 56);
+        assert_eq!(
+            CONFIG.dscp(unscheduled_priority_level(&workload, 10000)),
+            16
+        );
+        assert_eq!(CONFIG.control_dscp(), 56);
+
+        // Remote hosts may advertise more or fewer cutoffs than their levels
+        let cutoffs = [100, 200, 300, 400, 500, 600, 700, 800];
+        assert_eq!(unscheduled_partition(&cutoffs, 150), 1);
+        assert_eq!(unscheduled_partition(&cutoffs, 10000), 8);
+        assert_eq!(unscheduled_partition(&[100], 150), 1);
+        assert_eq!(CONFIG.priority_level(48), 6);
+
+        // A workload giving more levels to scheduled datagrams moves the
+        // unscheduled datagrams and the highest scheduled level up
+        let workload = HomaWorkload {
+            unscheduled_priority_levels: 3,
+            cutoffs: vec![1000, 2000],
+            ..Default::default()
+        };
+        assert_eq!(
+            CONFIG.dscp(unscheduled_priority_level(&workload, 10000)),
+            40
+        );
+        assert_eq!(CONFIG.dscp(scheduled_priority_level(1, 5)), 32);
+        assert_eq!(CONFIG.dscp(scheduled_priority_level(0, 5)), 24);
     }
 }
@@ -1,82 +1,249 @@
+/*
+WorkloadManager actor
+
+Sample the lengths of received messages to compute the workload advertised
+to remote hosts, the number of priority levels given to unscheduled datagrams
+and the message length cutoffs between them
+
+Unscheduled datagrams get levels in proportion to the share of bytes sent
+unscheduled, the PriorityManager is told to re-partition the levels whenever
+the split changes
+
+Messages starting within the incast window with more unscheduled datagrams
+than the incast threshold of messages sending all their unscheduled datagrams
+mark an incast, the unscheduled datagrams senders may send are then reduced
+so that the unscheduled datagrams of the burst stay those of the threshold
+
+Message starts are counted at the arrival of their first datagram, and the
+reduced limit holds for the incast hold after the burst was last counted, so
+that senders starting their next message a round trip or more later still
+send less. It is advertised with its remaining lifetime and
+recomputed whenever the workload is read, so that it is lifted once the hold
+ends even if no message starts
+*/
+use crate::components::priority_manager::PriorityManagerHandle;
 use crate::config::CONFIG;
 use crate::config::CONST;
+use crate::models::workload::HomaWorkload;
+use crate::utils::dilate_timeout;
 use crate::utils::quantile;
+use std::collections::VecDeque;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::sync::oneshot;
+use tokio::time::Duration;
+use tokio::time::Instant;
 
 struct WorkloadManager {
     message_lengths: Vec<u64>,
-    workload: [u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS],
+    // Sum of the sampled message lengths and of their unscheduled bytes
+    total_bytes: u64,
+    unscheduled_bytes: u64,
+    incast_detector: IncastDetector,
+    workload: HomaWorkload,
     rx: Receiver<WorkloadManagerMessage>,
+    priority_manager_handle: PriorityManagerHandle,
 }
 
 impl WorkloadManager {
-    fn handle_workload_manager_message(
+    async fn handle_workload_manager_message(
         &mut self,
         workload_manager_message: WorkloadManagerMessage,
     ) {
         use WorkloadManagerMessage::*;
         match workload_manager_message {
             GetWorkload(return_chan) => self.handle_get_workload(return_chan),
-            UpdateWorkload(message_length, return_chan) => {
-                self.handle_update_workload(message_length, return_chan)
+            UpdateWorkload(message_length, message_start, return_chan) => {
+                self.handle_update_workload(message_length, message_start, return_chan)
+                    .await
             }
         }
     }
 
-    fn handle_get_workload(
-        &self,
-        return_chan: oneshot::Sender<[u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS]>,
-    ) {
+    fn handle_get_workload(&mut self, return_chan: oneshot::Sender<HomaWorkload>) {
+        self.set_incast_limit(Instant::now());
         let _ = return_chan.send(self.workload.clone());
     }
 
-    fn handle_update_workload(
+    async fn handle_update_workload(
         &mut self,
         message_length: u64,
-        return_chan: oneshot::Sender<[u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS]>,
+        message_start: Instant,
+        return_chan: oneshot::Sender<HomaWorkload>,
     ) {
         let i = self
             .message_lengths
             .binary_search(&message_length)
             .unwrap_or_else(|j| j);
         self.message_lengths.insert(i, message_length);
-        self.calculate_priority_level_partitions();
+        self.total_bytes = self.total_bytes.saturating_add(message_length);
+        self.unscheduled_bytes = self
+            .unscheduled_bytes
+            .saturating_add(message_length.min(unscheduled_bytes_limit()));
+        self.calculate_priority_level_partitions().await;
+        self.detect_incast(message_length, message_start);
         let _ = return_chan.send(self.workload.clone());
     }
 
-    fn calculate_priority_level_partitions(&mut self) {
-        self.workload = [0; CONST::UNSCHEDULED_PRIORITY_PARTITIONS];
+    // Count the unscheduled datagrams of the message towards an incast at
+    // the arrival of its first datagram, so that a burst is not spread out
+    // by the time the actors take to start its messages, the limit holds
+    // from the time the burst is detected
+    fn detect_incast(&mut self, message_length: u64, message_start: Instant) {
+        let datagrams = message_length
+            .div_ceil(CONFIG.DATAGRAM_PAYLOAD_LENGTH as u64)
+            .min(CONFIG.UNSCHEDULED_DATAGRAM_LIMIT as u64) as usize;
+        let now = Instant::now();
+        self.incast_detector.start(message_start, datagrams, now);
+        self.set_incast_limit(now);
+    }
+
+    // Advertise the limit held at the instant
+    fn set_incast_limit(&mut self, now: Instant) {
+        let (limit, lifetime) = self.incast_detector.limit(now);
+        self.workload.unscheduled_datagram_limit = limit;
+        self.workload.limit_lifetime = lifetime.as_micros() as u32;
+    }
+
+    async fn calculate_priority_level_partitions(&mut self) {
+        let previous_levels = self.workload.unscheduled_priority_levels;
         if self.message_lengths.len() < CONST::MINIMUM_WORKLOAD_SAMPLE_SIZE {
-            let rtt_bytes =
-                CONFIG.UNSCHEDULED_DATAGRAM_LIMIT * CONFIG.DATAGRAM_PAYLOAD_LENGTH as usize;
-            let priority_level_width = rtt_bytes / CONST::UNSCHEDULED_PRIORITY_LEVELS;
-            for i in 1..CONST::UNSCHEDULED_PRIORITY_LEVELS {
-                let partition = (i * priority_level_width) as u64;
-                self.workload[i - 1] = partition;
-            }
+            self.workload = default_workload();
         } else {
-            for i in 1..CONST::UNSCHEDULED_PRIORITY_LEVELS {
-                let q = (1. / CONST::UNSCHEDULED_PRIORITY_LEVELS as f32) * i as f32;
-                self.workload[i - 1] = quantile(&self.message_lengths, q);
+            let levels = if CONFIG.FIXED_PRIORITY_LEVELS {
+                CONFIG.UNSCHEDULED_PRIORITY_LEVELS
+            } else {
+                unscheduled_priority_levels(self.total_bytes, self.unscheduled_bytes)
+            };
+            self.workload = HomaWorkload {
+                unscheduled_priority_levels: levels as u8,
+                cutoffs: (1..levels)
+                    .map(|i| {
+                        let q = (1. / levels as f32) * i as f32;
+                        quantile(&self.message_lengths, q)
+                    })
+                    .collect(),
+                unscheduled_datagram_limit: CONFIG.UNSCHEDULED_DATAGRAM_LIMIT as u32,
+                limit_lifetime: 0,
+            };
+        }
+        if self.workload.unscheduled_priority_levels != previous_levels {
+            self.priority_manager_handle
+                .put_unscheduled_priority_levels(self.workload.unscheduled_priority_levels as usize)
+                .await;
+        }
+    }
+}
+
+// Bytes of a message sent before the receiver grants any
+fn unscheduled_bytes_limit() -> u64 {
+    CONFIG.UNSCHEDULED_DATAGRAM_LIMIT as u64 * CONFIG.DATAGRAM_PAYLOAD_LENGTH as u64
+}
+
+// Cutoffs splitting the unscheduled bytes evenly over the unscheduled
+// priority levels, used until enough message lengths were sampled
+pub fn default_workload() -> HomaWorkload {
+    let levels = CONFIG.UNSCHEDULED_PRIORITY_LEVELS;
+    let priority_level_width = unscheduled_bytes_limit() / levels as u64;
+    HomaWorkload {
+        unscheduled_priority_levels: levels as u8,
+        cutoffs: (1..levels as u64)
+            .map(|i| i * priority_level_width)
+            .collect(),
+        unscheduled_datagram_limit: CONFIG.UNSCHEDULED_DATAGRAM_LIMIT as u32,
+        limit_lifetime: 0,
+    }
+}
+
+// Window over which starting messages are counted towards an incast
+pub fn incast_window() -> Duration {
+    Duration::from_micros(dilate_timeout(CONFIG.INCAST_WINDOW))
+}
+
+// Time the limit of an incast holds after the burst was last counted
+pub fn incast_hold() -> Duration {
+    Duration::from_micros(dilate_timeout(CONFIG.INCAST_HOLD))
+}
+
+// Unscheduled datagrams of the messages starting within the incast window
+// and the limit held since the last burst
+#[derive(Default)]
+struct IncastDetector {
+    // Instants the messages started at and their unscheduled datagrams
+    message_starts: VecDeque<(Instant, usize)>,
+    burst_datagrams: usize,
+    limit: u32,
+    limit_expiry: Option<Instant>,
+}
+
+impl IncastDetector {
+    // Count the message starting at the message start towards the burst,
+    // dropping the starts which left the window, a burst beyond the threshold
+    // holds its limit, or the lower limit still held, from now on
+    fn start(&mut self, message_start: Instant, datagrams: usize, now: Instant) {
+        while let Some((first_start, first_datagrams)) = self.message_starts.front() {
+            if message_start.duration_since(*first_start) < incast_window() {
+                break;
             }
+            self.burst_datagrams -= first_datagrams;
+            self.message_starts.pop_front();
         }
+        self.message_starts.push_back((message_start, datagrams));
+        self.burst_datagrams += datagrams;
+        let limit = incast_unscheduled_datagram_limit(self.burst_datagrams);
+        if limit < CONFIG.UNSCHEDULED_DATAGRAM_LIMIT as u32 {
+            self.limit = match self.limit_expiry {
+                Some(limit_expiry) if now < limit_expiry => self.limit.min(limit),
+                _ => limit,
+            };
+            self.limit_expiry = Some(now + incast_hold());
+        }
+    }
+
+    // Return the limit held and how long it still holds,
+    // the full limit once the hold ended
+    fn limit(&self, now: Instant) -> (u32, Duration) {
+        match self.limit_expiry {
+            Some(limit_expiry) if now < limit_expiry => (self.limit, limit_expiry - now),
+            _ => (CONFIG.UNSCHEDULED_DATAGRAM_LIMIT as u32, Duration::ZERO),
+        }
+    }
+}
+
+// Unscheduled datagrams senders may send while the messages starting within
+// the incast window send the burst datagrams unscheduled, at least one
+fn incast_unscheduled_datagram_limit(burst_datagrams: usize) -> u32 {
+    let limit = CONFIG.UNSCHEDULED_DATAGRAM_LIMIT;
+    let threshold = CONFIG.INCAST_THRESHOLD * limit;
+    if burst_datagrams <= threshold {
+        return limit as u32;
     }
+    (limit * threshold / burst_datagrams).max(1) as u32
 }
 
-fn run_workload_manager(mut workload_manager: WorkloadManager) {
-    workload_manager.calculate_priority_level_partitions();
-    while let Some(workload_manager_message) = workload_manager.rx.blocking_recv() {
-        workload_manager.handle_workload_manager_message(workload_manager_message);
+// Levels of the unscheduled datagrams in proportion to the share of the
+// sampled bytes sent unscheduled, leaving at least one level to each
+fn unscheduled_priority_levels(total_bytes: u64, unscheduled_bytes: u64) -> usize {
+    let levels = CONFIG.priority_levels();
+    if total_bytes == 0 {
+        return levels - 1;
+    }
+    let share = unscheduled_bytes as f64 / total_bytes as f64;
+    ((share * levels as f64).round() as usize).clamp(1, levels - 1)
+}
+
+async fn run_workload_manager(mut workload_manager: WorkloadManager) {
+    workload_manager.calculate_priority_level_partitions().await;
+    while let Some(workload_manager_message) = workload_manager.rx.recv().await {
+        workload_manager
+            .handle_workload_manager_message(workload_manager_message)
+            .await;
     }
 }
 
 pub enum WorkloadManagerMessage {
-    GetWorkload(oneshot::Sender<[u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS]>),
-    UpdateWorkload(
-        u64,
-        oneshot::Sender<[u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS]>,
-    ),
+    GetWorkload(oneshot::Sender<HomaWorkload>),
+    // Length of a message and the instant its first datagram arrived at
+    UpdateWorkload(u64, Instant, oneshot::Sender<HomaWorkload>),
 }
 
 #[derive(Clone)]
@@ -85,22 +252,24 @@ pub struct WorkloadManagerHandle {
 }
 
 impl WorkloadManagerHandle {
-    pub fn new() -> Self {
+    pub fn new(priority_manager_handle: PriorityManagerHandle) -> Self {
         let (tx, rx) = channel::<WorkloadManagerMessage>(1000);
         let workload_manager = WorkloadManager {
             rx,
             message_lengths: Vec::new(),
-            workload: [0; CONST::UNSCHEDULED_PRIORITY_PARTITIONS],
+            total_bytes: 0,
+            unscheduled_bytes: 0,
+            incast_detector: IncastDetector::default(),
+            workload: HomaWorkload::default(),
+            priority_manager_handle,
         };
-        tokio::task::spawn_blocking(move || run_workload_manager(workload_manager));
+        tokio::spawn(run_workload_manager(workload_manager));
         Self { tx }
     }
 
-    pub async fn get_workload(
-        &self,
-    ) -> Result<[u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS], String> {
+    pub async fn get_workload(&self) -> Result<HomaWorkload, String> {
         use WorkloadManagerMessage::*;
-        let (tx, rx) = oneshot::channel::<[u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS]>();
+        let (tx, rx) = oneshot::channel::<HomaWorkload>();
         let workload_manager_message = GetWorkload(tx);
         self.tx
             .send(workload_manager_message)
@@ -113,10 +282,11 @@ impl WorkloadManagerHandle {
     pub async fn update_workload(
         &self,
         message_length: u64,
-    ) -> Result<[u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS], String> {
+        message_start: Instant,
+    ) -> Result<HomaWorkload, String> {
         use WorkloadManagerMessage::*;
-        let (tx, rx) = oneshot::channel::<[u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS]>();
-        let workload_manager_message = UpdateWorkload(message_length, tx);
+        let (tx, rx) = oneshot::channel::<HomaWorkload>();
+        let workload_manager_message = UpdateWorkload(message_length, message_start, tx);
         self.tx
             .send(workload_manager_message)
             .await
@@ -125,3 +295,82 @@ impl WorkloadManagerHandle {
             .map_err(|_| "WorkloadManager failed to send update response".to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::incast_hold;
+    use super::incast_unscheduled_datagram_limit;
+    use super::incast_window;
+    use super::unscheduled_bytes_limit;
+    use super::unscheduled_priority_levels;
+    use super::IncastDetector;
+    use crate::models::workload::HomaWorkload;
+    use tokio::time::Duration;
+    use tokio::time::Instant;
+
+    // Levels for the sampled message lengths
+    fn levels(message_lengths: &[u64]) -> usize {
+        let total_bytes = message_lengths.iter().sum();
+        let unscheduled_bytes = message_lengths
+            .iter()
+            .map(|length| (*length).min(unscheduled_bytes_limit()))
+            .sum();
+        unscheduled_priority_levels(total_bytes, unscheduled_bytes)
+    }
+
+    #[test]
+    fn unscheduled_priority_levels_test() {
+        // Messages sent entirely unscheduled leave one level to scheduled datagrams
+        assert_eq!(levels(&[100, 1000, 8000]), 7);
+        // Large messages leave one level to unscheduled datagrams
+        assert_eq!(levels(&[1_000_000, 10_000_000]), 1);
+        // Half the bytes sent unscheduled split the levels evenly
+        assert_eq!(levels(&[8400, 8400, 33600]), 4);
+        assert_eq!(levels(&[]), 7);
+    }
+
+    #[test]
+    fn incast_test() {
+        // Bursts beyond the threshold share the unscheduled datagrams of the threshold
+        assert_eq!(incast_unscheduled_datagram_limit(16 * 6), 6);
+        assert_eq!(incast_unscheduled_datagram_limit(32 * 6), 3);
+        assert_eq!(incast_unscheduled_datagram_limit(1000 * 6), 1);
+    }
+
+    #[test]
+    fn incast_expiry_test() {
+        let mut incast_detector = IncastDetector::default();
+        let start = Instant::now();
+        for _ in 0..32 {
+            incast_detector.start(start, 6, start);
+        }
+        assert_eq!(incast_detector.limit(start), (3, incast_hold()));
+
+        // A workload taken during the incast carries the remaining lifetime
+        let workload = HomaWorkload {
+            unscheduled_datagram_limit: 3,
+            limit_lifetime: incast_hold().as_micros() as u32,
+            ..Default::default()
+        };
+        assert_eq!(
+            workload.aged(incast_hold() / 2).limit_lifetime * 2,
+            workload.limit_lifetime
+        );
+        assert_eq!(workload.aged(incast_hold()).limit_lifetime, 0);
+
+        // The limit holds past the window, a message starting after the
+        // burst left the window neither lowers nor extends it
+        let later = start + incast_window();
+        incast_detector.start(later, 6, later);
+        assert_eq!(
+            incast_detector.limit(later),
+            (3, incast_hold() - incast_window())
+        );
+
+        // The limit is lifted once the hold ends without new bursts
+        assert_eq!(
+            incast_detector.limit(start + incast_hold()),
+            (6, Duration::ZERO)
+        );
+    }
+}
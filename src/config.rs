@@ -1,16 +1,82 @@
-use clap::arg;
-use clap::command;
+use crate::models::admin::HomaFaultDatagramType;
+use crate::models::admin::HomaFaultOptions;
 use clap::value_parser;
 use clap::Parser;
+use clap::ValueEnum;
 use lazy_static::lazy_static;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
 
 #[allow(non_snake_case)]
 pub mod CONST {
-    pub const UNSCHEDULED_PRIORITY_LEVELS: usize = 6;
-    pub const UNSCHEDULED_PRIORITY_PARTITIONS: usize = 5;
-    pub const SCHEDULED_PRIORITY_LEVELS: usize = 2;
     pub const PRIORITY_LEVEL_WIDTH: usize = 8;
     pub const MINIMUM_WORKLOAD_SAMPLE_SIZE: usize = 100;
+    pub const APPLICATION_WRITER_BATCH_LIMIT: usize = 64;
+    pub const APPLICATION_WRITER_QUEUE_LENGTH: usize = 1000;
+    pub const APPLICATION_WRITER_CHUNK_LENGTH: usize = 65536;
+    pub const DATAGRAM_BATCH_LIMIT: usize = 64;
+    pub const RESEND_RANGE_LIMIT: usize = 64;
+    pub const DATAGRAM_SENDER_QUEUE_LENGTH: usize = 10000;
+    pub const PACKET_BUFFER_LENGTH: usize = 2048;
+    pub const HOMA_PROTOCOL: u8 = 146;
+    pub const CAPTURE_MAX_BYTES: u64 = 100 * 1024 * 1024;
+    pub const CAPTURE_MAX_DURATION: u64 = 300;
+    // Batches of packets queued for the capture writer before packets are dropped
+    pub const CAPTURE_QUEUE_LENGTH: usize = 1024;
+    // Received batches queued by the transports of the local addresses
+    // before packets are dropped
+    pub const MULTI_TRANSPORT_QUEUE_LENGTH: usize = 1024;
+    pub const FAULT_REORDER_DELAY: u64 = 1;
+    // Packets held by the fault transport to be sent later, and packets
+    // queued to be held, before further packets to delay are dropped
+    pub const FAULT_DELAY_QUEUE_LENGTH: usize = 10000;
+    // Time in milliseconds a registering application has for each
+    // read and write of the registration
+    pub const REGISTRATION_TIMEOUT: u64 = 1000;
+    // Bytes of content kept for all applications which shut down with
+    // unacknowledged messages, until they register again
+    pub const UNACKNOWLEDGED_STORE_BYTES: u64 = 64 * 1024 * 1024;
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum TransportMode {
+    /// Raw IP protocol 146, requires CAP_NET_RAW
+    Raw,
+    /// Homa datagrams encapsulated in UDP
+    Udp,
+}
+
+// Local address homad exchanges datagrams on, settings left out
+// default to the global ones
+#[derive(Clone)]
+pub struct LocalAddress {
+    pub address: Ipv4Addr,
+    // Interface the sockets are bound to with SO_BINDTODEVICE
+    pub interface: Option<String>,
+    pub transport: Option<TransportMode>,
+    pub udp_port: Option<u16>,
+    // DSCP of each priority level on the network of the address
+    pub dscp_map: Vec<u8>,
+}
+
+impl LocalAddress {
+    // Local address with the global settings
+    pub fn new(address: Ipv4Addr) -> Self {
+        Self {
+            address,
+            interface: None,
+            transport: None,
+            udp_port: None,
+            dscp_map: Vec::new(),
+        }
+    }
+
+    pub fn dscp(&self, level: usize) -> u8 {
+        match self.dscp_map.get(level) {
+            Some(dscp) => *dscp,
+            None => CONFIG.dscp(level),
+        }
+    }
 }
 
 #[derive(Parser)]
@@ -20,6 +86,9 @@ pub struct Config {
     /// Path to socket for applications to connect to
     #[arg(short, default_value = "/tmp/homa.sock")]
     pub SOCKET_PATH: String,
+    /// Path to socket for administrative commands
+    #[arg(long, default_value = "/tmp/homa-admin.sock")]
+    pub ADMIN_SOCKET_PATH: String,
     /// Max message length
     #[arg(short, default_value_t = 524_288_000)]
     pub MESSAGE_MAX_LENGTH: u64,
@@ -41,8 +110,230 @@ pub struct Config {
     /// Large number of resends to issue
     #[arg(short = 'R', default_value_t = 20)]
     pub LARGE_RESENDS: usize,
+    /// Time in milliseconds after which a queued delivery is written ahead of shorter ones
+    #[arg(long, default_value_t = 50)]
+    pub DELIVERY_AGING: u64,
+    /// Time in milliseconds a disconnected application session can be resumed
+    #[arg(long, default_value_t = 5000)]
+    pub SESSION_GRACE: u64,
+    /// Max number of messages buffered for a disconnected application session
+    #[arg(long, default_value_t = 1000)]
+    pub SESSION_BUFFER: usize,
+    /// Transport used to exchange datagrams with other hosts
+    #[arg(long, value_enum, default_value_t = TransportMode::Raw)]
+    pub TRANSPORT: TransportMode,
+    /// Port used by the UDP transport on all hosts
+    #[arg(long, default_value_t = 4146)]
+    pub UDP_PORT: u16,
+    /// Interfaces to exchange datagrams on, with all their IPv4 addresses, all interfaces if omitted
+    #[arg(long, value_delimiter = ',')]
+    pub INTERFACE: Vec<String>,
+    /// Local address to exchange datagrams on, as ADDRESS[,interface=NAME][,transport=MODE][,udp-port=PORT][,dscp-map=DSCP:DSCP:...], repeatable
+    #[arg(long, value_parser = parse_local_address)]
+    pub LOCAL_ADDRESS: Vec<LocalAddress>,
+    /// Number of priority levels for unscheduled datagrams, until enough message lengths are sampled
+    #[arg(long, default_value_t = 6, value_parser = parse_priority_levels)]
+    pub UNSCHEDULED_PRIORITY_LEVELS: usize,
+    /// Number of priority levels for scheduled datagrams below the unscheduled ones, until enough message lengths are sampled
+    #[arg(long, default_value_t = 2, value_parser = parse_priority_levels)]
+    pub SCHEDULED_PRIORITY_LEVELS: usize,
+    /// Keep the split between unscheduled and scheduled levels instead of adapting it to the workload
+    #[arg(long)]
+    pub FIXED_PRIORITY_LEVELS: bool,
+    /// Number of scheduled messages a receiver grants at once
+    #[arg(long, default_value_t = 2, value_parser = parse_priority_levels)]
+    pub OVERCOMMITMENT: usize,
+    /// DSCP of each priority level from the lowest to the highest, 8 times the level if omitted
+    #[arg(long, value_delimiter = ',', value_parser = value_parser!(u8).range(..64))]
+    pub DSCP_MAP: Vec<u8>,
+    /// Bytes a receiver grants ahead of the received bytes of a scheduled message
+    #[arg(long, default_value_t = 10000)]
+    pub RTT_BYTES: u64,
+    /// Number of messages sending all their unscheduled datagrams within the incast window above which a receiver reduces the unscheduled datagrams of its senders
+    #[arg(long, default_value_t = 16)]
+    pub INCAST_THRESHOLD: usize,
+    /// Window in microseconds over which starting messages are counted towards an incast
+    #[arg(long, default_value_t = 100)]
+    pub INCAST_WINDOW: u64,
+    /// Time in microseconds the reduced unscheduled datagrams of an incast hold after the burst was last counted, longer than senders take to start their next message
+    #[arg(long, default_value_t = 100_000)]
+    pub INCAST_HOLD: u64,
+    /// Link speed in Mbit/s the sending of datagrams is paced to, 0 disables pacing
+    #[arg(long, default_value_t = 0)]
+    pub LINK_SPEED: u64,
+    /// Max number of bytes queued below the daemon in the qdisc and NIC
+    #[arg(long, default_value_t = 65536)]
+    pub NIC_QUEUE_BYTES: u64,
+    /// Probability of dropping an outgoing datagram
+    #[arg(long, default_value_t = 0.0, value_parser = parse_probability)]
+    pub FAULT_DROP: f64,
+    /// Probability of duplicating an outgoing datagram
+    #[arg(long, default_value_t = 0.0, value_parser = parse_probability)]
+    pub FAULT_DUPLICATE: f64,
+    /// Probability of reordering an outgoing datagram behind the following ones
+    #[arg(long, default_value_t = 0.0, value_parser = parse_probability)]
+    pub FAULT_REORDER: f64,
+    /// Probability of delaying an outgoing datagram
+    #[arg(long, default_value_t = 0.0, value_parser = parse_probability)]
+    pub FAULT_DELAY: f64,
+    /// Delay in milliseconds of delayed datagrams
+    #[arg(long, default_value_t = 100)]
+    pub FAULT_DELAY_MS: u64,
+    /// Probability of corrupting an outgoing datagram
+    #[arg(long, default_value_t = 0.0, value_parser = parse_probability)]
+    pub FAULT_CORRUPT: f64,
+    /// Datagram types faults are injected into, all types if omitted
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub FAULT_TYPES: Vec<HomaFaultDatagramType>,
+    /// Peer address faults are injected towards, all peers if omitted
+    #[arg(long)]
+    pub FAULT_PEER: Option<Ipv4Addr>,
+}
+
+impl Config {
+    // Faults to inject from startup
+    pub fn fault_options(&self) -> HomaFaultOptions {
+        HomaFaultOptions {
+            drop: self.FAULT_DROP,
+            duplicate: self.FAULT_DUPLICATE,
+            reorder: self.FAULT_REORDER,
+            delay: self.FAULT_DELAY,
+            delay_ms: self.FAULT_DELAY_MS,
+            corrupt: self.FAULT_CORRUPT,
+            datagram_types: self.FAULT_TYPES.clone(),
+            peer: self.FAULT_PEER,
+        }
+    }
+
+    // Scheduled levels come first, then the unscheduled levels,
+    // control datagrams are sent at the highest level
+    pub fn priority_levels(&self) -> usize {
+        self.SCHEDULED_PRIORITY_LEVELS + self.UNSCHEDULED_PRIORITY_LEVELS
+    }
+
+    pub fn dscp(&self, level: usize) -> u8 {
+        match self.DSCP_MAP.get(level) {
+            Some(dscp) => *dscp,
+            None => (level * CONST::PRIORITY_LEVEL_WIDTH) as u8,
+        }
+    }
+
+    pub fn control_dscp(&self) -> u8 {
+        self.dscp(self.priority_levels() - 1)
+    }
+
+    // Level of the DSCP, the lowest level if no level maps to it
+    pub fn priority_level(&self, dscp: u8) -> usize {
+        (0..self.priority_levels())
+            .rev()
+            .find(|level| self.dscp(*level) == dscp)
+            .unwrap_or(0)
+    }
+
+    // Check the options which depend on each other
+    pub fn validate(&self) -> Result<(), String> {
+        let priority_levels = self.priority_levels();
+        if self.DSCP_MAP.is_empty() && priority_levels * CONST::PRIORITY_LEVEL_WIDTH > 64 {
+            return Err(format!(
+                "{} priority levels need a --dscp-map",
+                priority_levels
+            ));
+        }
+        if !self.DSCP_MAP.is_empty() && self.DSCP_MAP.len() != priority_levels {
+            return Err(format!(
+                "--dscp-map has {} entries for {} priority levels",
+                self.DSCP_MAP.len(),
+                priority_levels
+            ));
+        }
+        for local_address in &self.LOCAL_ADDRESS {
+            if !local_address.dscp_map.is_empty() && local_address.dscp_map.len() != priority_levels
+            {
+                return Err(format!(
+                    "dscp-map of {} has {} entries for {} priority levels",
+                    local_address.address,
+                    local_address.dscp_map.len(),
+                    priority_levels
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_local_address(value: &str) -> Result<LocalAddress, String> {
+    let mut fields = value.split(',');
+    let address = fields
+        .next()
+        .unwrap_or_default()
+        .parse::<Ipv4Addr>()
+        .map_err(|e| e.to_string())?;
+    let mut local_address = LocalAddress::new(address);
+    for field in fields {
+        match field.split_once('=') {
+            Some(("interface", interface)) if !interface.is_empty() => {
+                local_address.interface = Some(interface.to_string())
+            }
+            Some(("transport", transport)) => {
+                local_address.transport = Some(TransportMode::from_str(transport, true)?)
+            }
+            Some(("udp-port", udp_port)) => {
+                local_address.udp_port = Some(udp_port.parse::<u16>().map_err(|e| e.to_string())?)
+            }
+            Some(("dscp-map", dscp_map)) => {
+                local_address.dscp_map = dscp_map
+                    .split(':')
+                    .map(|dscp| match dscp.parse::<u8>() {
+                        Ok(dscp) if dscp < 64 => Ok(dscp),
+                        _ => Err(format!("invalid DSCP {}", dscp)),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            _ => return Err(format!("unknown setting {}", field)),
+        }
+    }
+    Ok(local_address)
+}
+
+fn parse_priority_levels(value: &str) -> Result<usize, String> {
+    let levels = value.parse::<usize>().map_err(|e| e.to_string())?;
+    if (1..=64).contains(&levels) {
+        Ok(levels)
+    } else {
+        Err("must be between 1 and 64".to_string())
+    }
+}
+
+fn parse_probability(value: &str) -> Result<f64, String> {
+    let probability = value.parse::<f64>().map_err(|e| e.to_string())?;
+    if (0.0..=1.0).contains(&probability) {
+        Ok(probability)
+    } else {
+        Err("must be between 0 and 1".to_string())
+    }
+}
+
+// Configuration handed over by a binary parsing its own arguments
+static INITIAL_CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
+// Use the configuration instead of parsing the arguments,
+// must be called before CONFIG is first accessed
+pub fn set_config(config: Config) {
+    *INITIAL_CONFIG.lock().unwrap() = Some(config);
+}
+
+// Tests run with the default configuration,
+// the arguments of the test binary belong to the test harness
+fn parse_config() -> Config {
+    if let Some(config) = INITIAL_CONFIG.lock().unwrap().take() {
+        config
+    } else if cfg!(test) {
+        Config::parse_from(["homad"])
+    } else {
+        Config::parse()
+    }
 }
 
 lazy_static! {
-    pub static ref CONFIG: Config = Config::parse();
+    pub static ref CONFIG: Config = parse_config();
 }
@@ -0,0 +1,114 @@
+/*
+DispatchTable
+
+Concurrent map from application or message ids to actor handles, read by the
+DatagramReceivers for every received datagram and written by the actors
+creating and retiring the handles
+
+Entries are spread over shards by the hash of their key, each behind its own
+read-write lock, so lookups only share a lock with writers to the same shard
+and never wait for each other. Lookups clone the handle out of the shard, no
+lock is held while a datagram is sent to the handle
+*/
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::hash::Hash;
+use std::sync::RwLock;
+
+const SHARDS: usize = 64;
+
+pub struct DispatchTable<K, V> {
+    shards: Vec<RwLock<HashMap<K, V>>>,
+    hasher: RandomState,
+}
+
+impl<K: Eq + Hash, V: Clone> DispatchTable<K, V> {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, V>> {
+        &self.shards[self.hasher.hash_one(key) as usize % SHARDS]
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shard(key).read().unwrap().contains_key(key)
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write().unwrap().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    // Clone all entries, each shard is read at a different moment
+    pub fn entries(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+    {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.write().unwrap().clear();
+        }
+    }
+}
+
+impl<K: Eq + Hash, V: Clone> Default for DispatchTable<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DispatchTable;
+    use std::sync::Arc;
+
+    #[test]
+    fn dispatch_table_test() {
+        let table = Arc::new(DispatchTable::<u64, u64>::new());
+        let writers = (0..4)
+            .map(|i| {
+                let table = Arc::clone(&table);
+                std::thread::spawn(move || {
+                    for key in (i * 1000)..(i + 1) * 1000 {
+                        table.insert(key, key * 2);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(table.get(&1234), Some(2468));
+        assert_eq!(table.remove(&1234), Some(2468));
+        assert!(!table.contains_key(&1234));
+        assert!(table.contains_key(&3999));
+        assert_eq!(table.entries().len(), 3999);
+        table.clear();
+        assert_eq!(table.get(&3999), None);
+    }
+}
@@ -1,4 +1,10 @@
 pub mod components;
 pub mod config;
+pub mod dispatch_table;
 pub mod models;
+pub mod protocol;
+#[cfg(feature = "sim")]
+pub mod simulator;
+pub mod stack;
+pub mod transport;
 pub mod utils;
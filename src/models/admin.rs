@@ -0,0 +1,167 @@
+use crate::models::datagram::HomaDatagramType;
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde::Serialize;
+use std::net::Ipv4Addr;
+
+// Newline-delimited JSON command written to the admin socket,
+// tagged by a "command" field
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum HomaAdminCommand {
+    StartCapture(HomaCaptureOptions),
+    StopCapture,
+    StartFaults(HomaFaultOptions),
+    StopFaults,
+    ApplicationStats,
+    TransportStats,
+}
+
+// Packets sent or received are captured if they match all the given
+// filters, the capture stops once either bound is reached
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HomaCaptureOptions {
+    pub path: String,
+    pub peer: Option<Ipv4Addr>,
+    pub application_id: Option<u32>,
+    pub message_id: Option<u64>,
+    // Max size of the capture file in bytes
+    pub max_bytes: Option<u64>,
+    // Max duration of the capture in seconds
+    pub max_duration: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HomaCaptureSummary {
+    pub path: String,
+    pub packets: u64,
+    pub bytes: u64,
+    // Packets not captured as the writer fell behind
+    pub dropped: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum HomaFaultDatagramType {
+    Data,
+    Grant,
+    Resend,
+    Busy,
+    Overloaded,
+}
+
+impl HomaFaultDatagramType {
+    pub fn matches(&self, datagram_type: &HomaDatagramType) -> bool {
+        matches!(
+            (self, datagram_type),
+            (Self::Data, HomaDatagramType::Data)
+                | (Self::Grant, HomaDatagramType::Grant)
+                | (Self::Resend, HomaDatagramType::Resend)
+                | (Self::Busy, HomaDatagramType::Busy)
+                | (Self::Overloaded, HomaDatagramType::Overloaded)
+        )
+    }
+}
+
+// Outgoing datagrams matching the filters are dropped, duplicated, reordered,
+// delayed or corrupted with the given probabilities, an empty list of
+// datagram types matches all types
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct HomaFaultOptions {
+    pub drop: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    pub delay: f64,
+    // Delay of delayed datagrams in milliseconds
+    pub delay_ms: u64,
+    pub corrupt: f64,
+    pub datagram_types: Vec<HomaFaultDatagramType>,
+    pub peer: Option<Ipv4Addr>,
+}
+
+impl HomaFaultOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.probabilities().iter().any(|p| *p > 0.0)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.probabilities().iter().all(|p| (0.0..=1.0).contains(p)) {
+            Ok(())
+        } else {
+            Err("fault probabilities must be between 0 and 1".to_string())
+        }
+    }
+
+    fn probabilities(&self) -> [f64; 5] {
+        [
+            self.drop,
+            self.duplicate,
+            self.reorder,
+            self.delay,
+            self.corrupt,
+        ]
+    }
+}
+
+// Number of datagrams affected by each fault
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HomaFaultSummary {
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub delayed: u64,
+    pub corrupted: u64,
+    // Delayed or reordered datagrams dropped as the delay queue was full
+    pub overflowed: u64,
+}
+
+// Counters of a registered application
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HomaApplicationSummary {
+    pub application_id: u32,
+    // Datagrams dropped because the application could not keep up
+    pub dropped_datagrams: u64,
+}
+
+// Counters of the packets sent through the transport
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HomaTransportSummary {
+    // Packets the transport failed to send
+    pub failed_packets: u64,
+    // Packets dropped as homad is not bound to their source address
+    pub unroutable_packets: u64,
+    // Received packets dropped as the receive path could not keep up
+    pub dropped_packets: u64,
+}
+
+// Newline-delimited JSON response to an admin command
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HomaAdminResponse {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capture: Option<HomaCaptureSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub faults: Option<HomaFaultSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub applications: Option<Vec<HomaApplicationSummary>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transport: Option<HomaTransportSummary>,
+}
+
+impl HomaAdminResponse {
+    pub fn from_result(result: Result<Self, String>) -> Self {
+        match result {
+            Ok(response) => Self {
+                ok: true,
+                ..response
+            },
+            Err(error) => Self {
+                error: Some(error),
+                ..Default::default()
+            },
+        }
+    }
+}
@@ -1,14 +1,16 @@
 use crate::config::CONST;
+use crate::models::workload::HomaWorkload;
 use bincode::serialize;
 use crc32fast::Hasher;
 use derive_builder::Builder;
+use pnet::packet::ip::IpNextHeaderProtocol;
+use pnet::packet::ipv4::checksum;
 use pnet::packet::ipv4::MutableIpv4Packet;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_repr::Deserialize_repr;
 use serde_repr::Serialize_repr;
 use std::net::Ipv4Addr;
-use std::u64;
 
 #[derive(Serialize_repr, Deserialize_repr, Debug, Default, Clone)]
 #[repr(u8)]
@@ -18,6 +20,9 @@ pub enum HomaDatagramType {
     Grant,
     Resend,
     Busy,
+    // Sent in place of a data datagram dropped because the application
+    // is overloaded, unlike busy datagrams the sender resends soon
+    Overloaded,
 }
 
 #[derive(Serialize, Deserialize, Debug, Builder, Default, Clone)]
@@ -28,9 +33,14 @@ pub struct HomaDatagram {
     pub source_id: u32,
     pub destination_id: u32,
     pub sequence_number: u32,
-    pub workload: [u64; CONST::UNSCHEDULED_PRIORITY_PARTITIONS],
+    pub workload: HomaWorkload,
     pub priority: u8,
     pub message_length: u64,
+    // Bytes of the message the sender may send, carried by grants,
+    // or sends unscheduled, carried by data datagrams
+    pub offset: u64,
+    // Half-open ranges of missing sequence numbers, carried by resends
+    pub ranges: Vec<(u32, u32)>,
     pub payload: Vec<u8>,
     pub checksum: u32,
 }
@@ -50,8 +60,11 @@ impl HomaDatagram {
         packet.set_total_length(20 + payload.len() as u16);
         packet.set_ttl(64);
         packet.set_dscp(priority);
+        packet.set_next_level_protocol(IpNextHeaderProtocol(CONST::HOMA_PROTOCOL));
         packet.set_source(source_address);
         packet.set_destination(destination_address);
+        let header_checksum = checksum(&packet.to_immutable());
+        packet.set_checksum(header_checksum);
         buffer[20..].copy_from_slice(&payload);
         buffer
     }
@@ -0,0 +1,328 @@
+use crate::config::CONFIG;
+use crate::models::message::HomaFileContent;
+use crate::models::message::HomaMessage;
+use crate::models::message::HomaMessageBuilder;
+use crate::models::message::HomaMessageMetadata;
+use async_std::io::BufRead;
+use async_std::io::BufReadExt;
+use async_std::io::ReadExt;
+use async_std::os::unix::net::UnixStream;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs::File;
+
+const FRAME_LENGTH_MASK: u64 = (1 << 56) - 1;
+
+// Type of a bincode frame, carried in the most significant byte of the
+// little-endian length prefix so that message frames are a plain length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HomaFrameType {
+    Message = 0,
+    FdMessage = 1,
+    FdSink = 2,
+    Batch = 3,
+    Chunk = 4,
+    Ack = 5,
+    SendFailure = 6,
+}
+
+impl TryFrom<u8> for HomaFrameType {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use HomaFrameType::*;
+        match value {
+            0 => Ok(Message),
+            1 => Ok(FdMessage),
+            2 => Ok(FdSink),
+            3 => Ok(Batch),
+            4 => Ok(Chunk),
+            5 => Ok(Ack),
+            6 => Ok(SendFailure),
+            _ => Err(format!("unknown frame type {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HomaFrameHeader {
+    pub frame_type: HomaFrameType,
+    pub length: u64,
+}
+
+impl HomaFrameHeader {
+    pub fn new(frame_type: HomaFrameType, length: u64) -> Self {
+        Self { frame_type, length }
+    }
+
+    pub fn from_bytes(bytes: [u8; 8]) -> Result<Self, String> {
+        let header = u64::from_le_bytes(bytes);
+        let frame_type = HomaFrameType::try_from((header >> 56) as u8)?;
+        Ok(Self::new(frame_type, header & FRAME_LENGTH_MASK))
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        let header = ((self.frame_type as u64) << 56) | (self.length & FRAME_LENGTH_MASK);
+        header.to_le_bytes()
+    }
+
+    pub fn check_length(&self) -> Result<(), String> {
+        if self.length > CONFIG.MESSAGE_MAX_LENGTH {
+            return Err("message too large".to_string());
+        }
+        Ok(())
+    }
+
+    // Read a frame header and its body from the stream
+    pub async fn read_frame(stream: &mut UnixStream) -> Result<(Self, Vec<u8>), String> {
+        let mut header_bytes = [0u8; 8];
+        stream
+            .read_exact(&mut header_bytes)
+            .await
+            .map_err(|e| e.to_string())?;
+        let header = Self::from_bytes(header_bytes)?;
+        header.check_length()?;
+
+        let mut body = vec![0; header.length as usize];
+        stream
+            .read_exact(&mut body)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok((header, body))
+    }
+}
+
+// Body of an FdMessage frame, the content is held in the region
+// of the file descriptor passed alongside the frame header
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HomaFdMessage {
+    pub source_address: [u8; 4],
+    pub destination_address: [u8; 4],
+    pub source_id: u32,
+    pub destination_id: u32,
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl HomaFdMessage {
+    // Describe a delivered message whose content was written at offset
+    pub fn from_message(message: &HomaMessage, offset: u64) -> Self {
+        Self {
+            source_address: message.source_address,
+            destination_address: message.destination_address,
+            source_id: message.source_id,
+            destination_id: message.destination_id,
+            offset,
+            length: message.content_length(),
+        }
+    }
+
+    // Create a message to be sent from the region of the file
+    pub fn into_message(self, file: File) -> Result<HomaMessage, String> {
+        if self.length > CONFIG.MESSAGE_MAX_LENGTH {
+            return Err("message too large".to_string());
+        }
+        let file_content = HomaFileContent::new(file, self.offset, self.length)?;
+        HomaMessageBuilder::default()
+            .source_address(self.source_address)
+            .destination_address(self.destination_address)
+            .source_id(self.source_id)
+            .destination_id(self.destination_id)
+            .file_content(Some(file_content))
+            .build()
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Body of a SendFailure frame, written to the application when a message
+// could not be sent, such as when reading the file of an FdMessage frame
+// failed or its source address is not bound, the message is then abandoned
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HomaSendFailure {
+    pub fd_message: HomaFdMessage,
+    pub error: String,
+}
+
+impl HomaSendFailure {
+    // Describe the message by the region of its file read so far,
+    // messages without a file by their whole content
+    pub fn from_message(message: &HomaMessage, error: String) -> Self {
+        let offset = match &message.file_content {
+            Some(file_content) => file_content.offset(),
+            None => 0,
+        };
+        Self {
+            fd_message: HomaFdMessage::from_message(message, offset),
+            error,
+        }
+    }
+}
+
+// Body of a Chunk frame, part of a large delivered message written
+// interleaved with other deliveries, chunks of a message are written
+// in order and the message is complete once offset + content length
+// reaches the total length
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HomaChunk {
+    pub message_id: u64,
+    pub source_address: [u8; 4],
+    pub destination_address: [u8; 4],
+    pub source_id: u32,
+    pub destination_id: u32,
+    pub offset: u64,
+    pub total_length: u64,
+    pub content: Vec<u8>,
+}
+
+impl HomaChunk {
+    // Take the content from offset up to length bytes from the message
+    pub fn from_message(message: &HomaMessage, offset: usize, length: usize) -> Self {
+        let end = usize::min(offset + length, message.content.len());
+        Self {
+            message_id: message.id,
+            source_address: message.source_address,
+            destination_address: message.destination_address,
+            source_id: message.source_id,
+            destination_id: message.destination_id,
+            offset: offset as u64,
+            total_length: message.content.len() as u64,
+            content: message.content[offset..end].to_vec(),
+        }
+    }
+
+    pub fn is_last(&self) -> bool {
+        self.offset + self.content.len() as u64 == self.total_length
+    }
+}
+
+// Body of an FdSink frame, the next delivered message is written
+// at offset into the file descriptor passed alongside the frame header
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HomaFdSink {
+    pub offset: u64,
+}
+
+// Newline-delimited JSON record exchanged with applications that
+// registered with REGISTRATION_FLAGS::JSON_LINES, tagged by a "type" field,
+// records of unknown types are read as Unknown
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HomaJsonFrame {
+    Message(HomaMessage),
+    Metadata(HomaMessageMetadata),
+    Batch {
+        messages: Vec<HomaMessage>,
+    },
+    Ack {
+        message_id: u64,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+// Error reading a JSON record, a closed stream ends the session while
+// an invalid record is skipped
+#[derive(Debug)]
+pub enum HomaJsonFrameError {
+    Closed(String),
+    Invalid(String),
+}
+
+impl HomaJsonFrame {
+    // Read a single line and parse it, the line is bounded by the
+    // base64 encoded size of the max message length
+    pub async fn from_buf_reader<R: BufRead + Unpin>(
+        reader: &mut R,
+    ) -> Result<Self, HomaJsonFrameError> {
+        use HomaJsonFrameError::*;
+        let limit = CONFIG.MESSAGE_MAX_LENGTH / 3 * 4 + 4096;
+        let mut line = String::new();
+
+        let size = reader
+            .by_ref()
+            .take(limit)
+            .read_line(&mut line)
+            .await
+            .map_err(|e| Closed(e.to_string()))?;
+
+        if size == 0 {
+            return Err(Closed("stream closed".to_string()));
+        }
+
+        if !line.ends_with('\n') {
+            return Err(Closed("line too long".to_string()));
+        }
+
+        serde_json::from_str(&line).map_err(|e| Invalid(e.to_string()))
+    }
+
+    // Serialize to a single newline terminated line
+    pub fn to_line(&self) -> Result<Vec<u8>, String> {
+        let mut line = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+// JSON record written to applications, serialized as the HomaJsonFrame of
+// the same type but borrowing its content, so that the delivered messages
+// are kept to be queued again if the write fails
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HomaJsonRecord<'a> {
+    Message(&'a HomaMessage),
+    Metadata(&'a HomaMessageMetadata),
+    Batch { messages: &'a [HomaMessage] },
+}
+
+impl HomaJsonRecord<'_> {
+    // Serialize to a single newline terminated line
+    pub fn to_line(&self) -> Result<Vec<u8>, String> {
+        let mut line = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HomaJsonFrame;
+    use super::HomaJsonFrameError;
+    use crate::models::message::HomaMessage;
+
+    #[test]
+    fn json_frame_test() {
+        let message = HomaMessage {
+            content: b"homa".to_vec(),
+            ..Default::default()
+        };
+        let line = HomaJsonFrame::Message(message).to_line().unwrap();
+        assert!(String::from_utf8_lossy(&line).contains("\"content\":\"aG9tYQ==\""));
+
+        let frame = serde_json::from_slice::<HomaJsonFrame>(&line).unwrap();
+        match frame {
+            HomaJsonFrame::Message(message) => assert_eq!(message.content, b"homa"),
+            _ => panic!("expected message frame"),
+        }
+
+        let frame = serde_json::from_str::<HomaJsonFrame>(r#"{"type":"ping"}"#).unwrap();
+        assert!(matches!(frame, HomaJsonFrame::Unknown));
+        assert!(serde_json::from_str::<HomaJsonFrame>(r#"{"type":"ack"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn json_frame_reader_test() {
+        let mut reader = "{\"type\":\"ack\",\"message_id\":7}\nnot json\n".as_bytes();
+        let frame = HomaJsonFrame::from_buf_reader(&mut reader).await.unwrap();
+        assert!(matches!(frame, HomaJsonFrame::Ack { message_id: 7 }));
+        assert!(matches!(
+            HomaJsonFrame::from_buf_reader(&mut reader).await,
+            Err(HomaJsonFrameError::Invalid(_))
+        ));
+        assert!(matches!(
+            HomaJsonFrame::from_buf_reader(&mut reader).await,
+            Err(HomaJsonFrameError::Closed(_))
+        ));
+    }
+}
@@ -2,16 +2,19 @@ use crate::config::CONFIG;
 use crate::models::datagram;
 use crate::models::datagram::HomaDatagram;
 use crate::models::datagram::HomaDatagramBuilder;
-use async_std::io::ReadExt;
-use async_std::os::unix::net::UnixStream;
-use bincode::deserialize;
 use datagram::HomaDatagramType;
 use derive_builder::Builder;
 use serde::Deserialize;
 use serde::Serialize;
+use std::borrow::Cow;
 use std::cmp::min;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
 
-#[derive(Serialize, Deserialize, Debug, Builder, Default)]
+#[derive(Serialize, Deserialize, Debug, Builder, Default, Clone)]
 #[builder(default)]
 pub struct HomaMessage {
     #[serde(skip)]
@@ -20,55 +23,190 @@ pub struct HomaMessage {
     pub destination_address: [u8; 4],
     pub source_id: u32,
     pub destination_id: u32,
+    #[serde(with = "content_encoding")]
     pub content: Vec<u8>,
+    #[serde(skip)]
+    pub metadata: Option<HomaMessageMetadata>,
+    // Content passed as a file descriptor, read while splitting
+    // into datagrams instead of being held in memory
+    #[serde(skip)]
+    pub file_content: Option<HomaFileContent>,
 }
 
-impl HomaMessage {
-    pub async fn from_unix_stream(stream: &mut UnixStream) -> Result<Option<Self>, String> {
-        let mut size_buffer = [0u8; 8];
+// Region of a file passed by the application over the unix socket
+#[derive(Debug, Clone)]
+pub struct HomaFileContent {
+    file: Arc<File>,
+    offset: u64,
+    length: u64,
+}
+
+impl HomaFileContent {
+    // Check the region lies within the file before accepting it
+    pub fn new(file: File, offset: u64, length: u64) -> Result<Self, String> {
+        let file_length = file.metadata().map_err(|e| e.to_string())?.len();
+        match offset.checked_add(length) {
+            Some(end) if end <= file_length => Ok(Self {
+                file: Arc::new(file),
+                offset,
+                length,
+            }),
+            _ => Err("file region out of bounds".to_string()),
+        }
+    }
+
+    // Offset of the region in the file
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // Read the bytes of the region starting at start
+    fn read(&self, start: u64, length: usize) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0; length];
+        self.file.read_exact_at(&mut buffer, self.offset + start)?;
+        Ok(buffer)
+    }
+}
+
+// File passed by the application to receive the content of the next
+// delivered message at the given offset
+#[derive(Debug)]
+pub struct HomaFileSink {
+    pub file: File,
+    pub offset: u64,
+}
+
+impl HomaFileSink {
+    pub fn write(&self, content: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(content, self.offset)
+    }
+}
+
+// Receive-side statistics delivered alongside a message to applications
+// that registered with REGISTRATION_FLAGS::RECEIVE_METADATA,
+// timestamps are in microseconds since the unix epoch
+#[derive(Serialize, Deserialize, Debug, Builder, Default, Clone)]
+#[builder(default)]
+pub struct HomaMessageMetadata {
+    pub message_id: u64,
+    pub first_datagram_time: u64,
+    pub completion_time: u64,
+    pub resends: u32,
+    pub grants: u32,
+    pub unscheduled_priority: u8,
+    pub scheduled_priorities: Vec<u8>,
+}
 
-        if let Err(e) = stream.read_exact(&mut size_buffer).await {
-            return Err(e.to_string());
+// Content is written as raw bytes in binary formats and
+// as a base64 string in human readable formats such as JSON
+mod content_encoding {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::Error;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serialize;
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(content: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return serializer.serialize_str(&STANDARD.encode(content));
         }
+        content.serialize(serializer)
+    }
 
-        let size = u64::from_le_bytes(size_buffer);
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            return STANDARD.decode(encoded).map_err(D::Error::custom);
+        }
+        Vec::<u8>::deserialize(deserializer)
+    }
+}
 
-        if size > CONFIG.MESSAGE_MAX_LENGTH {
-            return Err("message too large".to_string());
+impl HomaMessage {
+    // Length of the content, whether held in memory or in a file
+    pub fn content_length(&self) -> u64 {
+        match &self.file_content {
+            Some(file_content) => file_content.length,
+            None => self.content.len() as u64,
         }
+    }
 
-        let mut buffer = vec![0; size as usize];
-        stream
-            .read_exact(&mut buffer)
-            .await
-            .map_err(|_| "FROM UNIX STREAM FAILED MESSAGE")?;
-        let result = deserialize(&buffer).ok();
-        Ok(result)
+    pub fn datagram_count(&self) -> usize {
+        (self.content_length() as usize).div_ceil(CONFIG.DATAGRAM_PAYLOAD_LENGTH as usize)
     }
 
-    pub fn split(&self) -> Vec<HomaDatagram> {
-        let mut datagrams = Vec::<HomaDatagram>::new();
-        let num_datagrams = (self.content.len() + (CONFIG.DATAGRAM_PAYLOAD_LENGTH as usize) - 1)
-            / CONFIG.DATAGRAM_PAYLOAD_LENGTH as usize;
-        for i in 0..num_datagrams {
-            let datagram_length = min(
-                self.content.len() - i * CONFIG.DATAGRAM_PAYLOAD_LENGTH as usize,
-                CONFIG.DATAGRAM_PAYLOAD_LENGTH as usize,
-            );
-            let start = i * CONFIG.DATAGRAM_PAYLOAD_LENGTH as usize;
-            let end = start + datagram_length;
-            let datagram = HomaDatagramBuilder::default()
-                .datagram_type(HomaDatagramType::Data)
-                .message_id(self.id)
-                .source_id(self.source_id)
-                .destination_id(self.destination_id)
-                .sequence_number(i as u32)
-                .message_length(self.content.len() as u64)
-                .payload(self.content[start..end].to_vec())
-                .build()
-                .unwrap();
-            datagrams.push(datagram);
+    // Build the data datagrams of the range, reading their payloads from the
+    // file in a single read on a blocking thread if the content was passed
+    // as one, datagrams past the end of the message are left out
+    pub async fn read_datagrams(&self, range: Range<usize>) -> io::Result<Vec<HomaDatagram>> {
+        let datagram_count = self.datagram_count();
+        let range = range.start.min(datagram_count)..range.end.min(datagram_count);
+        if range.is_empty() {
+            return Ok(Vec::new());
         }
-        datagrams
+        let content_length = self.content_length() as usize;
+        let payload_length = CONFIG.DATAGRAM_PAYLOAD_LENGTH as usize;
+        let start = range.start * payload_length;
+        let end = min(range.end * payload_length, content_length);
+        let content = match &self.file_content {
+            Some(file_content) => {
+                let file_content = file_content.clone();
+                let content = tokio::task::spawn_blocking(move || {
+                    file_content.read(start as u64, end - start)
+                })
+                .await
+                .map_err(io::Error::other)??;
+                Cow::Owned(content)
+            }
+            None => Cow::Borrowed(&self.content[start..end]),
+        };
+        let datagrams = content
+            .chunks(payload_length)
+            .zip(range)
+            .map(|(payload, i)| {
+                HomaDatagramBuilder::default()
+                    .datagram_type(HomaDatagramType::Data)
+                    .message_id(self.id)
+                    .source_id(self.source_id)
+                    .destination_id(self.destination_id)
+                    .sequence_number(i as u32)
+                    .message_length(content_length as u64)
+                    .payload(payload.to_vec())
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        Ok(datagrams)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HomaFileContent;
+    use super::HomaMessageBuilder;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn read_datagrams_test() {
+        let path = std::env::temp_dir().join(format!("homa-{}", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"file content").unwrap();
+        let file_content = HomaFileContent::new(File::open(&path).unwrap(), 5, 7).unwrap();
+        let message = HomaMessageBuilder::default()
+            .file_content(Some(file_content))
+            .build()
+            .unwrap();
+        let datagrams = message.read_datagrams(0..2).await.unwrap();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0].payload, b"content");
+        assert!(message.read_datagrams(1..2).await.unwrap().is_empty());
+
+        // Reads of a file truncated after it was passed fail
+        file.set_len(6).unwrap();
+        assert!(message.read_datagrams(0..1).await.is_err());
+        std::fs::remove_file(path).unwrap();
     }
 }
@@ -1,3 +1,6 @@
+pub mod admin;
 pub mod datagram;
+pub mod frame;
 pub mod message;
 pub mod registration;
+pub mod workload;
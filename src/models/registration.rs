@@ -1,22 +1,150 @@
 use bincode::deserialize;
+use bincode::serialize;
+use bincode::serialized_size;
 use serde::Deserialize;
 use serde::Serialize;
 use std::io::Read;
+use std::io::Write;
 use std::os::unix::net::UnixStream;
 
-#[derive(Serialize, Deserialize, Debug)]
+// Options an application can enable for its connection
+// by setting the corresponding bits in the registration flags
+#[allow(non_snake_case)]
+pub mod REGISTRATION_FLAGS {
+    // Deliver HomaMessageMetadata alongside every received message
+    pub const RECEIVE_METADATA: u32 = 1 << 0;
+    // Exchange newline-delimited JSON frames instead of bincode frames
+    pub const JSON_LINES: u32 = 1 << 1;
+    // Accept file descriptors passed with FdMessage and FdSink frames
+    pub const FD_PASSING: u32 = 1 << 2;
+    // Deliver messages queued at the same time in Batch frames
+    pub const BATCH: u32 = 1 << 3;
+    // Deliver large messages in Chunk frames interleaved with shorter messages
+    pub const CHUNKED: u32 = 1 << 4;
+    // Require Ack frames for delivered messages before acknowledging them to
+    // the sender, implies RECEIVE_METADATA to carry the message id
+    pub const ACK: u32 = 1 << 5;
+    // Exchange a resume token after registration, so that the session
+    // survives the stream breaking for the grace period
+    pub const RESUME: u32 = 1 << 6;
+}
+
+// Prefix of a registration message carrying flags, "HOMA" in little-endian,
+// any other first 4 bytes are the id of an application registering without
+// flags
+pub const REGISTRATION_MAGIC: u32 = 0x414d_4f48;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct HomaRegistrationMessage {
     pub application_id: u32,
+    pub flags: u32,
 }
 
 impl HomaRegistrationMessage {
+    // Read the application id of a registration without flags,
+    // or the magic followed by the registration message, the magic
+    // is rejected as an application id
     pub fn from_unix_stream(stream: &mut UnixStream) -> Result<Self, String> {
-        let mut buffer = [0u8; 4];
+        let mut prefix = [0u8; 4];
+        stream
+            .read_exact(&mut prefix)
+            .map_err(|_| "HomaRegisrationMessage not read")?;
+        let application_id = u32::from_le_bytes(prefix);
+        if application_id != REGISTRATION_MAGIC {
+            return Ok(Self {
+                application_id,
+                flags: 0,
+            });
+        }
+
+        let size = serialized_size(&Self::default())
+            .map_err(|_| "HomaRegistrationMessage size not computed")?;
+        let mut buffer = vec![0u8; size as usize];
 
         stream
             .read_exact(&mut buffer)
             .map_err(|_| "HomaRegisrationMessage not read")?;
 
-        deserialize(&buffer).map_err(|_| "HomaRegistrationMessage not deserialized".to_string())
+        let registration_message: Self = deserialize(&buffer)
+            .map_err(|_| "HomaRegistrationMessage not deserialized".to_string())?;
+        if registration_message.application_id == REGISTRATION_MAGIC {
+            return Err(format!(
+                "Application id {:#x} is reserved for the registration magic",
+                REGISTRATION_MAGIC
+            ));
+        }
+        Ok(registration_message)
+    }
+
+    // Serialize the registration message preceded by the magic
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        if self.application_id == REGISTRATION_MAGIC {
+            return Err(format!(
+                "Application id {:#x} is reserved for the registration magic",
+                REGISTRATION_MAGIC
+            ));
+        }
+        let mut bytes = REGISTRATION_MAGIC.to_le_bytes().to_vec();
+        let mut message_bytes =
+            serialize(self).map_err(|_| "HomaRegistrationMessage not serialized")?;
+        bytes.append(&mut message_bytes);
+        Ok(bytes)
+    }
+
+    // Read the resume token following the registration message,
+    // a token of zero requests a new session
+    pub fn read_resume_token(stream: &mut UnixStream) -> Result<u64, String> {
+        let mut buffer = [0u8; 8];
+        stream
+            .read_exact(&mut buffer)
+            .map_err(|_| "Resume token not read")?;
+        Ok(u64::from_le_bytes(buffer))
+    }
+
+    // Answer the application with the token to resume its session
+    pub fn write_resume_token(stream: &mut UnixStream, token: u64) -> Result<(), String> {
+        stream
+            .write_all(&token.to_le_bytes())
+            .map_err(|_| "Resume token not written".to_string())
+    }
+
+    // Check whether the application enabled the given registration flag
+    pub fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn receive_metadata(&self) -> bool {
+        self.has_flag(REGISTRATION_FLAGS::RECEIVE_METADATA)
+            || self.has_flag(REGISTRATION_FLAGS::ACK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HomaRegistrationMessage;
+    use super::REGISTRATION_MAGIC;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn reserved_application_id_test() {
+        let (mut stream, mut other) = UnixStream::pair().unwrap();
+        let registration_message = HomaRegistrationMessage {
+            application_id: REGISTRATION_MAGIC,
+            flags: 0,
+        };
+        assert!(registration_message.to_bytes().is_err());
+
+        // The magic followed by a registration message with the magic as id
+        for _ in 0..2 {
+            stream.write_all(&REGISTRATION_MAGIC.to_le_bytes()).unwrap();
+        }
+        stream.write_all(&0u32.to_le_bytes()).unwrap();
+        assert!(HomaRegistrationMessage::from_unix_stream(&mut other).is_err());
+
+        stream.write_all(&7u32.to_le_bytes()).unwrap();
+        let registration_message = HomaRegistrationMessage::from_unix_stream(&mut other).unwrap();
+        assert_eq!(registration_message.application_id, 7);
+        assert_eq!(registration_message.flags, 0);
     }
 }
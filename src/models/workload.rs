@@ -0,0 +1,34 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+
+// Workload a host advertises along its datagrams, the number of priority
+// levels it gives to unscheduled datagrams and the message length cutoffs
+// between them, no levels if the host did not advertise a workload
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct HomaWorkload {
+    pub unscheduled_priority_levels: u8,
+    pub cutoffs: Vec<u64>,
+    // Unscheduled datagrams a sender may send to the host,
+    // reduced while the host is the target of an incast
+    pub unscheduled_datagram_limit: u32,
+    // Microseconds the reduced limit holds after the workload was taken,
+    // the full limit applies afterwards
+    pub limit_lifetime: u32,
+}
+
+impl HomaWorkload {
+    pub fn is_advertised(&self) -> bool {
+        self.unscheduled_priority_levels > 0
+    }
+
+    // The workload sent the elapsed time after it was taken,
+    // the lifetime of the limit is shortened by the elapsed time
+    pub fn aged(&self, elapsed: Duration) -> Self {
+        let elapsed = elapsed.as_micros().min(u32::MAX as u128) as u32;
+        Self {
+            limit_lifetime: self.limit_lifetime.saturating_sub(elapsed),
+            ..self.clone()
+        }
+    }
+}
@@ -0,0 +1,596 @@
+/*
+MessageReceiver state machine
+
+Protocol decisions of receiving a message, free of I/O and timers so that they
+can be tested and embedded. The state machine consumes events, arrived
+datagrams, fired timers, granted priorities and acknowledgements, and emits
+actions for its driver to carry out
+
+The unscheduled datagrams are collected first, as many as the data datagrams
+tell since the sender reduces them during an incast, resends are requested for
+the missing ones whenever the timer fires. Then the scheduled datagrams are granted
+at the priority granted for the remaining bytes of the message, keeping RTT
+bytes granted beyond the received bytes so that the link stays busy. Grants
+carry the offset up to which the sender may send and resends the ranges of
+missing datagrams, which are requested for the granted datagrams whenever the
+timer fires. Once all datagrams
+arrived the message is delivered and the final grant sent, after the
+application acknowledged the message if it has to, answering the sender with
+busy datagrams meanwhile
+
+Timers are set to their base timeout, the driver adds the jitter
+*/
+use crate::config::CONST;
+use crate::models::datagram::HomaDatagram;
+use crate::models::datagram::HomaDatagramType;
+use crate::models::message::HomaMessage;
+use crate::models::message::HomaMessageBuilder;
+use crate::models::message::HomaMessageMetadata;
+use crate::models::message::HomaMessageMetadataBuilder;
+use crate::models::workload::HomaWorkload;
+use crate::protocol::ProtocolConfig;
+use std::net::Ipv4Addr;
+use tokio::time::Duration;
+
+pub enum MessageReceiverEvent {
+    Datagram(HomaDatagram),
+    TimerFired,
+    // Scheduled priority for the remaining bytes of the message
+    PriorityGranted(u8),
+    // Whether the application acknowledged the delivered message
+    Acknowledged(bool),
+}
+
+#[derive(Debug)]
+pub enum MessageReceiverAction {
+    // Send the control datagram to the sender of the message
+    SendDatagram(HomaDatagram),
+    // Register and unregister the message for scheduled priorities
+    RegisterPriority(u64),
+    UnregisterPriority,
+    // Ask for the scheduled priority of the remaining bytes,
+    // answered with a PriorityGranted event
+    RequestPriority(u64),
+    // Fire the timer after the timeout, replacing the running timer,
+    // randomized by the driver if it has jitter
+    SetTimer { timeout: Duration, jitter: bool },
+    // Hand the message to the application, to be answered with an
+    // Acknowledged event if the message has to be acknowledged
+    Deliver(HomaMessage, bool),
+    // The message is complete or failed, no more events are handled
+    Finish,
+}
+
+enum MessageReceiverState {
+    Unscheduled { resends: usize },
+    Scheduled { resends: usize },
+    Acknowledging { busy_datagrams: usize },
+    Finished,
+}
+
+pub struct MessageReceiverCore {
+    config: ProtocolConfig,
+    message_id: u64,
+    source_address: Ipv4Addr,
+    destination_address: Ipv4Addr,
+    source_id: u32,
+    destination_id: u32,
+    priority: u8,
+    local_workload: HomaWorkload,
+    message_length: u64,
+    datagrams: Vec<Option<HomaDatagram>>,
+
+    expected_datagrams: u32,
+    unscheduled_datagrams: u32,
+    // Datagrams below this sequence number are unscheduled or granted
+    granted_datagrams: u32,
+    collected_datagrams: u32,
+    collected_bytes: u64,
+    unscheduled_only: bool,
+    acknowledge: bool,
+    state: MessageReceiverState,
+
+    // Statistics delivered to the application as HomaMessageMetadata
+    first_datagram_time: u64,
+    resends: u32,
+    grants: u32,
+    unscheduled_priority: u8,
+    scheduled_priorities: Vec<u8>,
+}
+
+impl MessageReceiverCore {
+    // Start receiving the message of the first datagram,
+    // which arrived at the first datagram time
+    pub fn new(
+        config: ProtocolConfig,
+        datagram: HomaDatagram,
+        source_address: Ipv4Addr,
+        destination_address: Ipv4Addr,
+        acknowledge: bool,
+        first_datagram_time: u64,
+    ) -> Self {
+        let message_length = datagram.message_length;
+        let expected_datagrams = message_length.div_ceil(config.datagram_payload_length) as u32;
+        // Data datagrams carry the unscheduled bytes of the message
+        // as their offset
+        let unscheduled_datagrams = match datagram.offset {
+            0 => config.unscheduled_datagram_limit as u64,
+            offset => offset.div_ceil(config.datagram_payload_length),
+        }
+        .min(expected_datagrams as u64) as u32;
+        let unscheduled_only = unscheduled_datagrams == expected_datagrams;
+        let mut core = Self {
+            config,
+            message_id: datagram.message_id,
+            source_address,
+            destination_address,
+            source_id: datagram.source_id,
+            destination_id: datagram.destination_id,
+            priority: 0,
+            local_workload: HomaWorkload::default(),
+            message_length,
+            datagrams: vec![None; expected_datagrams as usize],
+
+            expected_datagrams,
+            unscheduled_datagrams,
+            granted_datagrams: unscheduled_datagrams,
+            collected_datagrams: 0,
+            collected_bytes: 0,
+            unscheduled_only,
+            acknowledge,
+            state: MessageReceiverState::Unscheduled { resends: 0 },
+
+            first_datagram_time,
+            resends: 0,
+            grants: 0,
+            unscheduled_priority: datagram.priority,
+            scheduled_priorities: Vec::new(),
+        };
+        core.add_datagram(datagram);
+        core
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, MessageReceiverState::Finished)
+    }
+
+    // Start with the workload of the local host sent along control datagrams
+    pub fn start(&mut self, local_workload: HomaWorkload) -> Vec<MessageReceiverAction> {
+        self.local_workload = local_workload;
+        self.receive_unscheduled_datagrams()
+    }
+
+    pub fn handle(&mut self, event: MessageReceiverEvent) -> Vec<MessageReceiverAction> {
+        use MessageReceiverEvent::*;
+        use MessageReceiverState::*;
+        match (&mut self.state, event) {
+            (Unscheduled { .. }, Datagram(datagram)) => {
+                self.add_datagram(datagram);
+                self.receive_unscheduled_datagrams()
+            }
+            (Unscheduled { resends }, TimerFired) => {
+                if *resends == self.config.resends {
+                    return self.finish();
+                }
+                *resends += 1;
+                let mut actions =
+                    self.request_resend(0, self.unscheduled_datagrams, self.unscheduled_priority);
+                actions.push(Self::timer(self.config.timeout));
+                actions
+            }
+            (Scheduled { .. }, Datagram(datagram)) => {
+                self.add_datagram(datagram);
+                if self.collected_bytes == self.message_length {
+                    let mut actions = vec![MessageReceiverAction::UnregisterPriority];
+                    actions.extend(self.deliver());
+                    return actions;
+                }
+                vec![MessageReceiverAction::RequestPriority(
+                    self.remaining_bytes(),
+                )]
+            }
+            (Scheduled { resends }, TimerFired) => {
+                if *resends == self.config.large_resends {
+                    let mut actions = vec![MessageReceiverAction::UnregisterPriority];
+                    actions.extend(self.finish());
+                    return actions;
+                }
+                *resends += 1;
+                let mut actions = self.request_resend(
+                    self.unscheduled_datagrams,
+                    self.granted_datagrams,
+                    self.priority,
+                );
+                actions.push(MessageReceiverAction::RequestPriority(
+                    self.remaining_bytes(),
+                ));
+                actions
+            }
+            (Scheduled { .. }, PriorityGranted(priority)) => {
+                self.priority = priority;
+                let mut actions = self.grant_window();
+                actions.push(Self::timer(self.config.timeout));
+                actions
+            }
+            (Acknowledging { .. }, Datagram(_)) => {
+                vec![self.busy(), Self::timer(self.config.large_timeout / 2)]
+            }
+            (Acknowledging { busy_datagrams }, TimerFired) => {
+                if *busy_datagrams == self.config.large_resends {
+                    return self.finish();
+                }
+                *busy_datagrams += 1;
+                vec![self.busy(), Self::timer(self.config.large_timeout / 2)]
+            }
+            (Acknowledging { .. }, Acknowledged(true)) => {
+                let mut actions = vec![self.final_grant()];
+                actions.extend(self.finish());
+                actions
+            }
+            (Acknowledging { .. }, Acknowledged(false)) => self.finish(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Add the received datagram to the vector of datagrams if it has not yet been received
+    fn add_datagram(&mut self, datagram: HomaDatagram) {
+        if let Some(datagram_entry) = self.datagrams.get_mut(datagram.sequence_number as usize) {
+            if datagram_entry.is_none() {
+                self.collected_datagrams += 1;
+                self.collected_bytes += datagram.payload.len() as u64;
+                *datagram_entry = Some(datagram);
+            }
+        }
+    }
+
+    fn remaining_bytes(&self) -> u64 {
+        self.message_length - self.collected_bytes
+    }
+
+    // Continue with the scheduled datagrams or deliver the message once all
+    // unscheduled datagrams arrived, wait for them otherwise
+    fn receive_unscheduled_datagrams(&mut self) -> Vec<MessageReceiverAction> {
+        let complete = self.collected_bytes == self.message_length
+            || self.collected_datagrams >= self.unscheduled_datagrams;
+        if !complete {
+            return vec![Self::timer(self.config.timeout)];
+        }
+        if self.unscheduled_only {
+            return self.deliver();
+        }
+        self.state = MessageReceiverState::Scheduled { resends: 0 };
+        vec![
+            MessageReceiverAction::RegisterPriority(self.remaining_bytes()),
+            MessageReceiverAction::RequestPriority(self.remaining_bytes()),
+        ]
+    }
+
+    // Grant the datagrams up to RTT bytes beyond the received bytes
+    // which have not been granted yet
+    fn grant_window(&mut self) -> Vec<MessageReceiverAction> {
+        let window = self
+            .config
+            .rtt_bytes
+            .div_ceil(self.config.datagram_payload_length)
+            .max(1) as u32;
+        let granted_datagrams = self
+            .collected_datagrams
+            .saturating_add(window)
+            .min(self.expected_datagrams);
+        if granted_datagrams <= self.granted_datagrams {
+            return Vec::new();
+        }
+        self.granted_datagrams = granted_datagrams;
+        vec![self.grant()]
+    }
+
+    // Request resends for the datagrams in the range which have not yet been
+    // received, in a single resend carrying the ranges of missing datagrams
+    fn request_resend(&mut self, start: u32, end: u32, priority: u8) -> Vec<MessageReceiverAction> {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for sequence_number in start..end {
+            if self.datagrams[sequence_number as usize].is_some() {
+                continue;
+            }
+            if let Some((_, range_end)) = ranges.last_mut() {
+                if *range_end == sequence_number {
+                    *range_end += 1;
+                    continue;
+                }
+            }
+            if ranges.len() == CONST::RESEND_RANGE_LIMIT {
+                break;
+            }
+            ranges.push((sequence_number, sequence_number + 1));
+        }
+        if ranges.is_empty() {
+            return Vec::new();
+        }
+        self.resends += ranges.iter().map(|(start, end)| end - start).sum::<u32>();
+        let mut resend = self.control_datagram(HomaDatagramType::Resend);
+        resend.priority = priority;
+        resend.ranges = ranges;
+        let _ = resend.checksum();
+        vec![MessageReceiverAction::SendDatagram(resend)]
+    }
+
+    // Deliver the message, then send the final grant unless the
+    // application has to acknowledge the message first
+    fn deliver(&mut self) -> Vec<MessageReceiverAction> {
+        let mut actions = vec![MessageReceiverAction::Deliver(
+            self.build_message(),
+            self.acknowledge,
+        )];
+        if self.acknowledge {
+            self.state = MessageReceiverState::Acknowledging { busy_datagrams: 0 };
+            actions.push(self.busy());
+            actions.push(Self::timer(self.config.large_timeout / 2));
+            return actions;
+        }
+        actions.push(self.final_grant());
+        actions.extend(self.finish());
+        actions
+    }
+
+    fn finish(&mut self) -> Vec<MessageReceiverAction> {
+        self.state = MessageReceiverState::Finished;
+        vec![MessageReceiverAction::Finish]
+    }
+
+    fn timer(timeout: Duration) -> MessageReceiverAction {
+        MessageReceiverAction::SetTimer {
+            timeout,
+            jitter: true,
+        }
+    }
+
+    fn control_datagram(&self, datagram_type: HomaDatagramType) -> HomaDatagram {
+        HomaDatagram {
+            message_id: self.message_id,
+            datagram_type,
+            source_id: self.destination_id,
+            destination_id: self.source_id,
+            workload: self.local_workload.clone(),
+            ..Default::default()
+        }
+    }
+
+    // Grant the granted datagrams, the grant carries the number of
+    // received datagrams which completes the message once all arrived
+    fn grant(&mut self) -> MessageReceiverAction {
+        let mut grant = self.control_datagram(HomaDatagramType::Grant);
+        grant.sequence_number = self.collected_datagrams;
+        grant.offset = (self.granted_datagrams as u64 * self.config.datagram_payload_length)
+            .min(self.message_length);
+        grant.priority = self.priority;
+        let _ = grant.checksum();
+        self.grants += 1;
+        if !self.scheduled_priorities.contains(&self.priority) {
+            self.scheduled_priorities.push(self.priority);
+        }
+        MessageReceiverAction::SendDatagram(grant)
+    }
+
+    fn final_grant(&mut self) -> MessageReceiverAction {
+        self.granted_datagrams = self.expected_datagrams;
+        self.grant()
+    }
+
+    // Inform the sender that the message is complete but not yet acknowledged
+    fn busy(&self) -> MessageReceiverAction {
+        let mut busy = self.control_datagram(HomaDatagramType::Busy);
+        let _ = busy.checksum();
+        MessageReceiverAction::SendDatagram(busy)
+    }
+
+    fn build_message(&self) -> HomaMessage {
+        let content = self
+            .datagrams
+            .iter()
+            .map(|datagram_entry| datagram_entry.to_owned().unwrap())
+            .map(|datagram| datagram.payload.clone())
+            .collect::<Vec<Vec<u8>>>()
+            .concat();
+        HomaMessageBuilder::default()
+            .id(self.message_id)
+            .source_address(self.source_address.octets())
+            .destination_address(self.destination_address.octets())
+            .source_id(self.source_id)
+            .destination_id(self.destination_id)
+            .content(content)
+            .metadata(Some(self.build_metadata()))
+            .build()
+            .unwrap()
+    }
+
+    // The completion time is left for the driver to stamp
+    fn build_metadata(&self) -> HomaMessageMetadata {
+        HomaMessageMetadataBuilder::default()
+            .message_id(self.message_id)
+            .first_datagram_time(self.first_datagram_time)
+            .resends(self.resends)
+            .grants(self.grants)
+            .unscheduled_priority(self.unscheduled_priority)
+            .scheduled_priorities(self.scheduled_priorities.clone())
+            .build()
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageReceiverAction;
+    use super::MessageReceiverCore;
+    use super::MessageReceiverEvent;
+    use crate::config::CONFIG;
+    use crate::models::datagram::HomaDatagram;
+    use crate::models::datagram::HomaDatagramType;
+    use crate::models::workload::HomaWorkload;
+    use crate::protocol::ProtocolConfig;
+    use std::net::Ipv4Addr;
+
+    fn config() -> ProtocolConfig {
+        ProtocolConfig::from_config(&CONFIG)
+    }
+
+    fn data(sequence_number: u32, datagram_count: u32) -> HomaDatagram {
+        let payload_length = CONFIG.DATAGRAM_PAYLOAD_LENGTH as u64;
+        HomaDatagram {
+            datagram_type: HomaDatagramType::Data,
+            message_id: 1,
+            sequence_number,
+            message_length: payload_length * datagram_count as u64,
+            payload: vec![sequence_number as u8; payload_length as usize],
+            ..Default::default()
+        }
+    }
+
+    // Received datagrams and granted bytes of the grants
+    fn grants(actions: &[MessageReceiverAction]) -> Vec<(u32, u64)> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                MessageReceiverAction::SendDatagram(datagram)
+                    if matches!(datagram.datagram_type, HomaDatagramType::Grant) =>
+                {
+                    Some((datagram.sequence_number, datagram.offset))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn resend_ranges(actions: &[MessageReceiverAction]) -> Vec<(u32, u32)> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                MessageReceiverAction::SendDatagram(datagram)
+                    if matches!(datagram.datagram_type, HomaDatagramType::Resend) =>
+                {
+                    Some(datagram.ranges.clone())
+                }
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn message_receiver_test() {
+        let payload_length = CONFIG.DATAGRAM_PAYLOAD_LENGTH as u64;
+        let unscheduled = CONFIG.UNSCHEDULED_DATAGRAM_LIMIT as u32;
+        let datagram_count = unscheduled + 1;
+        let address = Ipv4Addr::new(10, 0, 0, 1);
+        let mut core = MessageReceiverCore::new(
+            config(),
+            data(1, datagram_count),
+            address,
+            address,
+            false,
+            0,
+        );
+        let actions = core.start(HomaWorkload::default());
+        assert!(matches!(
+            actions[..],
+            [MessageReceiverAction::SetTimer { .. }]
+        ));
+
+        let actions = core.handle(MessageReceiverEvent::TimerFired);
+        assert_eq!(resend_ranges(&actions), vec![(0, 1), (2, unscheduled)]);
+
+        let mut actions = Vec::new();
+        for i in (0..unscheduled).filter(|i| *i != 1) {
+            actions = core.handle(MessageReceiverEvent::Datagram(data(i, datagram_count)));
+        }
+        assert!(matches!(
+            actions.last(),
+            Some(MessageReceiverAction::RequestPriority(bytes)) if *bytes == payload_length
+        ));
+        let actions = core.handle(MessageReceiverEvent::PriorityGranted(2));
+        assert_eq!(
+            grants(&actions),
+            vec![(unscheduled, datagram_count as u64 * payload_length)]
+        );
+
+        let actions = core.handle(MessageReceiverEvent::Datagram(data(
+            unscheduled,
+            datagram_count,
+        )));
+        assert_eq!(
+            grants(&actions),
+            vec![(datagram_count, datagram_count as u64 * payload_length)]
+        );
+        let Some(MessageReceiverAction::Deliver(message, false)) = actions.get(1) else {
+            panic!("Message not delivered");
+        };
+        assert_eq!(message.content[CONFIG.DATAGRAM_PAYLOAD_LENGTH as usize], 1);
+        assert!(matches!(
+            actions.last(),
+            Some(MessageReceiverAction::Finish)
+        ));
+        assert!(core.is_finished());
+
+        // RTT bytes are granted beyond the received bytes
+        let window = CONFIG.RTT_BYTES.div_ceil(payload_length) as u32;
+        let datagram_count = unscheduled + window + 2;
+        let mut core = MessageReceiverCore::new(
+            config(),
+            data(0, datagram_count),
+            address,
+            address,
+            false,
+            0,
+        );
+        core.start(HomaWorkload::default());
+        for i in 1..unscheduled {
+            core.handle(MessageReceiverEvent::Datagram(data(i, datagram_count)));
+        }
+        let actions = core.handle(MessageReceiverEvent::PriorityGranted(2));
+        assert_eq!(
+            grants(&actions),
+            vec![(unscheduled, (unscheduled + window) as u64 * payload_length)]
+        );
+        core.handle(MessageReceiverEvent::Datagram(data(
+            unscheduled + 1,
+            datagram_count,
+        )));
+        let actions = core.handle(MessageReceiverEvent::PriorityGranted(2));
+        assert_eq!(
+            grants(&actions),
+            vec![(
+                unscheduled + 1,
+                (unscheduled + window + 1) as u64 * payload_length
+            )]
+        );
+        let actions = core.handle(MessageReceiverEvent::TimerFired);
+        assert_eq!(
+            resend_ranges(&actions),
+            vec![
+                (unscheduled, unscheduled + 1),
+                (unscheduled + 2, unscheduled + window + 1)
+            ]
+        );
+
+        // Senders reduce the unscheduled datagrams during an incast
+        let incast_data = |i| HomaDatagram {
+            offset: 2 * payload_length,
+            ..data(i, datagram_count)
+        };
+        let mut core =
+            MessageReceiverCore::new(config(), incast_data(0), address, address, false, 0);
+        core.start(HomaWorkload::default());
+        let actions = core.handle(MessageReceiverEvent::Datagram(incast_data(1)));
+        assert!(matches!(
+            actions[..],
+            [
+                MessageReceiverAction::RegisterPriority(_),
+                MessageReceiverAction::RequestPriority(_)
+            ]
+        ));
+        let actions = core.handle(MessageReceiverEvent::PriorityGranted(2));
+        assert_eq!(
+            grants(&actions),
+            vec![(2, (2 + window) as u64 * payload_length)]
+        );
+    }
+}
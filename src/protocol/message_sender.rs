@@ -0,0 +1,363 @@
+/*
+MessageSender state machine
+
+Protocol decisions of sending a message, free of I/O and timers so that they
+can be tested and embedded. The state machine consumes events, arrived
+datagrams and fired timers, and emits actions for its driver to carry out
+
+The unscheduled datagrams are sent first and sent again whenever the timer
+fires before a grant or resend arrives, until the resend limit is reached.
+Then the datagrams up to the offset of each grant and the ranges of each resend
+are sent, every datagram is sent once for the grants so that a lost grant is
+covered by the next one, until the final grant arrives or no datagram arrives
+within the large timeout
+
+Receivers which are the target of an incast reduce the unscheduled datagrams
+their senders may send, the remaining datagrams are sent once granted
+
+A busy datagram, sent by a receiver waiting for an acknowledgement, only
+postpones the timer by the large timeout, the unscheduled datagrams are still
+resent if nothing else arrives. An overloaded datagram, sent by a receiver
+dropping datagrams of an overloaded application, resends the unscheduled
+datagrams after the timeout instead, they count towards the resend limit
+
+Timers are set to their base timeout, the driver adds the jitter of the
+timers which have it
+*/
+use crate::config::CONST;
+use crate::models::datagram::HomaDatagram;
+use crate::models::datagram::HomaDatagramType;
+use crate::models::workload::HomaWorkload;
+use crate::protocol::ProtocolConfig;
+use tokio::time::Duration;
+
+pub enum MessageSenderEvent {
+    Datagram(HomaDatagram),
+    TimerFired,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MessageSenderAction {
+    // Send the datagram of the message with the sequence number at the
+    // priority, unscheduled datagrams are tagged with their priority
+    SendDatagram {
+        sequence_number: u32,
+        priority: u8,
+        unscheduled: bool,
+    },
+    // Record the workload the receiving host sent along a datagram
+    PutRemoteWorkload(HomaWorkload),
+    // Fire the timer after the timeout, replacing the running timer,
+    // randomized by the driver if it has jitter
+    SetTimer {
+        timeout: Duration,
+        jitter: bool,
+    },
+    // The message is transmitted or failed, no more events are handled
+    Finish,
+}
+
+enum MessageSenderState {
+    Unscheduled { resends: usize },
+    Requested,
+    Finished,
+}
+
+pub struct MessageSenderCore {
+    config: ProtocolConfig,
+    datagram_count: u32,
+    unscheduled_priority: u8,
+    unscheduled_datagrams: u32,
+    // Datagrams below this sequence number are unscheduled or were sent
+    sent_datagrams: u32,
+    state: MessageSenderState,
+}
+
+impl MessageSenderCore {
+    // Send the message of datagram count datagrams, at most the unscheduled
+    // datagram limit of the receiver of them before it grants any
+    pub fn new(
+        config: ProtocolConfig,
+        datagram_count: u32,
+        unscheduled_priority: u8,
+        unscheduled_datagram_limit: u32,
+    ) -> Self {
+        let unscheduled_datagrams = datagram_count
+            .min(config.unscheduled_datagram_limit)
+            .min(unscheduled_datagram_limit);
+        Self {
+            config,
+            datagram_count,
+            unscheduled_priority,
+            unscheduled_datagrams,
+            sent_datagrams: unscheduled_datagrams,
+            state: MessageSenderState::Unscheduled { resends: 0 },
+        }
+    }
+
+    // Number of datagrams sent unscheduled, which data datagrams
+    // tell the receiver
+    pub fn unscheduled_datagrams(&self) -> u32 {
+        self.unscheduled_datagrams
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, MessageSenderState::Finished)
+    }
+
+    // Send the unscheduled datagrams
+    pub fn start(&mut self) -> Vec<MessageSenderAction> {
+        let mut actions = self.send_unscheduled_datagrams();
+        actions.push(MessageSenderAction::SetTimer {
+            timeout: self.config.timeout,
+            jitter: true,
+        });
+        actions
+    }
+
+    pub fn handle(&mut self, event: MessageSenderEvent) -> Vec<MessageSenderAction> {
+        use MessageSenderEvent::*;
+        use MessageSenderState::*;
+        match (&mut self.state, event) {
+            (Unscheduled { resends }, TimerFired) => {
+                if *resends == self.config.resends {
+                    self.state = Finished;
+                    return vec![MessageSenderAction::Finish];
+                }
+                *resends += 1;
+                self.start()
+            }
+            (Unscheduled { .. }, Datagram(datagram))
+                if matches!(datagram.datagram_type, HomaDatagramType::Overloaded) =>
+            {
+                vec![MessageSenderAction::SetTimer {
+                    timeout: self.config.timeout,
+                    jitter: false,
+                }]
+            }
+            // Once requested the receiver asks for the dropped datagrams again
+            (Unscheduled { .. } | Requested, Datagram(datagram))
+                if matches!(
+                    datagram.datagram_type,
+                    HomaDatagramType::Busy | HomaDatagramType::Overloaded
+                ) =>
+            {
+                vec![MessageSenderAction::SetTimer {
+                    timeout: self.config.large_timeout,
+                    jitter: false,
+                }]
+            }
+            (Unscheduled { .. } | Requested, Datagram(datagram)) => {
+                self.state = Requested;
+                self.handle_datagram(datagram)
+            }
+            (Requested, TimerFired) => {
+                self.state = Finished;
+                vec![MessageSenderAction::Finish]
+            }
+            (Finished, _) => Vec::new(),
+        }
+    }
+
+    // Send the datagrams requested by a grant or resend,
+    // finish once the final grant arrived
+    fn handle_datagram(&mut self, datagram: HomaDatagram) -> Vec<MessageSenderAction> {
+        let mut actions = vec![MessageSenderAction::PutRemoteWorkload(datagram.workload)];
+        match datagram.datagram_type {
+            // The final grant carries the number of datagrams of the message
+            HomaDatagramType::Grant if datagram.sequence_number == self.datagram_count => {
+                self.state = MessageSenderState::Finished;
+                actions.push(MessageSenderAction::Finish);
+                return actions;
+            }
+            HomaDatagramType::Grant => {
+                let granted_datagrams = datagram
+                    .offset
+                    .div_ceil(self.config.datagram_payload_length)
+                    .min(self.datagram_count as u64) as u32;
+                actions.extend(self.send_datagrams(
+                    self.sent_datagrams,
+                    granted_datagrams,
+                    datagram.priority,
+                ));
+            }
+            HomaDatagramType::Resend => {
+                for (start, end) in datagram.ranges.iter().take(CONST::RESEND_RANGE_LIMIT) {
+                    actions.extend(self.send_datagrams(*start, *end, datagram.priority));
+                }
+            }
+            _ => (),
+        }
+        actions.push(MessageSenderAction::SetTimer {
+            timeout: self.config.large_timeout,
+            jitter: false,
+        });
+        actions
+    }
+
+    // Send the scheduled datagrams in the range, counting them as sent
+    fn send_datagrams(&mut self, start: u32, end: u32, priority: u8) -> Vec<MessageSenderAction> {
+        let end = end.min(self.datagram_count);
+        self.sent_datagrams = self.sent_datagrams.max(end);
+        (start..end)
+            .map(|sequence_number| MessageSenderAction::SendDatagram {
+                sequence_number,
+                priority,
+                unscheduled: false,
+            })
+            .collect()
+    }
+
+    fn send_unscheduled_datagrams(&self) -> Vec<MessageSenderAction> {
+        (0..self.unscheduled_datagrams)
+            .map(|sequence_number| MessageSenderAction::SendDatagram {
+                sequence_number,
+                priority: self.unscheduled_priority,
+                unscheduled: true,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageSenderAction;
+    use super::MessageSenderCore;
+    use super::MessageSenderEvent;
+    use crate::config::CONFIG;
+    use crate::models::datagram::HomaDatagram;
+    use crate::models::datagram::HomaDatagramType;
+    use crate::protocol::ProtocolConfig;
+    use tokio::time::Duration;
+
+    fn config() -> ProtocolConfig {
+        ProtocolConfig::from_config(&CONFIG)
+    }
+
+    fn grant(sequence_number: u32, datagrams: u32) -> MessageSenderEvent {
+        MessageSenderEvent::Datagram(HomaDatagram {
+            datagram_type: HomaDatagramType::Grant,
+            sequence_number,
+            offset: datagrams as u64 * CONFIG.DATAGRAM_PAYLOAD_LENGTH as u64,
+            priority: 3,
+            ..Default::default()
+        })
+    }
+
+    fn sent(actions: &[MessageSenderAction]) -> Vec<u32> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                MessageSenderAction::SendDatagram {
+                    sequence_number, ..
+                } => Some(*sequence_number),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn message_sender_test() {
+        let unscheduled_datagrams = CONFIG.UNSCHEDULED_DATAGRAM_LIMIT as u32;
+        let datagram_count = unscheduled_datagrams + 4;
+        let mut core = MessageSenderCore::new(config(), datagram_count, 7, unscheduled_datagrams);
+        let unscheduled = (0..unscheduled_datagrams).collect::<Vec<_>>();
+        assert_eq!(sent(&core.start()), unscheduled);
+        assert_eq!(
+            sent(&core.handle(MessageSenderEvent::TimerFired)),
+            unscheduled
+        );
+        let busy = HomaDatagram {
+            datagram_type: HomaDatagramType::Busy,
+            ..Default::default()
+        };
+        assert!(sent(&core.handle(MessageSenderEvent::Datagram(busy))).is_empty());
+        assert_eq!(
+            sent(&core.handle(MessageSenderEvent::TimerFired)),
+            unscheduled
+        );
+
+        // Grants send every datagram up to their offset once
+        let actions = core.handle(grant(unscheduled_datagrams, unscheduled_datagrams + 2));
+        assert!(actions.contains(&MessageSenderAction::SendDatagram {
+            sequence_number: unscheduled_datagrams,
+            priority: 3,
+            unscheduled: false,
+        }));
+        assert_eq!(
+            sent(&actions),
+            vec![unscheduled_datagrams, unscheduled_datagrams + 1]
+        );
+        assert!(
+            sent(&core.handle(grant(unscheduled_datagrams, unscheduled_datagrams + 2))).is_empty()
+        );
+        assert_eq!(
+            sent(&core.handle(grant(unscheduled_datagrams + 1, datagram_count))),
+            vec![unscheduled_datagrams + 2, unscheduled_datagrams + 3]
+        );
+
+        // Resends send the datagrams of their ranges again
+        let resend = HomaDatagram {
+            datagram_type: HomaDatagramType::Resend,
+            ranges: vec![
+                (1, 2),
+                (unscheduled_datagrams + 1, unscheduled_datagrams + 3),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            sent(&core.handle(MessageSenderEvent::Datagram(resend))),
+            vec![1, unscheduled_datagrams + 1, unscheduled_datagrams + 2]
+        );
+
+        assert!(core
+            .handle(grant(datagram_count, datagram_count))
+            .contains(&MessageSenderAction::Finish));
+        assert!(core.is_finished());
+        assert!(core.handle(MessageSenderEvent::TimerFired).is_empty());
+
+        // Receivers of an incast reduce the unscheduled datagrams,
+        // the grants cover the remaining ones
+        let mut core = MessageSenderCore::new(config(), datagram_count, 7, 2);
+        assert_eq!(sent(&core.start()), vec![0, 1]);
+        assert_eq!(sent(&core.handle(grant(2, 4))), vec![2, 3]);
+    }
+
+    #[test]
+    fn overloaded_test() {
+        let mut core = MessageSenderCore::new(config(), 4, 7, 4);
+        assert_eq!(sent(&core.start()), vec![0, 1, 2, 3]);
+
+        // Unscheduled datagrams dropped by an overloaded receiver
+        // are resent after the timeout, not the large timeout
+        let overloaded = HomaDatagram {
+            datagram_type: HomaDatagramType::Overloaded,
+            ..Default::default()
+        };
+        assert_eq!(
+            core.handle(MessageSenderEvent::Datagram(overloaded)),
+            vec![MessageSenderAction::SetTimer {
+                timeout: Duration::from_millis(CONFIG.TIMEOUT),
+                jitter: false,
+            }]
+        );
+        assert_eq!(
+            sent(&core.handle(MessageSenderEvent::TimerFired)),
+            vec![0, 1, 2, 3]
+        );
+
+        // Busy datagrams of a receiver waiting for an acknowledgement
+        // postpone the timer by the large timeout
+        let busy = HomaDatagram {
+            datagram_type: HomaDatagramType::Busy,
+            ..Default::default()
+        };
+        assert_eq!(
+            core.handle(MessageSenderEvent::Datagram(busy)),
+            vec![MessageSenderAction::SetTimer {
+                timeout: Duration::from_millis(CONFIG.LARGE_TIMEOUT),
+                jitter: false,
+            }]
+        );
+    }
+}
@@ -0,0 +1,32 @@
+use crate::config::Config;
+use tokio::time::Duration;
+
+pub mod message_receiver;
+pub mod message_sender;
+
+// Options of homad the state machines decide on, taken from the
+// configuration by their driver
+#[derive(Clone, Copy, Debug)]
+pub struct ProtocolConfig {
+    pub datagram_payload_length: u64,
+    pub unscheduled_datagram_limit: u32,
+    pub rtt_bytes: u64,
+    pub timeout: Duration,
+    pub large_timeout: Duration,
+    pub resends: usize,
+    pub large_resends: usize,
+}
+
+impl ProtocolConfig {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            datagram_payload_length: config.DATAGRAM_PAYLOAD_LENGTH as u64,
+            unscheduled_datagram_limit: config.UNSCHEDULED_DATAGRAM_LIMIT as u32,
+            rtt_bytes: config.RTT_BYTES,
+            timeout: Duration::from_millis(config.TIMEOUT),
+            large_timeout: Duration::from_millis(config.LARGE_TIMEOUT),
+            resends: config.RESENDS,
+            large_resends: config.LARGE_RESENDS,
+        }
+    }
+}
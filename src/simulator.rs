@@ -0,0 +1,716 @@
+/*
+Simulator
+
+Discrete-event simulation of a cluster of hosts running the MessageSender,
+MessageReceiver, PriorityManager and WorkloadManager actors of homad on the
+paused clock of a single-threaded tokio runtime. Time only advances once every
+actor waits on a timer, so a run is reproducible for its seed. As tokio timers
+only resolve milliseconds, the clock is dilated so that a millisecond of the
+runtime is a nanosecond of the simulation, the timeouts of the actors are
+stretched alike
+
+Each host takes the place of the Application actor, dispatching datagrams to
+its MessageSenders and MessageReceivers, and sends through the egress queue of
+the DatagramSender at link rate to a switch. The switch port towards each host
+serves a queue per priority level, mapped from the DSCP, strictly highest first,
+at link rate and drops datagrams once the buffer of the port is full
+
+Messages arrive at every host as a Poisson process offering the configured
+load, with lengths drawn from the workload and uniformly random destinations.
+The slowdown of a delivered message is its completion time over the time it
+takes on an idle network
+*/
+use crate::components::application::ApplicationHandle;
+use crate::components::application::ApplicationMessage;
+use crate::components::application_writer::ApplicationWriterHandle;
+use crate::components::application_writer::ApplicationWriterMessage;
+use crate::components::datagram_sender::DatagramSenderHandle;
+use crate::components::datagram_sender::DatagramSenderMessage;
+use crate::components::datagram_sender::EgressQueue;
+use crate::components::message_receiver::MessageReceiverHandle;
+use crate::components::message_sender::MessageSenderHandle;
+use crate::components::priority_manager::PriorityManagerHandle;
+use crate::components::workload_manager::WorkloadManagerHandle;
+use crate::config::CONFIG;
+use crate::config::CONST;
+use crate::models::datagram::HomaDatagram;
+use crate::models::datagram::HomaDatagramType;
+use crate::models::message::HomaMessageBuilder;
+use crate::utils::dilate_time;
+use crate::utils::seed_rng;
+use bincode::deserialize;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::Packet;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::select;
+use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
+use tokio::time::sleep;
+use tokio::time::sleep_until;
+use tokio::time::Duration;
+use tokio::time::Instant;
+
+// Runtime time per simulated time
+const TIME_DILATION: u32 = 1_000_000;
+
+// Distribution of message lengths as points of its CDF, lengths are
+// interpolated linearly between the points
+#[derive(Clone)]
+pub struct Workload {
+    cdf: Vec<(u64, f64)>,
+}
+
+impl Workload {
+    // Parse lines of a message length and its cumulative probability,
+    // lines starting with # are ignored
+    pub fn from_cdf(text: &str) -> Result<Self, String> {
+        let mut cdf = Vec::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(length), Some(probability)) = (fields.next(), fields.next()) else {
+                return Err(format!("Invalid workload line: {}", line));
+            };
+            let length = length
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid workload length {}: {}", length, e))?;
+            let probability = probability
+                .parse::<f64>()
+                .map_err(|e| format!("Invalid workload probability {}: {}", probability, e))?;
+            cdf.push((length, probability));
+        }
+        let sorted = cdf
+            .windows(2)
+            .all(|points| points[0].0 <= points[1].0 && points[0].1 <= points[1].1);
+        match cdf.last() {
+            Some((_, probability)) if sorted && (*probability - 1.0).abs() < 1e-9 => {
+                Ok(Self { cdf })
+            }
+            _ => Err("Workload CDF must be increasing and end at 1".to_string()),
+        }
+    }
+
+    // Lengths spread evenly on a logarithmic scale between min and max
+    pub fn log_uniform(min: u64, max: u64) -> Self {
+        let points = 100;
+        let ratio = (max as f64 / min as f64).ln();
+        let cdf = (0..=points)
+            .map(|i| {
+                let probability = i as f64 / points as f64;
+                let length = (min as f64 * (ratio * probability).exp()).round() as u64;
+                (length, probability)
+            })
+            .collect();
+        Self { cdf }
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> u64 {
+        let u = rng.random::<f64>();
+        let i = self
+            .cdf
+            .iter()
+            .position(|(_, probability)| *probability >= u)
+            .unwrap_or(self.cdf.len() - 1);
+        if i == 0 {
+            return self.cdf[0].0.max(1);
+        }
+        let (previous_length, previous_probability) = self.cdf[i - 1];
+        let (length, probability) = self.cdf[i];
+        let fraction = (u - previous_probability) / (probability - previous_probability);
+        let length = previous_length as f64 + fraction * (length - previous_length) as f64;
+        (length.round() as u64).max(1)
+    }
+
+    fn mean(&self) -> f64 {
+        let first = self.cdf[0].0 as f64 * self.cdf[0].1;
+        first
+            + self
+                .cdf
+                .windows(2)
+                .map(|points| {
+                    let ((a, p), (b, q)) = (points[0], points[1]);
+                    (q - p) * (a + b) as f64 / 2.0
+                })
+                .sum::<f64>()
+    }
+}
+
+pub struct SimulationOptions {
+    pub hosts: usize,
+    pub messages: usize,
+    // Offered load as a fraction of the link speed
+    pub load: f64,
+    pub seed: u64,
+    pub workload: Workload,
+    // Speed of each link in Mbit/s
+    pub link_speed: u64,
+    // Propagation delay of each link
+    pub link_delay: Duration,
+    // Buffer of each switch port in bytes, 0 for unlimited
+    pub switch_buffer: u64,
+}
+
+pub struct SimulationResult {
+    // Length and completion time of every message, none if not delivered
+    pub messages: Vec<(u64, Option<Duration>)>,
+    pub dropped_datagrams: u64,
+    pub duration: Duration,
+    link_speed: u64,
+    link_delay: Duration,
+}
+
+impl SimulationResult {
+    // Completion time of the message on an idle network, all datagrams are
+    // serialized onto the link of the sender and the last one again onto
+    // the link of the receiver
+    pub fn ideal_completion_time(&self, length: u64) -> Duration {
+        let payload_length = CONFIG.DATAGRAM_PAYLOAD_LENGTH as u64;
+        let datagrams = length.div_ceil(payload_length);
+        let last_payload_length = length - (datagrams - 1) * payload_length;
+        let bytes = length + datagrams * packet_overhead();
+        transmission_time(bytes, self.link_speed)
+            + transmission_time(last_payload_length + packet_overhead(), self.link_speed)
+            + self.link_delay * 2
+    }
+
+    // Slowdowns of the delivered messages grouped into buckets of lengths
+    // up to each power of two, with the count, median, 99th percentile
+    // and mean slowdown
+    pub fn slowdowns(&self) -> Vec<(u64, usize, f64, f64, f64)> {
+        let mut buckets = HashMap::<u64, Vec<f64>>::new();
+        for (length, completion_time) in &self.messages {
+            if let Some(completion_time) = completion_time {
+                let slowdown = completion_time.as_secs_f64()
+                    / self.ideal_completion_time(*length).as_secs_f64();
+                buckets
+                    .entry(length.next_power_of_two())
+                    .or_default()
+                    .push(slowdown);
+            }
+        }
+        let mut slowdowns = buckets
+            .into_iter()
+            .map(|(length, mut slowdowns)| {
+                slowdowns.sort_by(f64::total_cmp);
+                let percentile = |p: f64| {
+                    let i = ((slowdowns.len() - 1) as f64 * p).round() as usize;
+                    slowdowns[i]
+                };
+                let mean = slowdowns.iter().sum::<f64>() / slowdowns.len() as f64;
+                (
+                    length,
+                    slowdowns.len(),
+                    percentile(0.5),
+                    percentile(0.99),
+                    mean,
+                )
+            })
+            .collect::<Vec<_>>();
+        slowdowns.sort_by_key(|bucket| bucket.0);
+        slowdowns
+    }
+}
+
+fn host_address(host: usize) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 1)) + host as u32)
+}
+
+fn host_index(address: Ipv4Addr) -> usize {
+    (u32::from(address) - u32::from(Ipv4Addr::new(10, 0, 0, 1))) as usize
+}
+
+// Bytes of a packet besides the payload of its datagram
+fn packet_overhead() -> u64 {
+    let packet = HomaDatagram::default().to_ipv4(Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED, 0);
+    packet.len() as u64
+}
+
+fn transmission_time(bytes: u64, link_speed: u64) -> Duration {
+    Duration::from_nanos(bytes * 8 * 1000 / link_speed)
+}
+
+// Wait for the simulated duration on the dilated clock
+async fn simulated_sleep(duration: Duration) {
+    sleep(duration * TIME_DILATION).await;
+}
+
+// Simulated time since the instant of the dilated clock
+fn simulated_elapsed(instant: Instant) -> Duration {
+    instant.elapsed() / TIME_DILATION
+}
+
+// Deliver the packet at the end of the link
+fn propagate(packet: Vec<u8>, tx: UnboundedSender<Vec<u8>>, link_delay: Duration) {
+    tokio::spawn(async move {
+        simulated_sleep(link_delay).await;
+        let _ = tx.send(packet);
+    });
+}
+
+// Counters shared by the hosts to detect the end of the simulation
+#[derive(Default)]
+struct Progress {
+    // Hosts still generating messages
+    generating_hosts: usize,
+    message_senders: usize,
+    message_receivers: usize,
+}
+
+struct Simulation {
+    link_speed: u64,
+    link_delay: Duration,
+    switch_ports: Vec<UnboundedSender<Vec<u8>>>,
+    // Length and arrival time of every generated message
+    messages: Mutex<HashMap<u64, (u64, Instant)>>,
+    completion_times: Mutex<HashMap<u64, Duration>>,
+    dropped_datagrams: Mutex<u64>,
+    progress: Mutex<Progress>,
+    finished: Notify,
+}
+
+impl Simulation {
+    fn update_progress(&self, update: impl FnOnce(&mut Progress)) {
+        let mut progress = self.progress.lock().unwrap();
+        update(&mut progress);
+        if progress.generating_hosts == 0
+            && progress.message_senders == 0
+            && progress.message_receivers == 0
+        {
+            self.finished.notify_one();
+        }
+    }
+}
+
+// Send the packets queued by the actors of a host in egress queue order,
+// each after the previous one left the link
+async fn run_host_link(simulation: Arc<Simulation>, mut rx: Receiver<DatagramSenderMessage>) {
+    let mut egress_queue = EgressQueue::default();
+    loop {
+        if egress_queue.is_empty() {
+            match rx.recv().await {
+                Some(datagram_sender_message) => egress_queue.push(datagram_sender_message),
+                None => return,
+            }
+        }
+        while let Ok(datagram_sender_message) = rx.try_recv() {
+            egress_queue.push(datagram_sender_message);
+        }
+        let Some(packet) = egress_queue.pop() else {
+            continue;
+        };
+        simulated_sleep(transmission_time(
+            packet.len() as u64,
+            simulation.link_speed,
+        ))
+        .await;
+        let Some(ipv4_packet) = Ipv4Packet::new(&packet) else {
+            continue;
+        };
+        let destination = host_index(ipv4_packet.get_destination());
+        if let Some(switch_port) = simulation.switch_ports.get(destination) {
+            propagate(packet, switch_port.clone(), simulation.link_delay);
+        }
+    }
+}
+
+// Serve the priority queues of the switch port towards a host,
+// dropping packets arriving at a full buffer
+async fn run_switch_port(
+    simulation: Arc<Simulation>,
+    mut rx: UnboundedReceiver<Vec<u8>>,
+    host_tx: UnboundedSender<Vec<u8>>,
+    buffer: u64,
+) {
+    let mut queues = vec![VecDeque::<Vec<u8>>::new(); CONFIG.priority_levels()];
+    let mut queued_bytes = 0;
+    let enqueue = |queues: &mut Vec<VecDeque<Vec<u8>>>, queued_bytes: &mut u64, packet: Vec<u8>| {
+        let Some(ipv4_packet) = Ipv4Packet::new(&packet) else {
+            return;
+        };
+        let queue = CONFIG.priority_level(ipv4_packet.get_dscp());
+        if buffer > 0 && *queued_bytes + packet.len() as u64 > buffer {
+            *simulation.dropped_datagrams.lock().unwrap() += 1;
+            return;
+        }
+        *queued_bytes += packet.len() as u64;
+        queues[queue].push_back(packet);
+    };
+    loop {
+        if queued_bytes == 0 {
+            match rx.recv().await {
+                Some(packet) => enqueue(&mut queues, &mut queued_bytes, packet),
+                None => return,
+            }
+        }
+        while let Ok(packet) = rx.try_recv() {
+            enqueue(&mut queues, &mut queued_bytes, packet);
+        }
+        let Some(packet) = queues.iter_mut().rev().find_map(|queue| queue.pop_front()) else {
+            continue;
+        };
+        queued_bytes -= packet.len() as u64;
+        simulated_sleep(transmission_time(
+            packet.len() as u64,
+            simulation.link_speed,
+        ))
+        .await;
+        propagate(packet, host_tx.clone(), simulation.link_delay);
+    }
+}
+
+struct Host {
+    address: Ipv4Addr,
+    rng: StdRng,
+    // Messages left to generate and the arrival time of the next one
+    remaining_messages: usize,
+    next_arrival: Instant,
+    arrival_rate: f64,
+    workload: Workload,
+    hosts: usize,
+
+    message_senders: HashMap<u64, MessageSenderHandle>,
+    message_receivers: HashMap<u64, MessageReceiverHandle>,
+    delivered_messages: HashSet<u64>,
+
+    simulation: Arc<Simulation>,
+    application_handle: ApplicationHandle,
+    application_writer_handle: ApplicationWriterHandle,
+    datagram_sender_handle: DatagramSenderHandle,
+    priority_manager_handle: PriorityManagerHandle,
+    workload_manager_handle: WorkloadManagerHandle,
+}
+
+impl Host {
+    // Time on the dilated clock until the next message arrives,
+    // exponentially distributed
+    fn arrival_interval(&mut self) -> Duration {
+        let u = self.rng.random::<f64>();
+        Duration::from_secs_f64(-(1.0 - u).ln() / self.arrival_rate) * TIME_DILATION
+    }
+
+    // Spawn a MessageSender for a message to a random other host
+    fn generate_message(&mut self) {
+        let length = self.workload.sample(&mut self.rng);
+        let mut destination = self.rng.gen_range(0..self.hosts - 1);
+        if destination >= host_index(self.address) {
+            destination += 1;
+        }
+        let message_id = self.rng.random::<u64>();
+        let message = HomaMessageBuilder::default()
+            .id(message_id)
+            .source_address(self.address.octets())
+            .destination_address(host_address(destination).octets())
+            .source_id(1)
+            .destination_id(1)
+            .content(vec![0; length as usize])
+            .build()
+            .unwrap();
+        self.simulation
+            .messages
+            .lock()
+            .unwrap()
+            .insert(message_id, (length, Instant::now()));
+        let (message_sender_handle, _) = MessageSenderHandle::new(
+            message,
+            self.application_handle.clone(),
+            self.application_writer_handle.clone(),
+            self.datagram_sender_handle.clone(),
+            self.priority_manager_handle.clone(),
+            self.workload_manager_handle.clone(),
+        );
+        self.message_senders
+            .insert(message_id, message_sender_handle);
+        self.remaining_messages -= 1;
+        let generating = self.remaining_messages > 0;
+        self.simulation.update_progress(|progress| {
+            progress.message_senders += 1;
+            if !generating {
+                progress.generating_hosts -= 1;
+            }
+        });
+        let arrival_interval = self.arrival_interval();
+        self.next_arrival += arrival_interval;
+    }
+
+    // Validate the checksum and hand the datagram to its actor,
+    // as the DatagramReceiver and Application actor do
+    fn handle_packet(&mut self, packet: Vec<u8>) {
+        let Some(ipv4_packet) = Ipv4Packet::new(&packet) else {
+            return;
+        };
+        let Ok(mut datagram) = deserialize::<HomaDatagram>(ipv4_packet.payload()) else {
+            return;
+        };
+        let received_checksum = datagram.checksum;
+        if datagram.checksum() != Ok(received_checksum) {
+            return;
+        }
+        let message_id = datagram.message_id;
+        match datagram.datagram_type {
+            HomaDatagramType::Data => {
+                if self.delivered_messages.contains(&message_id) {
+                    return;
+                }
+                if let Some(message_receiver_handle) = self.message_receivers.get(&message_id) {
+                    let _ = message_receiver_handle.tx.try_send(datagram);
+                    return;
+                }
+                let (message_receiver_handle, _) = MessageReceiverHandle::new(
+                    datagram,
+                    ipv4_packet.get_source(),
+                    ipv4_packet.get_destination(),
+                    Instant::now(),
+                    self.application_handle.clone(),
+                    self.application_writer_handle.clone(),
+                    self.datagram_sender_handle.clone(),
+                    self.priority_manager_handle.clone(),
+                    self.workload_manager_handle.clone(),
+                    false,
+                );
+                self.message_receivers
+                    .insert(message_id, message_receiver_handle);
+                self.simulation
+                    .update_progress(|progress| progress.message_receivers += 1);
+            }
+            _ => {
+                if let Some(message_sender_handle) = self.message_senders.get(&message_id) {
+                    let _ = message_sender_handle.tx.try_send(datagram);
+                }
+            }
+        }
+    }
+
+    fn handle_application_message(&mut self, application_message: ApplicationMessage) {
+        use ApplicationMessage::*;
+        match application_message {
+            FromMessageSender(message_id) if self.message_senders.remove(&message_id).is_some() => {
+                self.simulation
+                    .update_progress(|progress| progress.message_senders -= 1);
+            }
+            FromMessageReceiver(message_id)
+                if self.message_receivers.remove(&message_id).is_some() =>
+            {
+                self.simulation
+                    .update_progress(|progress| progress.message_receivers -= 1);
+            }
+            _ => (),
+        }
+    }
+
+    // Record the completion time of the delivered message
+    fn handle_delivery(&mut self, application_writer_message: ApplicationWriterMessage) {
+        if let ApplicationWriterMessage::FromMessageReceiver(message) = application_writer_message {
+            self.delivered_messages.insert(message.id);
+            let messages = self.simulation.messages.lock().unwrap();
+            if let Some((_, arrival)) = messages.get(&message.id) {
+                self.simulation
+                    .completion_times
+                    .lock()
+                    .unwrap()
+                    .insert(message.id, simulated_elapsed(*arrival));
+            }
+        }
+    }
+}
+
+async fn run_host(
+    mut host: Host,
+    mut packet_rx: UnboundedReceiver<Vec<u8>>,
+    mut application_rx: Receiver<ApplicationMessage>,
+    mut application_writer_rx: Receiver<ApplicationWriterMessage>,
+) {
+    loop {
+        select! {
+            _ = sleep_until(host.next_arrival), if host.remaining_messages > 0 => {
+                host.generate_message();
+            }
+            Some(packet) = packet_rx.recv() => host.handle_packet(packet),
+            Some(application_message) = application_rx.recv() => {
+                host.handle_application_message(application_message);
+            }
+            Some(application_writer_message) = application_writer_rx.recv() => {
+                host.handle_delivery(application_writer_message);
+            }
+            else => return,
+        }
+    }
+}
+
+async fn run_simulation(options: SimulationOptions) -> SimulationResult {
+    let start = Instant::now();
+    let mut switch_ports = Vec::new();
+    let mut switch_port_rxs = Vec::new();
+    for _ in 0..options.hosts {
+        let (tx, rx) = unbounded_channel();
+        switch_ports.push(tx);
+        switch_port_rxs.push(rx);
+    }
+    let simulation = Arc::new(Simulation {
+        link_speed: options.link_speed,
+        link_delay: options.link_delay,
+        switch_ports,
+        messages: Mutex::new(HashMap::new()),
+        completion_times: Mutex::new(HashMap::new()),
+        dropped_datagrams: Mutex::new(0),
+        progress: Mutex::new(Progress {
+            generating_hosts: options.hosts,
+            ..Default::default()
+        }),
+        finished: Notify::new(),
+    });
+
+    // Spread the messages over the hosts, each offering the load
+    let link_bytes_per_second = options.link_speed as f64 * 1e6 / 8.0;
+    let arrival_rate = options.load * link_bytes_per_second / options.workload.mean();
+    for (i, switch_port_rx) in switch_port_rxs.into_iter().enumerate() {
+        let (packet_tx, packet_rx) = unbounded_channel();
+        tokio::spawn(run_switch_port(
+            Arc::clone(&simulation),
+            switch_port_rx,
+            packet_tx,
+            options.switch_buffer,
+        ));
+
+        let (datagram_sender_tx, datagram_sender_rx) = channel(CONST::DATAGRAM_SENDER_QUEUE_LENGTH);
+        tokio::spawn(run_host_link(Arc::clone(&simulation), datagram_sender_rx));
+        let (application_tx, application_rx) = channel(1000);
+        let (application_writer_tx, application_writer_rx) = channel(1000);
+        let priority_manager_handle = PriorityManagerHandle::new();
+
+        let remaining_messages =
+            options.messages / options.hosts + usize::from(i < options.messages % options.hosts);
+        if remaining_messages == 0 {
+            simulation.update_progress(|progress| progress.generating_hosts -= 1);
+        }
+        let mut host = Host {
+            address: host_address(i),
+            rng: StdRng::seed_from_u64(options.seed.wrapping_add(i as u64)),
+            remaining_messages,
+            next_arrival: start,
+            arrival_rate,
+            workload: options.workload.clone(),
+            hosts: options.hosts,
+
+            message_senders: HashMap::new(),
+            message_receivers: HashMap::new(),
+            delivered_messages: HashSet::new(),
+
+            simulation: Arc::clone(&simulation),
+            application_handle: ApplicationHandle::detached(application_tx),
+            application_writer_handle: ApplicationWriterHandle {
+                tx: application_writer_tx,
+            },
+            datagram_sender_handle: DatagramSenderHandle::detached(datagram_sender_tx),
+            priority_manager_handle: priority_manager_handle.clone(),
+            workload_manager_handle: WorkloadManagerHandle::new(priority_manager_handle),
+        };
+        let arrival_interval = host.arrival_interval();
+        host.next_arrival += arrival_interval;
+        tokio::spawn(run_host(
+            host,
+            packet_rx,
+            application_rx,
+            application_writer_rx,
+        ));
+    }
+
+    simulation.finished.notified().await;
+    let duration = simulated_elapsed(start);
+
+    let messages = simulation.messages.lock().unwrap();
+    let completion_times = simulation.completion_times.lock().unwrap();
+    let mut messages = messages
+        .iter()
+        .map(|(message_id, (length, arrival))| {
+            (*arrival, *length, completion_times.get(message_id).copied())
+        })
+        .collect::<Vec<_>>();
+    messages.sort_by_key(|message| message.0);
+    let dropped_datagrams = *simulation.dropped_datagrams.lock().unwrap();
+    SimulationResult {
+        messages: messages
+            .into_iter()
+            .map(|(_, length, completion_time)| (length, completion_time))
+            .collect(),
+        dropped_datagrams,
+        duration,
+        link_speed: options.link_speed,
+        link_delay: options.link_delay,
+    }
+}
+
+// Run the simulation to completion on a paused clock
+pub fn simulate(options: SimulationOptions) -> Result<SimulationResult, String> {
+    if options.hosts < 2 {
+        return Err("Simulation needs at least two hosts".to_string());
+    }
+    if options.link_speed == 0 {
+        return Err("Simulation needs a link speed, set --link-speed".to_string());
+    }
+    if !(options.load > 0.0 && options.load <= 1.0) {
+        return Err("Load must be above 0 and at most 1".to_string());
+    }
+    seed_rng(options.seed);
+    dilate_time(TIME_DILATION as u64);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let result = runtime.block_on(run_simulation(options));
+    runtime.shutdown_background();
+    dilate_time(1);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::simulate;
+    use super::SimulationOptions;
+    use super::Workload;
+    use tokio::time::Duration;
+
+    fn options() -> SimulationOptions {
+        SimulationOptions {
+            hosts: 4,
+            messages: 40,
+            load: 0.5,
+            seed: 7,
+            workload: Workload::log_uniform(100, 100_000),
+            link_speed: 10000,
+            link_delay: Duration::from_micros(1),
+            switch_buffer: 0,
+        }
+    }
+
+    #[test]
+    fn simulation_test() {
+        let first = simulate(options()).unwrap();
+        let second = simulate(options()).unwrap();
+        assert_eq!(first.messages.len(), 40);
+        assert!(first
+            .messages
+            .iter()
+            .all(|(_, completion_time)| completion_time.is_some()));
+        assert_eq!(first.messages, second.messages);
+        assert!(first
+            .slowdowns()
+            .iter()
+            .all(|(_, _, median, _, _)| *median >= 1.0));
+    }
+}
@@ -0,0 +1,431 @@
+/*
+HomaStack
+
+Start the actors of a homad instance on top of a transport, applications
+are connected by handing their streams to the ApplicationRegistrar
+
+The transport is wrapped in a CaptureTransport and a FaultTransport so that
+the admin interface can capture the traffic of the stack and inject faults,
+captures show the packets after the faults were injected
+*/
+use crate::components::application::ApplicationHandle;
+use crate::components::application_registrar::ApplicationRegistrarHandle;
+use crate::components::datagram_receiver::DatagramReceiver;
+use crate::components::datagram_sender::DatagramSenderHandle;
+use crate::components::priority_manager::PriorityManagerHandle;
+use crate::components::workload_manager::WorkloadManagerHandle;
+use crate::config::CONFIG;
+use crate::dispatch_table::DispatchTable;
+use crate::transport::capture::CaptureTransport;
+use crate::transport::fault::FaultTransport;
+use crate::transport::Transport;
+use std::sync::Arc;
+
+pub struct HomaStack {
+    pub application_registrar_handle: ApplicationRegistrarHandle,
+    pub application_handles: Arc<DispatchTable<u32, ApplicationHandle>>,
+    pub datagram_sender_handle: DatagramSenderHandle,
+    pub capture_transport: Arc<CaptureTransport>,
+    pub fault_transport: Arc<FaultTransport>,
+}
+
+impl HomaStack {
+    // Start the actors, must be called within a tokio runtime
+    pub fn start(transport: Arc<dyn Transport>) -> Self {
+        let capture_transport = Arc::new(CaptureTransport::new(transport));
+        let fault_transport = Arc::new(FaultTransport::new(
+            capture_transport.clone(),
+            Some(CONFIG.fault_options()),
+        ));
+        let transport: Arc<dyn Transport> = fault_transport.clone();
+
+        let priority_manager_handle = PriorityManagerHandle::new();
+
+        let workload_manager_handle = WorkloadManagerHandle::new(priority_manager_handle.clone());
+
+        let datagram_sender_handle = DatagramSenderHandle::new(Arc::clone(&transport));
+
+        let application_handles = Arc::new(DispatchTable::<u32, ApplicationHandle>::new());
+
+        let application_handles_clone = Arc::clone(&application_handles);
+        let application_registrar_handle = ApplicationRegistrarHandle::new(
+            application_handles_clone,
+            priority_manager_handle,
+            workload_manager_handle,
+            datagram_sender_handle.clone(),
+        );
+
+        let application_handles_clone = Arc::clone(&application_handles);
+        DatagramReceiver::start_many(
+            transport,
+            application_handles_clone,
+            datagram_sender_handle.clone(),
+        );
+
+        Self {
+            application_registrar_handle,
+            application_handles,
+            datagram_sender_handle,
+            capture_transport,
+            fault_transport,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HomaStack;
+    use crate::config::LocalAddress;
+    use crate::models::datagram::HomaDatagram;
+    use crate::models::datagram::HomaDatagramType;
+    use crate::models::frame::HomaFrameHeader;
+    use crate::models::frame::HomaFrameType;
+    use crate::models::frame::HomaSendFailure;
+    use crate::models::message::HomaMessage;
+    use crate::models::message::HomaMessageBuilder;
+    use crate::models::registration::HomaRegistrationMessage;
+    use crate::models::registration::REGISTRATION_FLAGS;
+    use crate::transport::loopback::LoopbackNetwork;
+    use crate::transport::loopback::LoopbackTransport;
+    use crate::transport::multi::MultiTransport;
+    use crate::transport::Transport;
+    use bincode::deserialize;
+    use bincode::serialize;
+    use pnet::packet::ipv4::Ipv4Packet;
+    use pnet::packet::Packet;
+    use std::io;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::Ipv4Addr;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+
+    // Loopback transport keeping the datagrams it sent
+    struct RecordingTransport {
+        transport: LoopbackTransport,
+        sent: Mutex<Vec<HomaDatagram>>,
+    }
+
+    impl Transport for RecordingTransport {
+        fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<usize> {
+            let mut sent = self.sent.lock().unwrap();
+            for packet in packets {
+                if let Some(packet) = Ipv4Packet::new(packet) {
+                    sent.extend(deserialize::<HomaDatagram>(packet.payload()));
+                }
+            }
+            self.transport.send_batch(packets)
+        }
+
+        fn recv_batch(&self, limit: usize) -> io::Result<Vec<Vec<u8>>> {
+            self.transport.recv_batch(limit)
+        }
+    }
+
+    // Register an application with the stack through one end of a stream pair
+    fn register(runtime: &Runtime, stack: &HomaStack, application_id: u32) -> UnixStream {
+        register_with_flags(runtime, stack, application_id, 0, &[])
+    }
+
+    // Register with the flags, the bytes following the registration message
+    // are written along
+    fn register_with_flags(
+        runtime: &Runtime,
+        stack: &HomaStack,
+        application_id: u32,
+        flags: u32,
+        bytes: &[u8],
+    ) -> UnixStream {
+        let (mut stream, other) = UnixStream::pair().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let registration_message = HomaRegistrationMessage {
+            application_id,
+            flags,
+        };
+        stream
+            .write_all(&registration_message.to_bytes().unwrap())
+            .unwrap();
+        stream.write_all(bytes).unwrap();
+        let registered = runtime.block_on(stack.application_registrar_handle.register(other));
+        assert!(registered.is_ok());
+        stream
+    }
+
+    fn write_message(stream: &mut UnixStream, message: &HomaMessage) {
+        let body = serialize(message).unwrap();
+        let header = HomaFrameHeader::new(HomaFrameType::Message, body.len() as u64);
+        stream.write_all(&header.to_bytes()).unwrap();
+        stream.write_all(&body).unwrap();
+    }
+
+    fn read_message(stream: &mut UnixStream) -> HomaMessage {
+        let mut header_bytes = [0u8; 8];
+        stream.read_exact(&mut header_bytes).unwrap();
+        let header = HomaFrameHeader::from_bytes(header_bytes).unwrap();
+        let mut body = vec![0; header.length as usize];
+        stream.read_exact(&mut body).unwrap();
+        deserialize(&body).unwrap()
+    }
+
+    #[test]
+    fn loopback_message_test() {
+        let runtime = Runtime::new().unwrap();
+        let network = LoopbackNetwork::new();
+        let first_address = Ipv4Addr::new(10, 0, 0, 1);
+        let second_address = Ipv4Addr::new(10, 0, 0, 2);
+
+        let (first_stack, second_stack) = runtime.block_on(async {
+            (
+                HomaStack::start(Arc::new(network.attach(first_address))),
+                HomaStack::start(Arc::new(network.attach(second_address))),
+            )
+        });
+        let mut first = register(&runtime, &first_stack, 1);
+        let mut second = register(&runtime, &second_stack, 2);
+
+        // Long enough to need scheduled datagrams
+        let content = (0..100_000).map(|i| i as u8).collect::<Vec<u8>>();
+        let message = HomaMessageBuilder::default()
+            .source_address(first_address.octets())
+            .destination_address(second_address.octets())
+            .source_id(1)
+            .destination_id(2)
+            .content(content.clone())
+            .build()
+            .unwrap();
+        write_message(&mut first, &message);
+
+        let received = read_message(&mut second);
+        assert_eq!(received.source_address, first_address.octets());
+        assert_eq!(received.source_id, 1);
+        assert_eq!(received.content, content);
+
+        let reply = HomaMessageBuilder::default()
+            .source_address(second_address.octets())
+            .destination_address(first_address.octets())
+            .source_id(2)
+            .destination_id(1)
+            .content(b"reply".to_vec())
+            .build()
+            .unwrap();
+        write_message(&mut second, &reply);
+        assert_eq!(read_message(&mut first).content, b"reply");
+
+        network.detach(first_address);
+        network.detach(second_address);
+        runtime.shutdown_background();
+    }
+
+    #[test]
+    fn registration_test() {
+        let runtime = Runtime::new().unwrap();
+        let network = LoopbackNetwork::new();
+        let address = Ipv4Addr::new(10, 0, 1, 1);
+        let stack = runtime.block_on(async { HomaStack::start(Arc::new(network.attach(address))) });
+
+        // An application stalling its registration times out
+        // without holding up the others
+        let (_stalled, stalled_other) = UnixStream::pair().unwrap();
+
+        // Applications writing only their id register without flags
+        let (mut legacy, legacy_other) = UnixStream::pair().unwrap();
+        legacy.write_all(&3u32.to_le_bytes()).unwrap();
+
+        let (stalled_registered, legacy_registered) = runtime.block_on(async {
+            tokio::join!(
+                stack.application_registrar_handle.register(stalled_other),
+                stack.application_registrar_handle.register(legacy_other),
+            )
+        });
+        assert!(stalled_registered.is_err());
+        assert!(legacy_registered.is_ok());
+        let _flagged = register(&runtime, &stack, 4);
+        assert!(stack.application_handles.contains_key(&3));
+        assert!(stack.application_handles.contains_key(&4));
+
+        network.detach(address);
+        runtime.shutdown_background();
+    }
+
+    #[test]
+    fn resume_test() {
+        let runtime = Runtime::new().unwrap();
+        let network = LoopbackNetwork::new();
+        let first_address = Ipv4Addr::new(10, 0, 2, 1);
+        let second_address = Ipv4Addr::new(10, 0, 2, 2);
+
+        let (first_stack, second_stack) = runtime.block_on(async {
+            (
+                HomaStack::start(Arc::new(network.attach(first_address))),
+                HomaStack::start(Arc::new(network.attach(second_address))),
+            )
+        });
+        let flags = REGISTRATION_FLAGS::ACK | REGISTRATION_FLAGS::RESUME;
+        let mut first = register(&runtime, &first_stack, 1);
+        let mut second =
+            register_with_flags(&runtime, &second_stack, 2, flags, &0u64.to_le_bytes());
+        let mut token = [0u8; 8];
+        second.read_exact(&mut token).unwrap();
+
+        let message = HomaMessageBuilder::default()
+            .source_address(first_address.octets())
+            .destination_address(second_address.octets())
+            .source_id(1)
+            .destination_id(2)
+            .content(b"unacknowledged".to_vec())
+            .build()
+            .unwrap();
+        write_message(&mut first, &message);
+        let delivered = read_message(&mut second);
+
+        // The stream breaks before the application acknowledges the message,
+        // the resumed session delivers it again whether or not the broken
+        // stream was noticed yet
+        drop(second);
+        let mut second = register_with_flags(&runtime, &second_stack, 2, flags, &token);
+        let mut resumed_token = [0u8; 8];
+        second.read_exact(&mut resumed_token).unwrap();
+        assert_eq!(resumed_token, token);
+        let redelivered = read_message(&mut second);
+        assert_eq!(redelivered.id, delivered.id);
+        assert_eq!(redelivered.content, b"unacknowledged");
+
+        network.detach(first_address);
+        network.detach(second_address);
+        runtime.shutdown_background();
+    }
+
+    #[test]
+    fn unbound_source_test() {
+        let runtime = Runtime::new().unwrap();
+        let network = LoopbackNetwork::new();
+        let address = Ipv4Addr::new(10, 0, 3, 1);
+        let unbound_address = Ipv4Addr::new(10, 0, 3, 9);
+        let stack = runtime.block_on(async {
+            let loopback = Arc::new(network.attach(address));
+            HomaStack::start(Arc::new(MultiTransport::new(vec![(
+                LocalAddress::new(address),
+                loopback,
+            )])))
+        });
+        let mut stream = register(&runtime, &stack, 1);
+
+        // A message from an address homad is not bound to is refused
+        let message = HomaMessageBuilder::default()
+            .source_address(unbound_address.octets())
+            .destination_address(address.octets())
+            .source_id(1)
+            .destination_id(1)
+            .content(b"unbound".to_vec())
+            .build()
+            .unwrap();
+        write_message(&mut stream, &message);
+
+        let mut header_bytes = [0u8; 8];
+        stream.read_exact(&mut header_bytes).unwrap();
+        let header = HomaFrameHeader::from_bytes(header_bytes).unwrap();
+        assert!(matches!(header.frame_type, HomaFrameType::SendFailure));
+        let mut body = vec![0; header.length as usize];
+        stream.read_exact(&mut body).unwrap();
+        let send_failure = deserialize::<HomaSendFailure>(&body).unwrap();
+        assert_eq!(
+            send_failure.fd_message.source_address,
+            unbound_address.octets()
+        );
+        assert_eq!(send_failure.fd_message.length, 7);
+        assert!(send_failure.error.contains("not bound"));
+
+        network.detach(address);
+        runtime.shutdown_background();
+    }
+
+    #[test]
+    fn incast_test() {
+        let runtime = Runtime::new().unwrap();
+        let network = LoopbackNetwork::new();
+        let receiver_address = Ipv4Addr::new(10, 0, 4, 1);
+        let sender_address = Ipv4Addr::new(10, 0, 4, 2);
+        let burst_address = Ipv4Addr::new(10, 0, 4, 3);
+        let sender_transport = Arc::new(RecordingTransport {
+            transport: network.attach(sender_address),
+            sent: Mutex::new(Vec::new()),
+        });
+        let (receiver_stack, sender_stack) = runtime.block_on(async {
+            (
+                HomaStack::start(Arc::new(network.attach(receiver_address))),
+                HomaStack::start(sender_transport.clone()),
+            )
+        });
+        let mut receiver = register(&runtime, &receiver_stack, 2);
+        let mut sender = register(&runtime, &sender_stack, 1);
+
+        // Far more messages than the incast threshold start at once,
+        // each sending all its unscheduled datagrams
+        let burst_peer = network.attach(burst_address);
+        let burst = (0..40)
+            .map(|message_id| {
+                let mut datagram = HomaDatagram {
+                    datagram_type: HomaDatagramType::Data,
+                    message_id,
+                    source_id: 3,
+                    destination_id: 2,
+                    message_length: 8400,
+                    offset: 8400,
+                    payload: vec![0; 1400],
+                    ..Default::default()
+                };
+                let _ = datagram.checksum();
+                datagram.to_ipv4(burst_address, receiver_address, 0)
+            })
+            .collect::<Vec<_>>();
+        burst_peer.send_batch(&burst).unwrap();
+
+        // The receiver grants a message sent after the burst, which tells
+        // the sender the limit of the incast, then the next message of the
+        // sender is sent with fewer unscheduled datagrams. The burst is
+        // detected once its messages started, so a message may overtake it
+        let message = |length| {
+            HomaMessageBuilder::default()
+                .source_address(sender_address.octets())
+                .destination_address(receiver_address.octets())
+                .source_id(1)
+                .destination_id(2)
+                .content(vec![0; length])
+                .build()
+                .unwrap()
+        };
+        // Unscheduled bytes of the messages of the length in the order sent
+        let unscheduled_bytes = |message_length| {
+            let sent = sender_transport.sent.lock().unwrap();
+            sent.iter()
+                .filter(|datagram| {
+                    matches!(datagram.datagram_type, HomaDatagramType::Data)
+                        && datagram.message_length == message_length
+                        && datagram.sequence_number == 0
+                })
+                .map(|datagram| datagram.offset)
+                .collect::<Vec<_>>()
+        };
+        for _ in 0..10 {
+            write_message(&mut sender, &message(9800));
+            assert_eq!(read_message(&mut receiver).content.len(), 9800);
+            write_message(&mut sender, &message(8400));
+            assert_eq!(read_message(&mut receiver).content.len(), 8400);
+            if unscheduled_bytes(8400).last() < Some(&8400) {
+                break;
+            }
+        }
+        assert_eq!(unscheduled_bytes(9800)[0], 8400);
+        assert!(unscheduled_bytes(8400).last() < Some(&8400));
+
+        network.detach(receiver_address);
+        network.detach(sender_address);
+        network.detach(burst_address);
+        runtime.shutdown_background();
+    }
+}
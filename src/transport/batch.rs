@@ -0,0 +1,90 @@
+/*
+Batched socket I/O
+
+Send and receive many datagrams per system call with sendmmsg and recvmmsg,
+datagrams are received into buffers kept by each receiving thread
+*/
+use crate::config::CONST;
+use nix::sys::socket::recvmmsg;
+use nix::sys::socket::sendmmsg;
+use nix::sys::socket::ControlMessage;
+use nix::sys::socket::MsgFlags;
+use nix::sys::socket::MultiHeaders;
+use nix::sys::socket::SockaddrIn;
+use std::cell::RefCell;
+use std::io;
+use std::io::IoSlice;
+use std::io::IoSliceMut;
+use std::net::SocketAddrV4;
+use std::os::fd::RawFd;
+
+thread_local! {
+    // Buffers the datagrams of a thread are received into, reused across
+    // batches so that only the received bytes are allocated
+    static RECEIVE_BUFFERS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+// Receive up to limit datagrams, blocking until the first one arrives,
+// return each with the address it was sent from
+pub fn recv_batch(fd: RawFd, limit: usize) -> io::Result<Vec<(Vec<u8>, Option<SocketAddrV4>)>> {
+    RECEIVE_BUFFERS.with_borrow_mut(|buffers| {
+        if buffers.len() < limit {
+            buffers.resize_with(limit, || vec![0u8; CONST::PACKET_BUFFER_LENGTH]);
+        }
+        let mut headers = MultiHeaders::<SockaddrIn>::preallocate(limit, None);
+        let mut slices = buffers[..limit]
+            .iter_mut()
+            .map(|buffer| [IoSliceMut::new(buffer)])
+            .collect::<Vec<_>>();
+        let received = recvmmsg(
+            fd,
+            &mut headers,
+            slices.iter_mut(),
+            MsgFlags::MSG_WAITFORONE,
+            None,
+        )?
+        .map(|message| (message.bytes, message.address.map(SocketAddrV4::from)))
+        .collect::<Vec<_>>();
+        Ok(buffers
+            .iter()
+            .zip(received)
+            .map(|(buffer, (length, address))| (buffer[..length].to_vec(), address))
+            .collect())
+    })
+}
+
+// Send every datagram to its address, a datagram the kernel
+// refuses is skipped, return the number of datagrams sent so that
+// the caller can count the skipped ones
+pub fn send_batch(fd: RawFd, datagrams: &[(&[u8], SocketAddrV4)]) -> usize {
+    let mut headers = MultiHeaders::<SockaddrIn>::preallocate(datagrams.len(), None);
+    let mut sent = 0;
+    let mut offset = 0;
+    while offset < datagrams.len() {
+        let slices = datagrams[offset..]
+            .iter()
+            .map(|(datagram, _)| [IoSlice::new(datagram)])
+            .collect::<Vec<_>>();
+        let addresses = datagrams[offset..]
+            .iter()
+            .map(|(_, address)| Some(SockaddrIn::from(*address)))
+            .collect::<Vec<_>>();
+        let cmsgs: [ControlMessage; 0] = [];
+        match sendmmsg(
+            fd,
+            &mut headers,
+            &slices,
+            addresses,
+            cmsgs,
+            MsgFlags::empty(),
+        ) {
+            Ok(results) => {
+                let count = results.count();
+                sent += count;
+                offset += count.max(1);
+            }
+            Err(_) => offset += 1,
+        }
+    }
+    sent
+}
@@ -0,0 +1,322 @@
+/*
+CaptureTransport
+
+Transport wrapper writing the packets sent and received through it to a pcap
+file while a capture started from the admin interface is running, packets are
+written as raw IPv4 with the header built by HomaDatagram::to_ipv4
+
+Packets can be filtered by peer address, application id and message id, and
+the capture stops by itself once the file or the duration reaches its bound
+
+Packets are handed to a writer thread over a bounded channel, so that the
+packet path never waits for the file, batches finding the channel full are
+dropped and counted
+*/
+use crate::config::CONST;
+use crate::models::admin::HomaCaptureOptions;
+use crate::models::admin::HomaCaptureSummary;
+use crate::models::datagram::HomaDatagram;
+use crate::transport::Transport;
+use bincode::deserialize;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::Packet;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_LINKTYPE_IPV4: u32 = 228;
+const PCAP_SNAPLEN: u32 = 65535;
+
+struct Capture {
+    options: HomaCaptureOptions,
+    writer: BufWriter<File>,
+    start_time: Instant,
+    max_bytes: u64,
+    max_duration: Duration,
+    packets: u64,
+    bytes: u64,
+}
+
+impl Capture {
+    // Create the capture file and write the pcap global header
+    fn create(options: HomaCaptureOptions) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(&options.path)?);
+        writer.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&4u16.to_le_bytes())?;
+        writer.write_all(&0i32.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+        writer.write_all(&PCAP_LINKTYPE_IPV4.to_le_bytes())?;
+        Ok(Self {
+            max_bytes: options.max_bytes.unwrap_or(CONST::CAPTURE_MAX_BYTES),
+            max_duration: Duration::from_secs(
+                options.max_duration.unwrap_or(CONST::CAPTURE_MAX_DURATION),
+            ),
+            options,
+            writer,
+            start_time: Instant::now(),
+            packets: 0,
+            bytes: 24,
+        })
+    }
+
+    // Check the packet against the peer, application and message filters
+    fn matches(&self, packet: &[u8]) -> bool {
+        let Some(ipv4_packet) = Ipv4Packet::new(packet) else {
+            return false;
+        };
+        if let Some(peer) = self.options.peer {
+            if ipv4_packet.get_source() != peer && ipv4_packet.get_destination() != peer {
+                return false;
+            }
+        }
+        if self.options.application_id.is_none() && self.options.message_id.is_none() {
+            return true;
+        }
+        let Ok(datagram) = deserialize::<HomaDatagram>(ipv4_packet.payload()) else {
+            return false;
+        };
+        if let Some(application_id) = self.options.application_id {
+            if datagram.source_id != application_id && datagram.destination_id != application_id {
+                return false;
+            }
+        }
+        if let Some(message_id) = self.options.message_id {
+            if datagram.message_id != message_id {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Write the packet as a pcap record with the time it passed the transport
+    fn write(&mut self, timestamp: Duration, packet: &[u8]) -> io::Result<()> {
+        self.writer
+            .write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&timestamp.subsec_micros().to_le_bytes())?;
+        self.writer
+            .write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.writer.write_all(packet)?;
+        self.packets += 1;
+        self.bytes += 16 + packet.len() as u64;
+        Ok(())
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.bytes >= self.max_bytes || self.start_time.elapsed() >= self.max_duration
+    }
+
+    fn summary(&self) -> HomaCaptureSummary {
+        HomaCaptureSummary {
+            path: self.options.path.clone(),
+            packets: self.packets,
+            bytes: self.bytes,
+            dropped: 0,
+        }
+    }
+}
+
+// Packets passing the transport at once and the time since the unix epoch
+type CaptureBatch = (Duration, Vec<Vec<u8>>);
+
+// Running capture, the writer thread returns the summary once the
+// sender is dropped or the capture finished
+struct CaptureSession {
+    tx: SyncSender<CaptureBatch>,
+    writer: JoinHandle<io::Result<HomaCaptureSummary>>,
+    dropped: AtomicU64,
+}
+
+// Write the matching packets until the sender is dropped, finish the
+// capture once it is exhausted or writing fails
+fn run_capture_writer(
+    mut capture: Capture,
+    rx: Receiver<CaptureBatch>,
+    capturing: Arc<AtomicBool>,
+) -> io::Result<HomaCaptureSummary> {
+    for (timestamp, packets) in rx {
+        let mut result = Ok(());
+        for packet in &packets {
+            if result.is_ok() && !capture.is_exhausted() && capture.matches(packet) {
+                result = capture.write(timestamp, packet);
+            }
+        }
+        if result.is_err() || capture.is_exhausted() {
+            capturing.store(false, Ordering::Release);
+            break;
+        }
+    }
+    capture.writer.flush()?;
+    Ok(capture.summary())
+}
+
+pub struct CaptureTransport {
+    transport: Arc<dyn Transport>,
+    // Set while a capture is writing, checked before taking the lock
+    capturing: Arc<AtomicBool>,
+    session: Mutex<Option<CaptureSession>>,
+}
+
+impl CaptureTransport {
+    pub fn new(transport: Arc<dyn Transport>) -> Self {
+        Self {
+            transport,
+            capturing: Arc::new(AtomicBool::new(false)),
+            session: Mutex::new(None),
+        }
+    }
+
+    // Start a capture and its writer thread, replacing a capture which
+    // reached its bound
+    pub fn start_capture(&self, options: HomaCaptureOptions) -> Result<(), String> {
+        let mut session = self.session.lock().unwrap();
+        if self.capturing.load(Ordering::Acquire) {
+            return Err("capture already running".to_string());
+        }
+        if let Some(previous) = session.take() {
+            drop(previous.tx);
+            let _ = previous.writer.join();
+        }
+        let capture = Capture::create(options).map_err(|e| e.to_string())?;
+        let (tx, rx) = sync_channel(CONST::CAPTURE_QUEUE_LENGTH);
+        self.capturing.store(true, Ordering::Release);
+        let capturing = Arc::clone(&self.capturing);
+        let writer = std::thread::spawn(move || run_capture_writer(capture, rx, capturing));
+        *session = Some(CaptureSession {
+            tx,
+            writer,
+            dropped: AtomicU64::new(0),
+        });
+        Ok(())
+    }
+
+    // Stop the running or finished capture, wait for its writer
+    // to write the queued packets and summarize it
+    pub fn stop_capture(&self) -> Result<HomaCaptureSummary, String> {
+        let mut session = self.session.lock().unwrap();
+        self.capturing.store(false, Ordering::Release);
+        let session = session.take().ok_or("no capture running".to_string())?;
+        drop(session.tx);
+        let mut summary = session
+            .writer
+            .join()
+            .map_err(|_| "capture writer panicked".to_string())?
+            .map_err(|e| e.to_string())?;
+        summary.dropped = session.dropped.load(Ordering::Relaxed);
+        Ok(summary)
+    }
+
+    // Queue the packets to the writer without blocking,
+    // counting them as dropped if the writer is behind
+    fn record(&self, packets: &[Vec<u8>]) {
+        if !self.capturing.load(Ordering::Acquire) {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let batch = (timestamp, packets.to_vec());
+        let session = self.session.lock().unwrap();
+        let Some(session) = session.as_ref() else {
+            return;
+        };
+        if let Err(TrySendError::Full(_)) = session.tx.try_send(batch) {
+            session
+                .dropped
+                .fetch_add(packets.len() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Transport for CaptureTransport {
+    fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<usize> {
+        let sent = self.transport.send_batch(packets)?;
+        self.record(packets);
+        Ok(sent)
+    }
+
+    fn recv_batch(&self, limit: usize) -> io::Result<Vec<Vec<u8>>> {
+        let packets = self.transport.recv_batch(limit)?;
+        self.record(&packets);
+        Ok(packets)
+    }
+
+    fn local_addresses(&self) -> Option<Vec<Ipv4Addr>> {
+        self.transport.local_addresses()
+    }
+
+    fn unroutable_packets(&self) -> u64 {
+        self.transport.unroutable_packets()
+    }
+
+    fn dropped_packets(&self) -> u64 {
+        self.transport.dropped_packets()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CaptureTransport;
+    use crate::models::admin::HomaCaptureOptions;
+    use crate::models::datagram::HomaDatagram;
+    use crate::transport::loopback::LoopbackNetwork;
+    use crate::transport::Transport;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    #[test]
+    fn capture_test() {
+        let network = LoopbackNetwork::new();
+        let address = Ipv4Addr::new(10, 0, 0, 1);
+        let capture_transport = CaptureTransport::new(Arc::new(network.attach(address)));
+        let path = std::env::temp_dir().join("homad_capture_test.pcap");
+        let options = HomaCaptureOptions {
+            path: path.to_string_lossy().to_string(),
+            message_id: Some(1),
+            ..Default::default()
+        };
+        capture_transport.start_capture(options).unwrap();
+
+        let packets = [1, 2]
+            .map(|message_id| {
+                let datagram = HomaDatagram {
+                    message_id,
+                    ..Default::default()
+                };
+                datagram.to_ipv4(address, address, 0)
+            })
+            .to_vec();
+        capture_transport.send_batch(&packets).unwrap();
+        let received = capture_transport.recv_batch(2).unwrap();
+        assert_eq!(received.len(), 2);
+
+        let summary = capture_transport.stop_capture().unwrap();
+        assert_eq!(summary.packets, 2);
+        assert_eq!(summary.dropped, 0);
+        let expected_bytes = 24 + 2 * (16 + packets[0].len() as u64);
+        assert_eq!(summary.bytes, expected_bytes);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), expected_bytes);
+        let _ = std::fs::remove_file(path);
+    }
+}
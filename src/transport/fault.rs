@@ -0,0 +1,359 @@
+/*
+FaultTransport
+
+Transport wrapper injecting faults into outgoing packets while enabled from the
+configuration or the admin interface, so that the loss handling of the
+MessageReceivers and MessageSenders can be exercised on demand
+
+Each packet matching the datagram type and peer filters is dropped, duplicated,
+reordered, delayed or corrupted with the configured probabilities. Delayed
+packets are sent from a dedicated thread once their delay passed, reordered
+packets are delayed just long enough for the following packets to overtake
+them, corrupted packets have a byte of the datagram flipped so that the
+receiver fails its checksum
+
+Delayed packets already left the egress queue and the pacer of the
+DatagramSender and are sent directly through the wrapped transport. Packets
+to delay are dropped once the delay queue is full, delayed packets the
+transport fails to send are counted with the failed packets
+*/
+use crate::config::CONST;
+use crate::models::admin::HomaFaultOptions;
+use crate::models::admin::HomaFaultSummary;
+use crate::models::datagram::HomaDatagram;
+use crate::transport::Transport;
+use bincode::deserialize;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::Packet;
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+
+#[derive(Default)]
+struct FaultCounters {
+    dropped: AtomicU64,
+    duplicated: AtomicU64,
+    reordered: AtomicU64,
+    delayed: AtomicU64,
+    corrupted: AtomicU64,
+    overflowed: AtomicU64,
+}
+
+impl FaultCounters {
+    fn summary(&self) -> HomaFaultSummary {
+        HomaFaultSummary {
+            dropped: self.dropped.load(Ordering::Relaxed),
+            duplicated: self.duplicated.load(Ordering::Relaxed),
+            reordered: self.reordered.load(Ordering::Relaxed),
+            delayed: self.delayed.load(Ordering::Relaxed),
+            corrupted: self.corrupted.load(Ordering::Relaxed),
+            overflowed: self.overflowed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct FaultTransport {
+    transport: Arc<dyn Transport>,
+    faults: RwLock<Option<HomaFaultOptions>>,
+    counters: FaultCounters,
+    // Packets to send later, with the instant they are due
+    delayed_tx: Mutex<SyncSender<(Instant, Vec<u8>)>>,
+    // Delayed packets the transport failed to send
+    failed_packets: Arc<AtomicU64>,
+}
+
+impl FaultTransport {
+    // Wrap the transport and spawn the thread sending delayed packets
+    pub fn new(transport: Arc<dyn Transport>, faults: Option<HomaFaultOptions>) -> Self {
+        let (delayed_tx, delayed_rx) = sync_channel(CONST::FAULT_DELAY_QUEUE_LENGTH);
+        let delayed_transport = Arc::clone(&transport);
+        let failed_packets = Arc::new(AtomicU64::new(0));
+        let delayed_failed_packets = Arc::clone(&failed_packets);
+        std::thread::spawn(move || {
+            run_delayed_sender(delayed_transport, delayed_rx, delayed_failed_packets)
+        });
+        Self {
+            transport,
+            faults: RwLock::new(faults.filter(HomaFaultOptions::is_enabled)),
+            counters: FaultCounters::default(),
+            delayed_tx: Mutex::new(delayed_tx),
+            failed_packets,
+        }
+    }
+
+    // Number of delayed packets the transport failed to send
+    pub fn failed_packets(&self) -> u64 {
+        self.failed_packets.load(Ordering::Relaxed)
+    }
+
+    // Replace the injected faults and reset the counters
+    pub fn start_faults(&self, faults: HomaFaultOptions) -> Result<(), String> {
+        faults.validate()?;
+        *self.faults.write().unwrap() = Some(faults);
+        self.reset_counters();
+        Ok(())
+    }
+
+    // Stop injecting faults and summarize the injected faults
+    pub fn stop_faults(&self) -> HomaFaultSummary {
+        *self.faults.write().unwrap() = None;
+        let summary = self.counters.summary();
+        self.reset_counters();
+        summary
+    }
+
+    fn reset_counters(&self) {
+        self.counters.dropped.store(0, Ordering::Relaxed);
+        self.counters.duplicated.store(0, Ordering::Relaxed);
+        self.counters.reordered.store(0, Ordering::Relaxed);
+        self.counters.delayed.store(0, Ordering::Relaxed);
+        self.counters.corrupted.store(0, Ordering::Relaxed);
+        self.counters.overflowed.store(0, Ordering::Relaxed);
+    }
+
+    // Check the packet against the datagram type and peer filters
+    fn matches(faults: &HomaFaultOptions, packet: &[u8]) -> bool {
+        let Some(ipv4_packet) = Ipv4Packet::new(packet) else {
+            return false;
+        };
+        if let Some(peer) = faults.peer {
+            if ipv4_packet.get_destination() != peer {
+                return false;
+            }
+        }
+        if faults.datagram_types.is_empty() {
+            return true;
+        }
+        let Ok(datagram) = deserialize::<HomaDatagram>(ipv4_packet.payload()) else {
+            return false;
+        };
+        faults
+            .datagram_types
+            .iter()
+            .any(|datagram_type| datagram_type.matches(&datagram.datagram_type))
+    }
+
+    // Apply the faults to the packets, return the packets to send now,
+    // the number of duplicates among them and the number of packets
+    // withheld by dropping or delaying them, and queue the delayed packets,
+    // dropping them if the delay queue is full
+    fn inject(
+        &self,
+        faults: &HomaFaultOptions,
+        packets: &[Vec<u8>],
+    ) -> (Vec<Vec<u8>>, usize, usize) {
+        let mut rng = rand::thread_rng();
+        let mut outgoing = Vec::with_capacity(packets.len());
+        let mut duplicates = 0;
+        let mut withheld = 0;
+        for packet in packets {
+            if !Self::matches(faults, packet) {
+                outgoing.push(packet.clone());
+                continue;
+            }
+            if rng.gen_bool(faults.drop) {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                withheld += 1;
+                continue;
+            }
+            let mut packet = packet.clone();
+            if rng.gen_bool(faults.corrupt) && packet.len() > 20 {
+                let i = rng.gen_range(20..packet.len());
+                packet[i] ^= 0xff;
+                self.counters.corrupted.fetch_add(1, Ordering::Relaxed);
+            }
+            if rng.gen_bool(faults.duplicate) {
+                outgoing.push(packet.clone());
+                self.counters.duplicated.fetch_add(1, Ordering::Relaxed);
+                duplicates += 1;
+            }
+            let delay = if rng.gen_bool(faults.delay) {
+                self.counters.delayed.fetch_add(1, Ordering::Relaxed);
+                Some(Duration::from_millis(faults.delay_ms))
+            } else if rng.gen_bool(faults.reorder) {
+                self.counters.reordered.fetch_add(1, Ordering::Relaxed);
+                Some(Duration::from_millis(CONST::FAULT_REORDER_DELAY))
+            } else {
+                None
+            };
+            match delay {
+                Some(delay) => {
+                    withheld += 1;
+                    let queued = self
+                        .delayed_tx
+                        .lock()
+                        .unwrap()
+                        .try_send((Instant::now() + delay, packet));
+                    if let Err(TrySendError::Full(_)) = queued {
+                        self.counters.overflowed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                None => outgoing.push(packet),
+            }
+        }
+        (outgoing, duplicates, withheld)
+    }
+}
+
+impl Transport for FaultTransport {
+    fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<usize> {
+        let faults = self.faults.read().unwrap().clone();
+        match faults {
+            // Dropped and delayed packets count as sent, duplicates do not
+            Some(faults) => {
+                let (outgoing, duplicates, withheld) = self.inject(&faults, packets);
+                match self.transport.send_batch(&outgoing) {
+                    Ok(sent) => Ok(sent.saturating_sub(duplicates) + withheld),
+                    Err(e) if withheld == 0 => Err(e),
+                    Err(_) => Ok(withheld),
+                }
+            }
+            None => self.transport.send_batch(packets),
+        }
+    }
+
+    fn recv_batch(&self, limit: usize) -> io::Result<Vec<Vec<u8>>> {
+        self.transport.recv_batch(limit)
+    }
+
+    fn local_addresses(&self) -> Option<Vec<Ipv4Addr>> {
+        self.transport.local_addresses()
+    }
+
+    fn unroutable_packets(&self) -> u64 {
+        self.transport.unroutable_packets()
+    }
+
+    fn dropped_packets(&self) -> u64 {
+        self.transport.dropped_packets()
+    }
+}
+
+// Send each delayed packet once it is due, in order of due instants,
+// counting the packets the transport failed to send. No more packets are
+// received while the delay queue is full, so that the channel fills up
+// and further packets to delay are dropped
+fn run_delayed_sender(
+    transport: Arc<dyn Transport>,
+    delayed_rx: Receiver<(Instant, Vec<u8>)>,
+    failed_packets: Arc<AtomicU64>,
+) {
+    let mut delayed = BinaryHeap::<Reverse<(Instant, Vec<u8>)>>::new();
+    loop {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        while let Some(Reverse((instant, _))) = delayed.peek() {
+            if *instant > now {
+                break;
+            }
+            let Reverse((_, packet)) = delayed.pop().unwrap();
+            due.push(packet);
+        }
+        if !due.is_empty() {
+            let sent = transport.send_batch(&due).unwrap_or(0);
+            failed_packets.fetch_add(due.len().saturating_sub(sent) as u64, Ordering::Relaxed);
+        }
+
+        let received = match delayed.peek() {
+            Some(Reverse((instant, _))) if delayed.len() >= CONST::FAULT_DELAY_QUEUE_LENGTH => {
+                std::thread::sleep(instant.saturating_duration_since(now));
+                continue;
+            }
+            Some(Reverse((instant, _))) => {
+                delayed_rx.recv_timeout(instant.saturating_duration_since(now))
+            }
+            None => delayed_rx
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(packet) => delayed.push(Reverse(packet)),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FaultTransport;
+    use crate::config::CONST;
+    use crate::models::admin::HomaFaultDatagramType;
+    use crate::models::admin::HomaFaultOptions;
+    use crate::models::datagram::HomaDatagram;
+    use crate::models::datagram::HomaDatagramType;
+    use crate::transport::loopback::LoopbackNetwork;
+    use crate::transport::Transport;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    #[test]
+    fn fault_test() {
+        let network = LoopbackNetwork::new();
+        let address = Ipv4Addr::new(10, 0, 0, 1);
+        let faults = HomaFaultOptions {
+            drop: 1.0,
+            datagram_types: vec![HomaFaultDatagramType::Grant],
+            ..Default::default()
+        };
+        let fault_transport = FaultTransport::new(Arc::new(network.attach(address)), Some(faults));
+
+        let packets = [HomaDatagramType::Grant, HomaDatagramType::Data]
+            .map(|datagram_type| {
+                let datagram = HomaDatagram {
+                    datagram_type,
+                    ..Default::default()
+                };
+                datagram.to_ipv4(address, address, 0)
+            })
+            .to_vec();
+        assert_eq!(fault_transport.send_batch(&packets).unwrap(), 2);
+        assert_eq!(
+            fault_transport.recv_batch(2).unwrap(),
+            vec![packets[1].clone()]
+        );
+
+        let faults = HomaFaultOptions {
+            duplicate: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(fault_transport.stop_faults().dropped, 1);
+        fault_transport.start_faults(faults).unwrap();
+        assert_eq!(fault_transport.send_batch(&packets[..1]).unwrap(), 1);
+        assert_eq!(fault_transport.recv_batch(2).unwrap().len(), 2);
+        assert_eq!(fault_transport.stop_faults().duplicated, 1);
+    }
+    #[test]
+    fn delay_overflow_test() {
+        let network = LoopbackNetwork::new();
+        let address = Ipv4Addr::new(10, 0, 0, 1);
+        let faults = HomaFaultOptions {
+            delay: 1.0,
+            delay_ms: 60000,
+            ..Default::default()
+        };
+        let fault_transport = FaultTransport::new(Arc::new(network.attach(address)), Some(faults));
+
+        // Packets to delay beyond the delay queue are dropped and counted
+        let packet = HomaDatagram::default().to_ipv4(address, address, 0);
+        let packets = vec![packet; 3 * CONST::FAULT_DELAY_QUEUE_LENGTH];
+        assert_eq!(fault_transport.send_batch(&packets).unwrap(), packets.len());
+        let summary = fault_transport.stop_faults();
+        assert_eq!(summary.delayed, packets.len() as u64);
+        assert!(summary.overflowed >= CONST::FAULT_DELAY_QUEUE_LENGTH as u64);
+    }
+}
@@ -0,0 +1,141 @@
+/*
+Transport
+
+A transport moves the IPv4 packets built by HomaDatagram::to_ipv4 between
+hosts, the DatagramSender sends batches of queued packets through it and the
+DatagramReceivers block on it for batches of incoming packets
+
+The raw transport sends the packets as IP protocol 146 and requires
+CAP_NET_RAW, the UDP transport encapsulates them in UDP datagrams on a
+configurable port and maps the priority to DSCP through IP_TOS, the loopback
+transport connects stacks inside one process
+
+When homad is bound to local addresses or interfaces, a transport is opened
+for each local address and combined by the multi transport, which maps the
+priority of the packets to the DSCP map of their local address. The capture
+transport wraps another transport and writes the packets passing through it to
+a pcap file on request of the admin interface, the fault transport injects
+loss and other faults into the outgoing packets
+*/
+pub mod batch;
+pub mod capture;
+pub mod fault;
+pub mod loopback;
+pub mod multi;
+pub mod raw;
+pub mod udp;
+
+use crate::config::LocalAddress;
+use crate::config::TransportMode;
+use crate::config::CONFIG;
+use multi::MultiTransport;
+use nix::ifaddrs::getifaddrs;
+use nix::sys::socket::setsockopt;
+use nix::sys::socket::sockopt::BindToDevice;
+use raw::RawTransport;
+use std::ffi::OsString;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::fd::AsFd;
+use std::sync::Arc;
+use udp::UdpTransport;
+
+pub trait Transport: Send + Sync {
+    // Send the IPv4 packets, each to the destination address in its header,
+    // return the number of packets sent
+    fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<usize>;
+
+    // Block until at least one IPv4 packet is received, return the
+    // received packets up to the limit, leaving out those it rejects
+    fn recv_batch(&self, limit: usize) -> io::Result<Vec<Vec<u8>>>;
+
+    // Addresses packets are received for, all addresses if none
+    fn local_addresses(&self) -> Option<Vec<Ipv4Addr>> {
+        None
+    }
+
+    // Number of packets dropped as no local address can send them
+    fn unroutable_packets(&self) -> u64 {
+        0
+    }
+
+    // Number of received packets dropped as they could not be queued
+    fn dropped_packets(&self) -> u64 {
+        0
+    }
+}
+
+// Bind the socket to the interface with SO_BINDTODEVICE
+pub(crate) fn bind_interface(socket: &impl AsFd, interface: &str) -> io::Result<()> {
+    setsockopt(socket, BindToDevice, &OsString::from(interface)).map_err(io::Error::from)
+}
+
+// Open the transport of the local address, or of all addresses if unspecified
+fn open_local_transport(
+    address: Ipv4Addr,
+    interface: Option<&str>,
+    transport: TransportMode,
+    udp_port: u16,
+) -> io::Result<Arc<dyn Transport>> {
+    match transport {
+        TransportMode::Raw => Ok(Arc::new(RawTransport::new(address, interface)?)),
+        TransportMode::Udp => Ok(Arc::new(UdpTransport::new(address, udp_port, interface)?)),
+    }
+}
+
+// Local addresses from the configuration, the addresses of each configured
+// interface are added unless given explicitly
+fn local_addresses() -> io::Result<Vec<LocalAddress>> {
+    let mut local_addresses = CONFIG.LOCAL_ADDRESS.clone();
+    for interface in &CONFIG.INTERFACE {
+        let addresses = getifaddrs()?
+            .filter(|interface_address| interface_address.interface_name == *interface)
+            .filter_map(|interface_address| Some(interface_address.address?.as_sockaddr_in()?.ip()))
+            .collect::<Vec<_>>();
+        if addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No IPv4 address on interface {}", interface),
+            ));
+        }
+        for address in addresses {
+            if local_addresses
+                .iter()
+                .all(|local_address| local_address.address != address)
+            {
+                local_addresses.push(LocalAddress {
+                    interface: Some(interface.clone()),
+                    ..LocalAddress::new(address)
+                });
+            }
+        }
+    }
+    Ok(local_addresses)
+}
+
+// Open the transport selected in the configuration, one per local address
+// if homad is bound to local addresses or interfaces
+pub fn open_transport() -> io::Result<Arc<dyn Transport>> {
+    let local_addresses = local_addresses()?;
+    if local_addresses.is_empty() {
+        return open_local_transport(
+            Ipv4Addr::UNSPECIFIED,
+            None,
+            CONFIG.TRANSPORT,
+            CONFIG.UDP_PORT,
+        );
+    }
+    let transports = local_addresses
+        .into_iter()
+        .map(|local_address| {
+            let transport = open_local_transport(
+                local_address.address,
+                local_address.interface.as_deref(),
+                local_address.transport.unwrap_or(CONFIG.TRANSPORT),
+                local_address.udp_port.unwrap_or(CONFIG.UDP_PORT),
+            )?;
+            Ok((local_address, transport))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok(Arc::new(MultiTransport::new(transports)))
+}
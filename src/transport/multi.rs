@@ -0,0 +1,225 @@
+/*
+MultiTransport
+
+Combine one transport per local address, each bound to its address and
+possibly to an interface with its own transport settings
+
+Packets are sent through the transport of their source address with the DSCP
+the map of the address gives their priority level, packets from
+any other source are dropped and counted so that no datagram leaves from an
+address homad is not bound to. A transport failing to send does not keep the
+packets of the other transports from being sent. Every transport is received
+on by a dedicated thread forwarding the batches into a shared bounded channel,
+batches finding the channel full are dropped and counted
+*/
+use crate::config::LocalAddress;
+use crate::config::CONFIG;
+use crate::config::CONST;
+use crate::transport::Transport;
+use pnet::packet::ipv4::checksum;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv4::MutableIpv4Packet;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// Packets received in one call of a transport
+type ReceivedBatch = Vec<Vec<u8>>;
+
+pub struct MultiTransport {
+    transports: Vec<(LocalAddress, Arc<dyn Transport>)>,
+    // Channel of the received batches and the packets of a batch
+    // left over by the last receive
+    rx: Mutex<(Receiver<ReceivedBatch>, ReceivedBatch)>,
+    // Packets dropped as no transport is bound to their source address
+    unroutable_packets: AtomicU64,
+    // Received packets dropped as the channel was full
+    dropped_packets: Arc<AtomicU64>,
+}
+
+impl MultiTransport {
+    // Spawn a receiving thread for each transport
+    pub fn new(transports: Vec<(LocalAddress, Arc<dyn Transport>)>) -> Self {
+        let (tx, rx) = sync_channel(CONST::MULTI_TRANSPORT_QUEUE_LENGTH);
+        let dropped_packets = Arc::new(AtomicU64::new(0));
+        for (_, transport) in &transports {
+            let transport = Arc::clone(transport);
+            let tx = tx.clone();
+            let dropped_packets = Arc::clone(&dropped_packets);
+            std::thread::spawn(move || run_receiver(transport, tx, dropped_packets));
+        }
+        Self {
+            transports,
+            rx: Mutex::new((rx, Vec::new())),
+            unroutable_packets: AtomicU64::new(0),
+            dropped_packets,
+        }
+    }
+}
+
+impl Transport for MultiTransport {
+    // Return the number of packets sent by all transports, or the first
+    // error if none were sent
+    fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<usize> {
+        let mut routed = 0;
+        let mut sent = 0;
+        let mut error = None;
+        for (local_address, transport) in &self.transports {
+            let mut packets = packets
+                .iter()
+                .filter(|packet| {
+                    Ipv4Packet::new(packet)
+                        .is_some_and(|packet| packet.get_source() == local_address.address)
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            if !local_address.dscp_map.is_empty() {
+                for packet in &mut packets {
+                    map_dscp(local_address, packet);
+                }
+            }
+            if packets.is_empty() {
+                continue;
+            }
+            routed += packets.len();
+            match transport.send_batch(&packets) {
+                Ok(transport_sent) => sent += transport_sent,
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        self.unroutable_packets
+            .fetch_add((packets.len() - routed) as u64, Ordering::Relaxed);
+        match error {
+            Some(e) if sent == 0 => Err(e),
+            _ => Ok(sent),
+        }
+    }
+
+    fn recv_batch(&self, limit: usize) -> io::Result<Vec<Vec<u8>>> {
+        let mut rx = self.rx.lock().unwrap();
+        let (rx, packets) = &mut *rx;
+        if packets.is_empty() {
+            *packets = rx
+                .recv()
+                .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "transports closed"))?;
+        }
+        while packets.len() < limit {
+            match rx.try_recv() {
+                Ok(batch) => packets.extend(batch),
+                Err(_) => break,
+            }
+        }
+        let rest = packets.split_off(limit.min(packets.len()));
+        Ok(std::mem::replace(packets, rest))
+    }
+
+    fn local_addresses(&self) -> Option<Vec<Ipv4Addr>> {
+        Some(
+            self.transports
+                .iter()
+                .map(|(local_address, _)| local_address.address)
+                .collect(),
+        )
+    }
+
+    fn unroutable_packets(&self) -> u64 {
+        self.unroutable_packets.load(Ordering::Relaxed)
+    }
+
+    fn dropped_packets(&self) -> u64 {
+        self.dropped_packets.load(Ordering::Relaxed)
+    }
+}
+
+// Set the DSCP of the local address for the priority level of the packet
+fn map_dscp(local_address: &LocalAddress, packet: &mut [u8]) {
+    if let Some(mut packet) = MutableIpv4Packet::new(packet) {
+        let level = CONFIG.priority_level(packet.get_dscp());
+        packet.set_dscp(local_address.dscp(level));
+        let header_checksum = checksum(&packet.to_immutable());
+        packet.set_checksum(header_checksum);
+    }
+}
+
+// Forward the batches received on the transport until it is no longer
+// connected, counting the packets of batches finding the channel full
+fn run_receiver(
+    transport: Arc<dyn Transport>,
+    tx: SyncSender<ReceivedBatch>,
+    dropped_packets: Arc<AtomicU64>,
+) {
+    loop {
+        match transport.recv_batch(CONST::DATAGRAM_BATCH_LIMIT) {
+            Ok(packets) if packets.is_empty() => (),
+            Ok(packets) => match tx.try_send(packets) {
+                Ok(()) => (),
+                Err(TrySendError::Full(packets)) => {
+                    dropped_packets.fetch_add(packets.len() as u64, Ordering::Relaxed);
+                }
+                Err(TrySendError::Disconnected(_)) => return,
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => return,
+            Err(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MultiTransport;
+    use crate::config::LocalAddress;
+    use crate::config::CONFIG;
+    use crate::models::datagram::HomaDatagram;
+    use crate::transport::loopback::LoopbackNetwork;
+    use crate::transport::Transport;
+    use pnet::packet::ipv4::Ipv4Packet;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    #[test]
+    fn multi_test() {
+        let network = LoopbackNetwork::new();
+        let storage_address = Ipv4Addr::new(10, 1, 0, 1);
+        let management_address = Ipv4Addr::new(10, 2, 0, 1);
+        let peer_address = Ipv4Addr::new(10, 1, 0, 2);
+        let peer = network.attach(peer_address);
+        let storage: Arc<dyn Transport> = Arc::new(network.attach(storage_address));
+        let local_address = LocalAddress {
+            dscp_map: vec![0, 10, 12, 14, 18, 20, 26, 46],
+            ..LocalAddress::new(storage_address)
+        };
+        let multi_transport = MultiTransport::new(vec![(local_address, storage)]);
+        assert_eq!(
+            multi_transport.local_addresses(),
+            Some(vec![storage_address])
+        );
+
+        let datagram = HomaDatagram::default();
+        let packets = vec![
+            datagram.to_ipv4(management_address, peer_address, 0),
+            datagram.to_ipv4(storage_address, peer_address, CONFIG.control_dscp()),
+        ];
+        assert_eq!(multi_transport.send_batch(&packets).unwrap(), 1);
+        assert_eq!(multi_transport.unroutable_packets(), 1);
+
+        // The packet is sent with the DSCP of its level on the storage network
+        let received = peer.recv_batch(2).unwrap();
+        assert_eq!(received.len(), 1);
+        let received = Ipv4Packet::new(&received[0]).unwrap();
+        assert_eq!(received.get_source(), storage_address);
+        assert_eq!(received.get_dscp(), 46);
+
+        let packet = datagram.to_ipv4(peer_address, storage_address, 0);
+        peer.send_batch(std::slice::from_ref(&packet)).unwrap();
+        assert_eq!(multi_transport.recv_batch(2).unwrap(), vec![packet]);
+    }
+}
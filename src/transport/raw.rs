@@ -0,0 +1,158 @@
+/*
+RawTransport
+
+Receive packets on a single raw IP protocol 146 socket and send them on a
+single IPPROTO_RAW socket, which is send-only since the kernel never delivers
+incoming packets to it, so no socket buffers fill up with unread copies
+
+Packets are sent with an outer IPv4 header carrying the DSCP of the packet,
+the kernel fills in the identification, checksum and the source address unless
+the transport is bound to a local address. Bound to an address, only packets to
+that address are received, bound to an interface, packets are only sent and
+received on that interface
+*/
+use crate::config::CONST;
+use crate::transport::batch::recv_batch;
+use crate::transport::batch::send_batch;
+use crate::transport::bind_interface;
+use crate::transport::Transport;
+use pnet::packet::ip::IpNextHeaderProtocol;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::Packet;
+use socket2::Domain;
+use socket2::Protocol;
+use socket2::Socket;
+use socket2::Type;
+use std::io;
+use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
+use std::os::fd::AsRawFd;
+
+pub struct RawTransport {
+    receive_socket: Socket,
+    send_socket: Socket,
+    // Source address of the outer header, unspecified to let the kernel fill it in
+    address: Ipv4Addr,
+}
+
+impl RawTransport {
+    // Open the receive-only and the send-only socket, bound to the address
+    // unless it is unspecified and to the interface if any
+    pub fn new(address: Ipv4Addr, interface: Option<&str>) -> io::Result<Self> {
+        let receive_socket = Socket::new(
+            Domain::IPV4,
+            Type::RAW,
+            Some(Protocol::from(CONST::HOMA_PROTOCOL as i32)),
+        )?;
+        receive_socket.set_recv_buffer_size(3000000)?;
+        receive_socket.bind(&SocketAddrV4::new(address, 0).into())?;
+        let send_socket = Socket::new(
+            Domain::IPV4,
+            Type::RAW,
+            Some(Protocol::from(libc::IPPROTO_RAW)),
+        )?;
+        send_socket.set_send_buffer_size(3000000)?;
+        if let Some(interface) = interface {
+            bind_interface(&receive_socket, interface)?;
+            bind_interface(&send_socket, interface)?;
+        }
+        Ok(Self {
+            receive_socket,
+            send_socket,
+            address,
+        })
+    }
+
+    // Prefix the packet with the outer IPv4 header
+    fn encapsulate(&self, packet: &Ipv4Packet) -> Vec<u8> {
+        let inner = packet.packet();
+        let mut buffer = vec![0u8; 20 + inner.len()];
+        let mut outer = MutableIpv4Packet::new(&mut buffer).unwrap();
+        outer.set_version(4);
+        outer.set_header_length(5);
+        outer.set_total_length((20 + inner.len()) as u16);
+        outer.set_ttl(64);
+        outer.set_dscp(packet.get_dscp());
+        outer.set_next_level_protocol(IpNextHeaderProtocol(CONST::HOMA_PROTOCOL));
+        outer.set_source(self.address);
+        outer.set_destination(packet.get_destination());
+        buffer[20..].copy_from_slice(inner);
+        buffer
+    }
+}
+
+impl Transport for RawTransport {
+    fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<usize> {
+        let datagrams = packets
+            .iter()
+            .filter_map(|packet| Ipv4Packet::new(packet))
+            .map(|packet| {
+                let address = SocketAddrV4::new(packet.get_destination(), 0);
+                (self.encapsulate(&packet), address)
+            })
+            .collect::<Vec<_>>();
+        let datagrams = datagrams
+            .iter()
+            .map(|(datagram, address)| (datagram.as_slice(), *address))
+            .collect::<Vec<_>>();
+        Ok(send_batch(self.send_socket.as_raw_fd(), &datagrams))
+    }
+
+    // Received packets start with the outer IPv4 header, which is stripped
+    fn recv_batch(&self, limit: usize) -> io::Result<Vec<Vec<u8>>> {
+        let mut packets = recv_batch(self.receive_socket.as_raw_fd(), limit)?
+            .into_iter()
+            .map(|(packet, _)| packet)
+            .collect::<Vec<_>>();
+        packets.retain_mut(decapsulate);
+        Ok(packets)
+    }
+}
+
+// Strip the outer IPv4 header, keeping the packet only if the source of the
+// inner header is the source of the outer one, which is the sending peer
+fn decapsulate(packet: &mut Vec<u8>) -> bool {
+    let (header_length, source) = match Ipv4Packet::new(packet) {
+        Some(outer) => (outer.get_header_length() as usize * 4, outer.get_source()),
+        None => return false,
+    };
+    packet.drain(..header_length.min(packet.len()));
+    Ipv4Packet::new(packet).is_some_and(|inner| inner.get_source() == source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decapsulate;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use std::net::Ipv4Addr;
+
+    // IPv4 packet with the source and destination addresses and the payload
+    fn packet(source: Ipv4Addr, destination: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0u8; 20 + payload.len()];
+        let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length((20 + payload.len()) as u16);
+        packet.set_source(source);
+        packet.set_destination(destination);
+        buffer[20..].copy_from_slice(payload);
+        buffer
+    }
+
+    #[test]
+    fn spoofed_source_test() {
+        let address = Ipv4Addr::new(127, 0, 0, 1);
+        let peer_address = Ipv4Addr::new(127, 0, 0, 2);
+
+        // Only the packet whose inner source is the outer source is kept
+        let spoofed = packet(Ipv4Addr::new(10, 0, 0, 1), address, b"spoofed");
+        let genuine = packet(peer_address, address, b"genuine");
+        let mut packets = vec![
+            packet(peer_address, address, &spoofed),
+            packet(peer_address, address, &genuine),
+        ];
+        packets.retain_mut(decapsulate);
+        assert_eq!(packets, vec![genuine]);
+    }
+}
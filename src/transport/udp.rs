@@ -0,0 +1,131 @@
+/*
+UdpTransport
+
+Encapsulate packets in UDP datagrams sent to the same port on the destination
+host, which needs no privileges and passes middleboxes dropping unknown IP
+protocols. Batches are split into runs of packets with the same DSCP, which is
+applied with IP_TOS before sending each run. Bound to an address, packets are
+sent from and received on that address only, bound to an interface, packets
+are only sent and received on that interface
+
+The encapsulated packets carry the addresses of their IPv4 header, received
+packets whose source differs from the address of the UDP datagram are dropped
+so that a host cannot pose as another Homa peer
+*/
+use crate::transport::batch::recv_batch;
+use crate::transport::batch::send_batch;
+use crate::transport::bind_interface;
+use crate::transport::Transport;
+use pnet::packet::ipv4::Ipv4Packet;
+use socket2::SockRef;
+use std::io;
+use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
+use std::net::UdpSocket;
+use std::os::fd::AsRawFd;
+use std::sync::Mutex;
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    port: u16,
+    // DSCP currently set on the socket, locked for the duration of
+    // a send so that concurrent sends do not race on IP_TOS
+    dscp: Mutex<u8>,
+}
+
+impl UdpTransport {
+    // Bind the UDP socket on the port of the address, all addresses if
+    // unspecified, and to the interface if any
+    pub fn new(address: Ipv4Addr, port: u16, interface: Option<&str>) -> io::Result<Self> {
+        let socket = UdpSocket::bind((address, port))?;
+        SockRef::from(&socket).set_recv_buffer_size(3000000)?;
+        SockRef::from(&socket).set_send_buffer_size(3000000)?;
+        if let Some(interface) = interface {
+            bind_interface(&socket, interface)?;
+        }
+        Ok(Self {
+            socket,
+            port,
+            dscp: Mutex::new(0),
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<usize> {
+        let datagrams = packets
+            .iter()
+            .filter_map(|packet| {
+                let ipv4_packet = Ipv4Packet::new(packet)?;
+                let address = SocketAddrV4::new(ipv4_packet.get_destination(), self.port);
+                Some((packet.as_slice(), address, ipv4_packet.get_dscp()))
+            })
+            .collect::<Vec<_>>();
+
+        let mut dscp = self.dscp.lock().unwrap();
+        let mut sent = 0;
+        for run in datagrams.chunk_by(|a, b| a.2 == b.2) {
+            let run_dscp = run[0].2;
+            if *dscp != run_dscp {
+                SockRef::from(&self.socket).set_tos((run_dscp as u32) << 2)?;
+                *dscp = run_dscp;
+            }
+            let run = run
+                .iter()
+                .map(|(datagram, address, _)| (*datagram, *address))
+                .collect::<Vec<_>>();
+            sent += send_batch(self.socket.as_raw_fd(), &run);
+        }
+        Ok(sent)
+    }
+
+    fn recv_batch(&self, limit: usize) -> io::Result<Vec<Vec<u8>>> {
+        let packets = recv_batch(self.socket.as_raw_fd(), limit)?
+            .into_iter()
+            .filter(|(packet, address)| {
+                let source = Ipv4Packet::new(packet).map(|packet| packet.get_source());
+                source.is_some() && source == address.map(|address| *address.ip())
+            })
+            .map(|(packet, _)| packet)
+            .collect();
+        Ok(packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UdpTransport;
+    use crate::transport::Transport;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use std::net::Ipv4Addr;
+    use std::net::UdpSocket;
+
+    // IPv4 packet with the source and destination addresses and the payload
+    fn packet(source: Ipv4Addr, destination: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0u8; 20 + payload.len()];
+        let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length((20 + payload.len()) as u16);
+        packet.set_source(source);
+        packet.set_destination(destination);
+        buffer[20..].copy_from_slice(payload);
+        buffer
+    }
+
+    #[test]
+    fn spoofed_source_test() {
+        let address = Ipv4Addr::new(127, 0, 0, 1);
+        let peer_address = Ipv4Addr::new(127, 0, 0, 2);
+        let udp_transport = UdpTransport::new(address, 0, None).unwrap();
+        let port = udp_transport.socket.local_addr().unwrap().port();
+        let peer = UdpSocket::bind((peer_address, 0)).unwrap();
+
+        // Only the packet whose source is the address of the peer is received
+        let spoofed = packet(Ipv4Addr::new(10, 0, 0, 1), address, b"spoofed");
+        let genuine = packet(peer_address, address, b"genuine");
+        peer.send_to(&spoofed, (address, port)).unwrap();
+        peer.send_to(&genuine, (address, port)).unwrap();
+        assert_eq!(udp_transport.recv_batch(2).unwrap(), vec![genuine]);
+    }
+}
@@ -1,6 +1,27 @@
+use nix::cmsg_space;
+use nix::sys::socket::recvmsg;
+use nix::sys::socket::ControlMessageOwned;
+use nix::sys::socket::MsgFlags;
+#[cfg(feature = "sim")]
+use rand::rngs::StdRng;
 use rand::Rng;
+#[cfg(feature = "sim")]
+use rand::SeedableRng;
+#[cfg(feature = "sim")]
+use std::cell::Cell;
+#[cfg(feature = "sim")]
+use std::cell::RefCell;
+use std::io::IoSliceMut;
 use std::ops::Range;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::fd::RawFd;
 use std::os::unix::net::UnixStream;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::io::unix::AsyncFd;
 
 pub fn split_unix_stream(stream: UnixStream) -> Result<(UnixStream, UnixStream), String> {
     let other = stream
@@ -9,7 +30,7 @@ pub fn split_unix_stream(stream: UnixStream) -> Result<(UnixStream, UnixStream),
     Ok((stream, other))
 }
 
-pub fn quantile(v: &Vec<u64>, q: f32) -> u64 {
+pub fn quantile(v: &[u64], q: f32) -> u64 {
     let pos = (v.len() - 1) as f32 * q;
     let quotient = pos.floor();
     let remainder = pos - (quotient);
@@ -23,10 +44,110 @@ pub fn quantile(v: &Vec<u64>, q: f32) -> u64 {
     }
 
     let i = quotient as usize;
-    return v[i];
+    v[i]
 }
 
+#[cfg(feature = "sim")]
+thread_local! {
+    // Generator seeded on this thread for reproducible simulations,
+    // the thread-local generator of rand is used otherwise
+    static SEEDED_RNG: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+    // Factor stretching the timeouts on this thread, simulations run on a
+    // dilated clock as tokio timers only resolve milliseconds
+    static TIME_DILATION: Cell<u64> = const { Cell::new(1) };
+}
+
+#[cfg(feature = "sim")]
+pub fn seed_rng(seed: u64) {
+    SEEDED_RNG.with(|rng| *rng.borrow_mut() = Some(StdRng::seed_from_u64(seed)));
+}
+
+#[cfg(feature = "sim")]
+pub fn dilate_time(factor: u64) {
+    TIME_DILATION.with(|dilation| dilation.set(factor));
+}
+
+// Timeouts are only dilated by the simulator
+#[cfg(feature = "sim")]
+pub fn dilate_timeout(timeout: u64) -> u64 {
+    timeout * TIME_DILATION.with(Cell::get)
+}
+
+#[cfg(not(feature = "sim"))]
+pub fn dilate_timeout(timeout: u64) -> u64 {
+    timeout
+}
+
+#[cfg(feature = "sim")]
+pub fn fuzz_timeout(timeout_base: u64) -> u64 {
+    let timeout_base = dilate_timeout(timeout_base);
+    let timeout_range = (timeout_base - timeout_base / 2)..(timeout_base + timeout_base / 2);
+    SEEDED_RNG.with(|rng| match rng.borrow_mut().as_mut() {
+        Some(rng) => rng.gen_range::<u64, Range<u64>>(timeout_range),
+        None => rand::thread_rng().gen_range::<u64, Range<u64>>(timeout_range),
+    })
+}
+
+#[cfg(not(feature = "sim"))]
 pub fn fuzz_timeout(timeout_base: u64) -> u64 {
     let timeout_range = (timeout_base - timeout_base / 2)..(timeout_base + timeout_base / 2);
     rand::thread_rng().gen_range::<u64, Range<u64>>(timeout_range)
 }
+
+// Duration of a timer set by the protocol state machines, the timeout is
+// dilated and, if the timer has jitter, fuzzed by up to half of it
+pub fn timer_duration(timeout: Duration, jitter: bool) -> Duration {
+    let timeout = timeout.as_millis() as u64;
+    if jitter {
+        Duration::from_millis(fuzz_timeout(timeout))
+    } else {
+        Duration::from_millis(dilate_timeout(timeout))
+    }
+}
+
+pub fn timestamp_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+// Read exactly enough bytes to fill the buffer from the socket,
+// collecting any file descriptors passed alongside the data
+pub async fn recv_exact_with_fds(
+    socket: &AsyncFd<OwnedFd>,
+    buffer: &mut [u8],
+) -> Result<Vec<OwnedFd>, String> {
+    let mut fds = Vec::new();
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let mut guard = socket.readable().await.map_err(|e| e.to_string())?;
+        let result = guard.try_io(|socket| {
+            let mut cmsg_buffer = cmsg_space!([RawFd; 4]);
+            let mut iov = [IoSliceMut::new(&mut buffer[filled..])];
+            let message = recvmsg::<()>(
+                socket.as_raw_fd(),
+                &mut iov,
+                Some(&mut cmsg_buffer),
+                MsgFlags::MSG_CMSG_CLOEXEC,
+            )?;
+            for cmsg in message.cmsgs()? {
+                if let ControlMessageOwned::ScmRights(raw_fds) = cmsg {
+                    fds.extend(
+                        raw_fds
+                            .into_iter()
+                            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                    );
+                }
+            }
+            Ok(message.bytes)
+        });
+        match result {
+            Ok(Ok(0)) => return Err("stream closed".to_string()),
+            Ok(Ok(bytes)) => filled += bytes,
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(_would_block) => continue,
+        }
+    }
+    Ok(fds)
+}